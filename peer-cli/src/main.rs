@@ -1,154 +1,478 @@
-use peer_core::{start_listener, start_client, listen_for_peers, persistence::Persist};
+use peer_core::{start_listener, start_client, load_or_create_identity, parse_addr, console, DiscoveryService, FoundPeer, PeerEvent, DEFAULT_PORT, persistence::{self, History, Persist}, FramingConfig};
+use peer_common::SafetyNumber;
+use clap::{CommandFactory, Parser, Subcommand};
 use std::env;
-use tokio;
+use std::future::Future;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "tui")]
+mod tui;
+
+/// The environment variable the history passphrase can be given in, so the chat can
+/// run without a terminal to ask on.
+const PASSPHRASE_ENV: &str = "P2P_CHAT_PASSPHRASE";
+
+/// A serverless, end-to-end encrypted chat for your local network.
+#[derive(Parser)]
+#[command(name = "peer-cli", version, about)]
+struct Cli {
+    /// Keep the saved peers and settings in this file instead of ~/.p2p-chat.json, and the
+    /// identity key and history in its directory instead of ~
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// The port to listen on, and to connect to, when an address does not include one
+    #[arg(long, global = true, default_value_t = DEFAULT_PORT)]
+    port: u16,
+
+    /// Do not announce yourself on the local network while listening, nor search for
+    /// peers
+    #[arg(long, global = true)]
+    no_discovery: bool,
+
+    /// The name to show your own messages with, instead of "You"
+    #[arg(long, global = true, value_name = "NAME")]
+    nickname: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Listen for peers and host a chat room for everyone who connects
+    Listen {
+        /// The address to listen on, e.g. 0.0.0.0:12345, [::] for IPv6 and IPv4, or
+        /// 0.0.0.0 to use --port
+        addr: String,
+        /// The name of the room to host
+        #[arg(default_value = "lobby")]
+        room: String,
+    },
+
+    /// Connect to a saved peer or an address
+    Connect {
+        /// The alias of a saved peer, or an address like 192.168.1.10:12345 or
+        /// [fe80::1%eth0]:12345
+        #[arg(value_name = "ALIAS|ADDR")]
+        target: String,
+    },
+
+    /// Search for peers on the local network and connect to one of them
+    Discover,
+
+    /// Save a peer's address under an alias
+    AddPeer {
+        /// The alias to save the peer under
+        alias: String,
+        /// The peer's address, e.g. 192.168.1.10:12345 or [fd00::7]:12345, or
+        /// 192.168.1.10 to use --port
+        addr: String,
+    },
+
+    /// List the saved peers and the status each last showed
+    ListPeers,
+
+    /// Compare safety numbers with a saved peer and mark them as verified
+    Verify {
+        /// The alias of a saved peer
+        alias: String,
+    },
+
+    /// Show or set the directory received files are saved in
+    DownloadDir {
+        /// The directory to save received files in from now on
+        dir: Option<PathBuf>,
+    },
+
+    /// Show the messages exchanged with a saved peer
+    History {
+        /// The alias of a saved peer
+        alias: String,
+        /// Only show messages after a date (2024-05-01, "2024-05-01 14:30") or within an
+        /// age (30m, 12h, 7d, 2w)
+        #[arg(long, value_name = "DATE|AGE", value_parser = parse_since)]
+        since: Option<u64>,
+        /// Only show messages containing this text, ignoring case
+        #[arg(long, value_name = "TEXT")]
+        grep: Option<String>,
+    },
+
+    /// Print a shell completion script
+    Completions {
+        /// The shell to complete commands in
+        shell: clap_complete::Shell,
+    },
+}
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // Parse command-line arguments
-    let args: Vec<String> = env::args().collect();
-
-    // If no command is provided, print usage information and exit
-    if args.len() < 2 {
-        eprintln!("Usage:");
-        eprintln!("  {} listen <ADDR:PORT>", args[0]);
-        eprintln!("  {} connect <ALIAS|ADDR:PORT>", args[0]);
-        eprintln!("  {} discover", args[0]);
-        eprintln!("  {} add-peer <ALIAS> <ADDR:PORT>", args[0]);
-        eprintln!("  {} list-peers", args[0]);
+async fn main() -> ExitCode {
+    // Parse command-line arguments. Invalid arguments exit with status 2
+    let cli = Cli::parse();
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the command given on the command line.
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    if let Some(path) = &cli.config {
+        persistence::set_config_path(path.clone());
+    }
+    if let Some(nickname) = &cli.nickname {
+        peer_core::console::set_nickname(nickname);
+    }
+
+    // Generating completions needs neither the persisted data nor the identity key
+    if let Command::Completions { shell } = cli.command {
+        clap_complete::generate(shell, &mut Cli::command(), "peer-cli", &mut std::io::stdout());
         return Ok(());
     }
 
     // Load the persisted peer data
-    let mut persist = Persist::load();
+    let mut persist = Persist::load()?;
+    // Load this install's long-term identity key, creating it on first run
+    let identity = Arc::new(load_or_create_identity()?);
+    // Use the saved discovery settings, unless discovery is turned off
+    let mut discovery = persist.discovery.clone();
+    if cli.no_discovery {
+        discovery.enabled = false;
+    }
 
     // Dispatch the command to the appropriate handler
-    match args[1].as_str() {
-        "listen" => {
-            if args.len() != 3 {
-                eprintln!("Usage: {} listen <ADDR:PORT>", args[0]);
-                return Ok(());
-            }
-            // Start the listener
-            start_listener(&args[2]).await?;
+    match cli.command {
+        Command::Listen { addr, room } => {
+            // Start the listener, hosting the given room or the default one
+            let addr = parse_addr(&addr, cli.port)?;
+            chat(start_listener(&addr, identity, FramingConfig::default(), &room, unlock_history(), &discovery)).await?;
         }
 
-        "connect" => {
-            if args.len() != 3 {
-                eprintln!("Usage: {} connect <ALIAS|ADDR:PORT>", args[0]);
-                return Ok(());
-            }
+        Command::Connect { target } => {
             // If the provided address is an alias, get the corresponding address from the
             // persisted data. Otherwise, use the provided address directly.
-            let addr = persist.get_peer(&args[2]).map(|p| p.addr.clone()).unwrap_or(args[2].clone());
+            let saved = persist.get_peer(&target);
+            let addr = parse_addr(saved.map_or(&target, |p| &p.addr), cli.port)?;
+            let alias = saved.map(|p| p.name.clone());
             // Start the client and connect to the peer
-            start_client(&addr).await?;
+            chat(start_client(&addr, identity, alias.as_deref(), FramingConfig::default(), unlock_history())).await?;
         }
 
-        "discover" => {
-            use std::collections::HashSet;
-            use std::io::{stdin, stdout, Write};
+        Command::Discover => {
+            use std::io::{stdout, Write};
 
-            let mut discovered_peers = HashSet::new();
-            println!("Searching for peers... (Press Ctrl+C to stop)");
+            if !discovery.enabled {
+                anyhow::bail!("discovery is disabled");
+            }
+            let (service, mut events) = DiscoveryService::start(&discovery)?;
+            println!("Searching for peers... Type the number of a peer to connect to, Enter to list them, or q to quit.");
 
-            // Loop to discover peers on the network
-            loop {
-                match tokio::time::timeout(std::time::Duration::from_secs(5), listen_for_peers()).await {
-                    Ok(Ok(peer_addr)) => {
-                        if discovered_peers.insert(peer_addr) {
-                            println!("Found peer: {}", peer_addr);
+            // Show the peers as they appear, change and leave, until the user picks one.
+            // Peers keep their number when they leave and come back.
+            let mut numbers: Vec<(String, u16)> = Vec::new();
+            let peer = loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Some(PeerEvent::Appeared(seen)) => {
+                            println!("[{}] Found peer: {}", peer_number(&mut numbers, &seen.peer), describe_peer(&seen.peer, &persist));
                         }
-                    }
-                    Ok(Err(e)) => {
-                        eprintln!("Discovery error: {}", e);
-                    }
-                    Err(_) => {
-                        // If no peers have been discovered yet, continue searching. Otherwise,
-                        // break the loop and present the list of discovered peers to the user.
-                        if discovered_peers.is_empty() {
-                            println!("No peers found yet...");
-                        } else {
-                            break;
+                        Some(PeerEvent::Updated(seen)) => {
+                            println!("[{}] Updated: {}", peer_number(&mut numbers, &seen.peer), describe_peer(&seen.peer, &persist));
+                        }
+                        Some(PeerEvent::Expired(seen)) => {
+                            println!("[{}] Gone: {}", peer_number(&mut numbers, &seen.peer), describe_peer(&seen.peer, &persist));
+                        }
+                        Some(PeerEvent::Stopped(e)) => anyhow::bail!("discovery failed: {}", e),
+                        None => anyhow::bail!("discovery stopped"),
+                    },
+                    line = console::read_line() => {
+                        let Some(line) = line else { return Ok(()) };
+                        let choice = line.trim();
+                        if choice == "q" {
+                            return Ok(());
+                        }
+                        let peers = service.peers();
+                        if choice.is_empty() {
+                            if peers.is_empty() {
+                                println!("No peers found yet...");
+                            }
+                            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                            for seen in &peers {
+                                let ago = now.saturating_sub(seen.last_seen);
+                                println!("  [{}] {} - seen {}s ago", peer_number(&mut numbers, &seen.peer), describe_peer(&seen.peer, &persist), ago);
+                            }
+                            continue;
+                        }
+                        let key = choice.parse::<usize>().ok().and_then(|n| numbers.get(n));
+                        match peers.into_iter().find(|seen| key == Some(&peer_key(&seen.peer))) {
+                            Some(seen) => break seen.peer,
+                            None => println!("No peer [{}] is listed.", choice),
                         }
                     }
                 }
-            }
+            };
+            drop(service);
+            let peer_addr = peer.addr;
 
-            if discovered_peers.is_empty() {
-                println!("No peers found.");
-                return Ok(());
-            }
-
-            // Present the list of discovered peers to the user
-            let peers: Vec<_> = discovered_peers.into_iter().collect();
-            println!("\nDiscovered peers:");
-            for (i, peer) in peers.iter().enumerate() {
-                println!("  [{}] {}", i, peer);
-            }
-
-            // Prompt the user to select a peer to connect to
-            print!("\nEnter the number of the peer to connect to (or 'q' to quit): ");
-            stdout().flush()?;
-
-            let mut choice = String::new();
-            stdin().read_line(&mut choice)?;
-
-            if choice.trim() == "q" {
-                return Ok(());
-            }
-
-            // Parse the user's choice and connect to the selected peer
-            if let Ok(n) = choice.trim().parse::<usize>() {
-                if n < peers.len() {
-                    let peer_addr = peers[n];
-                    // Prompt the user for an optional alias for the peer
+            // A peer saved with the announced key keeps its alias. Otherwise, prompt the
+            // user for an optional alias for the peer.
+            let alias = match persist.get_peer_by_key(&peer.announcement.identity) {
+                Some(saved) => saved.name.clone(),
+                None => {
                     print!("Enter an alias for this peer (optional): ");
                     stdout().flush()?;
-                    let mut alias = String::new();
-                    stdin().read_line(&mut alias)?;
-                    let alias = alias.trim();
+                    let alias = console::read_line().await.unwrap_or_default().trim().to_string();
 
                     // If an alias is provided, save the peer to the persisted data
                     if !alias.is_empty() {
-                        persist.add_peer(alias.to_string(), peer_addr.to_string());
-                        persist.save()?;
+                        Persist::update(|p| {
+                            p.add_peer(alias.clone(), peer_addr.to_string());
+                            true
+                        })?;
                         println!("Peer '{}' saved.", alias);
                     }
-
-                    // Connect to the selected peer
-                    println!("Connecting to {}...", peer_addr);
-                    start_client(&peer_addr.to_string()).await?;
-                } else {
-                    eprintln!("Invalid selection.");
+                    alias
                 }
-            } else {
-                eprintln!("Invalid input.");
-            }
+            };
+
+            // Connect to the selected peer
+            println!("Connecting to {}...", peer_addr);
+            let alias = if alias.is_empty() { None } else { Some(alias.as_str()) };
+            chat(start_client(&peer_addr.to_string(), identity, alias, FramingConfig::default(), unlock_history())).await?;
         }
 
-        "add-peer" => {
-            if args.len() != 4 {
-                eprintln!("Usage: {} add-peer <ALIAS> <ADDR:PORT>", args[0]);
-                return Ok(());
-            }
+        Command::AddPeer { alias, addr } => {
             // Add the peer to the persisted data and save it to the configuration file
-            persist.add_peer(args[2].clone(), args[3].clone());
-            persist.save()?;
-            println!("Peer '{}' added.", args[2]);
+            let addr = parse_addr(&addr, cli.port)?;
+            Persist::update(|p| {
+                p.add_peer(alias.clone(), addr);
+                true
+            })?;
+            println!("Peer '{}' added.", alias);
         }
 
-        "list-peers" => {
+        Command::ListPeers => {
             // List all the saved peers
             println!("Saved peers:");
             for peer in persist.list_peers() {
-                println!("  - {}: {}", peer.name, peer.addr);
+                let status = if peer.verified { "verified" } else { "⚠ unverified" };
+                let mut line = format!("  - {}: {} ({})", peer.name, peer.addr, status);
+                if let Some(known) = &peer.status {
+                    let since = chrono::DateTime::from_timestamp(known.since as i64, 0)
+                        .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+                        .unwrap_or_default();
+                    line.push_str(&format!(" - {} since {}", known.status, since));
+                }
+                println!("{}", line);
             }
         }
 
-        _ => {
-            eprintln!("Unknown command: {}", args[1]);
+        Command::Verify { alias } => {
+            use std::io::{stdin, stdout, Write};
+
+            let Some(peer) = persist.get_peer(&alias) else {
+                anyhow::bail!("unknown peer '{}'", alias);
+            };
+            let Some(peer_key) = peer.pubkey_b64.clone() else {
+                anyhow::bail!("no identity key pinned for '{}' yet, connect to them first", alias);
+            };
+
+            // Show the safety number derived from both identity keys
+            let safety = SafetyNumber::new(&identity.public_b64(), &peer_key)?;
+            println!("Safety number with '{}':\n{}\n", alias, safety);
+            println!("{}\n", safety.emoji_line());
+            if peer.verified {
+                println!("'{}' is already verified.", alias);
+                return Ok(());
+            }
+
+            // Ask the user to confirm the comparison before marking the peer as verified
+            print!("Does this match what '{}' sees? Mark as verified? [y/N]: ", alias);
+            stdout().flush()?;
+            let mut answer = String::new();
+            stdin().read_line(&mut answer)?;
+            if answer.trim().eq_ignore_ascii_case("y") {
+                Persist::update(|p| p.mark_verified(&alias))?;
+                println!("'{}' marked as verified.", alias);
+            } else {
+                println!("'{}' left unverified.", alias);
+            }
         }
+
+        Command::DownloadDir { dir } => {
+            // Without a directory, show where received files are saved
+            if let Some(dir) = dir {
+                let dir = env::current_dir()?.join(dir);
+                Persist::update(|p| {
+                    p.download_dir = Some(dir.clone());
+                    true
+                })?;
+                persist.download_dir = Some(dir);
+            }
+            println!("Received files are saved in {}", persist.download_dir()?.display());
+        }
+
+        Command::History { alias, since, grep } => {
+            let Some(peer_key) = persist.get_peer(&alias).and_then(|p| p.pubkey_b64.clone()) else {
+                anyhow::bail!("no history with '{}', connect to them first", alias);
+            };
+            if !History::exists() {
+                println!("No chat history has been kept yet.");
+                return Ok(());
+            }
+            let Some(passphrase) = read_passphrase("History passphrase: ") else {
+                anyhow::bail!("could not read the passphrase");
+            };
+            let history = History::open(&passphrase).map_err(|e| anyhow::anyhow!("could not open the chat history: {}", e))?;
+
+            // Show the matching messages, oldest first
+            let own_id = identity.public_b64();
+            let own_name = cli.nickname.as_deref().unwrap_or("You");
+            let grep = grep.map(|grep| grep.to_lowercase());
+            for entry in history.load(&peer_key)? {
+                if since.is_some_and(|since| entry.timestamp < since) {
+                    continue;
+                }
+                if grep.as_ref().is_some_and(|grep| !entry.text.to_lowercase().contains(grep.as_str())) {
+                    continue;
+                }
+                let sender = match persist.get_peer_by_key(&entry.sender_id) {
+                    _ if entry.sender_id == own_id => own_name.to_string(),
+                    Some(peer) => peer.name.clone(),
+                    None => format!("{}…", entry.sender_id.chars().take(8).collect::<String>()),
+                };
+                let time = chrono::DateTime::from_timestamp(entry.timestamp as i64, 0)
+                    .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default();
+                println!("{} {}: {}", time, sender, entry.text);
+            }
+        }
+
+        Command::Completions { .. } => unreachable!("handled above"),
     }
 
     Ok(())
 }
+
+/// Runs a chat session, in the full-screen UI if built with the `tui` feature.
+async fn chat(session: impl Future<Output = anyhow::Result<()>>) -> anyhow::Result<()> {
+    #[cfg(feature = "tui")]
+    return tui::run(session).await;
+    #[cfg(not(feature = "tui"))]
+    session.await
+}
+
+/// Identifies a discovered peer by its identity key and listening port.
+fn peer_key(peer: &FoundPeer) -> (String, u16) {
+    (peer.announcement.identity.clone(), peer.announcement.port)
+}
+
+/// Returns the number of a discovered peer in the picker. Peers are numbered in the
+/// order they first appeared.
+fn peer_number(numbers: &mut Vec<(String, u16)>, peer: &FoundPeer) -> usize {
+    let key = peer_key(peer);
+    match numbers.iter().position(|k| *k == key) {
+        Some(number) => number,
+        None => {
+            numbers.push(key);
+            numbers.len() - 1
+        }
+    }
+}
+
+/// Describes a discovered peer: its address, nickname, key fingerprint and protocol
+/// version, and whether its key matches a saved peer. A saved peer at the same address
+/// with a different pinned key is flagged.
+fn describe_peer(peer: &FoundPeer, persist: &Persist) -> String {
+    let announcement = &peer.announcement;
+    let name = announcement.nickname.as_deref().unwrap_or("(no nickname)");
+    let mut line = format!("{} {}", peer.addr, name);
+    if let Some(status) = &announcement.status {
+        line.push_str(&format!(" [{}]", status));
+    }
+    line.push_str(&format!(" (key {}, protocol v{})", peer.fingerprint, announcement.version));
+    if let Some(saved) = persist.get_peer_by_key(&announcement.identity) {
+        let status = if saved.verified { "verified" } else { "⚠ unverified" };
+        line.push_str(&format!(" - saved as '{}', key matches, {}", saved.name, status));
+    } else if let Some(saved) = persist.get_peer_by_addr(&peer.addr.to_string()).filter(|p| p.pubkey_b64.is_some()) {
+        line.push_str(&format!(" - ⚠ key differs from saved peer '{}'", saved.name));
+    }
+    line
+}
+
+/// Unlocks the chat history for a chat session. The first time, the user chooses the
+/// passphrase. Returns `None`, and no history is kept, if the passphrase is left
+/// empty, there is no terminal to ask on, or the passphrase is wrong three times.
+fn unlock_history() -> Option<History> {
+    let exists = History::exists();
+    let prompt = if exists {
+        "History passphrase (leave empty to chat without history): "
+    } else {
+        "Choose a passphrase to keep an encrypted chat history (leave empty to skip): "
+    };
+    for _ in 0..3 {
+        let passphrase = read_passphrase(prompt)?;
+        if passphrase.is_empty() {
+            return None;
+        }
+        if !exists && read_passphrase("Repeat the passphrase: ")? != passphrase {
+            eprintln!("The passphrases do not match.");
+            continue;
+        }
+        match History::open(&passphrase) {
+            Ok(history) => return Some(history),
+            Err(e) => eprintln!("Could not open the chat history: {}", e),
+        }
+        if env::var(PASSPHRASE_ENV).is_ok() {
+            break;
+        }
+    }
+    None
+}
+
+/// Returns the history passphrase from `P2P_CHAT_PASSPHRASE`, or asks for it on the
+/// terminal without echoing it.
+fn read_passphrase(prompt: &str) -> Option<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Some(passphrase);
+    }
+    rpassword::prompt_password(prompt).ok()
+}
+
+/// Parses the argument of `history --since` into a Unix timestamp. It is either a
+/// date (`2024-05-01`), a date and time (`2024-05-01 14:30`), or an age in minutes,
+/// hours, days or weeks (`30m`, `12h`, `7d`, `2w`).
+fn parse_since(arg: &str) -> Result<u64, String> {
+    use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+
+    const INVALID: &str = "use a date like 2024-05-01, or an age like 30m, 12h or 7d";
+    let start = match NaiveDate::parse_from_str(arg, "%Y-%m-%d") {
+        Ok(date) => date.and_hms_opt(0, 0, 0),
+        Err(_) => NaiveDateTime::parse_from_str(arg, "%Y-%m-%d %H:%M").ok(),
+    };
+    if let Some(start) = start {
+        let start = Local.from_local_datetime(&start).earliest().ok_or(INVALID)?;
+        return u64::try_from(start.timestamp()).map_err(|_| INVALID.to_string());
+    }
+
+    let unit = match arg.chars().last() {
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        Some('w') => 7 * 24 * 60 * 60,
+        _ => return Err(INVALID.to_string()),
+    };
+    let count: u64 = arg[..arg.len() - 1].parse().map_err(|_| INVALID)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| e.to_string())?
+        .as_secs();
+    Ok(now.saturating_sub(count.saturating_mul(unit)))
+}
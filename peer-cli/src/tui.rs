@@ -0,0 +1,330 @@
+//! The full-screen terminal UI, built with the `tui` feature. Messages scroll in a
+//! pane of their own, so incoming messages no longer break the line being typed. A
+//! sidebar lists the conversations and a status bar shows the state of the active
+//! one.
+
+use std::future::Future;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use ansi_to_tui::IntoText;
+use peer_core::console::{self, ConversationStatus, Status};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::Frame;
+use tokio::sync::mpsc;
+
+/// How many lines the message pane keeps.
+const MAX_LINES: usize = 2000;
+
+/// How often the sidebar and the status bar are refreshed while nothing happens.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The width of the sidebar, borders included.
+const SIDEBAR_WIDTH: u16 = 30;
+
+/// Runs a chat session in the full-screen UI until it ends. Without a terminal, the
+/// session runs on the plain console.
+pub async fn run(session: impl Future<Output = anyhow::Result<()>>) -> anyhow::Result<()> {
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        return session.await;
+    }
+    let (output_tx, mut output) = mpsc::unbounded_channel();
+    let Some(input) = console::attach(output_tx) else {
+        return session.await;
+    };
+
+    let mut terminal = ratatui::init();
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut events = read_events(stop.clone());
+    let mut tick = tokio::time::interval(STATUS_INTERVAL);
+    let mut ui = Ui::default();
+    tokio::pin!(session);
+    let result = loop {
+        tokio::select! {
+            result = &mut session => break result,
+            Some(line) = output.recv() => {
+                ui.push(&line);
+                ui.status = console::status();
+            }
+            Some(event) = events.recv() => ui.handle_event(event, &input),
+            _ = tick.tick() => ui.status = console::status(),
+        }
+        if let Err(e) = terminal.draw(|frame| ui.draw(frame)) {
+            break Err(e.into());
+        }
+    };
+    stop.store(true, Ordering::SeqCst);
+    ratatui::restore();
+
+    // Whatever was written while the session closed is shown on the plain console.
+    while let Ok(line) = output.try_recv() {
+        println!("{}", line);
+    }
+    result
+}
+
+/// Reads terminal events on a thread of its own until `stop` is set.
+fn read_events(stop: Arc<AtomicBool>) -> mpsc::UnboundedReceiver<Event> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            match event::poll(Duration::from_millis(100)) {
+                Ok(true) => match event::read() {
+                    Ok(event) => {
+                        if tx.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                },
+                Ok(false) => {}
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+/// Turns a line of console output, which may contain color codes, into styled text.
+fn styled(line: &str) -> Line<'static> {
+    line.into_text()
+        .ok()
+        .and_then(|text| text.lines.into_iter().next())
+        .unwrap_or_else(|| Line::raw(line.to_string()))
+}
+
+/// The state of the UI between frames.
+#[derive(Default)]
+struct Ui {
+    /// The lines of the message pane, oldest first.
+    lines: Vec<Line<'static>>,
+    /// How many rows the message pane is scrolled up from the latest message.
+    scroll: usize,
+    /// The height of the message pane when it was last drawn.
+    page: usize,
+    /// The line being typed, and the cursor position in it in characters.
+    input: String,
+    cursor: usize,
+    /// The lines typed so far, oldest first, and the one recalled with Up and Down.
+    typed: Vec<String>,
+    recalled: Option<usize>,
+    status: Status,
+}
+
+impl Ui {
+    /// Adds a line of output to the message pane. If the pane is scrolled up, it
+    /// keeps showing the same lines.
+    fn push(&mut self, line: &str) {
+        self.lines.push(styled(line));
+        if self.lines.len() > MAX_LINES {
+            self.lines.remove(0);
+        }
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    /// Handles a key press. Typed lines are sent on `input`.
+    fn handle_event(&mut self, event: Event, input: &mpsc::UnboundedSender<String>) {
+        let Event::Key(key) = event else { return };
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let len = self.input.chars().count();
+        match key.code {
+            KeyCode::Char('c') if ctrl => {
+                let _ = input.send("/quit".to_string());
+            }
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = len,
+            KeyCode::Char('u') if ctrl => {
+                self.input.replace_range(..self.byte_index(self.cursor), "");
+                self.cursor = 0;
+            }
+            KeyCode::Char('w') if ctrl => {
+                let before: Vec<char> = self.input.chars().take(self.cursor).collect();
+                let spaces = before.iter().rev().take_while(|c| c.is_whitespace()).count();
+                let word = before.iter().rev().skip(spaces).take_while(|c| !c.is_whitespace()).count();
+                let start = self.cursor - spaces - word;
+                self.input.replace_range(self.byte_index(start)..self.byte_index(self.cursor), "");
+                self.cursor = start;
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.input.insert(self.byte_index(self.cursor), c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.byte_index(self.cursor));
+            }
+            KeyCode::Delete if self.cursor < len => {
+                self.input.remove(self.byte_index(self.cursor));
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(len),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = len,
+            KeyCode::Up => self.recall(true),
+            KeyCode::Down => self.recall(false),
+            KeyCode::PageUp => self.scroll += (self.page / 2).max(1),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub((self.page / 2).max(1)),
+            KeyCode::Tab => {
+                if let Some(next) = self.next_conversation() {
+                    let _ = input.send(format!("/switch {}", next));
+                }
+            }
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                self.cursor = 0;
+                self.recalled = None;
+                self.scroll = 0;
+                if line.trim().is_empty() {
+                    return;
+                }
+                if self.typed.last() != Some(&line) {
+                    self.typed.push(line.clone());
+                }
+                let _ = input.send(line);
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the byte offset of the character at `cursor` in the input.
+    fn byte_index(&self, cursor: usize) -> usize {
+        self.input.char_indices().nth(cursor).map_or(self.input.len(), |(i, _)| i)
+    }
+
+    /// Replaces the input with an earlier line (`back`) or a later one.
+    fn recall(&mut self, back: bool) {
+        let recalled = match (self.recalled, back) {
+            (None, true) => self.typed.len().checked_sub(1),
+            (None, false) => return,
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) => Some(i + 1).filter(|&i| i < self.typed.len()),
+        };
+        self.recalled = recalled;
+        self.input = recalled.map(|i| self.typed[i].clone()).unwrap_or_default();
+        self.cursor = self.input.chars().count();
+    }
+
+    /// Returns the name of the conversation after the active one, if there are
+    /// several.
+    fn next_conversation(&self) -> Option<String> {
+        let names: Vec<&String> = self.status.conversations.iter().map(|c| &c.name).collect();
+        if names.len() < 2 {
+            return None;
+        }
+        let active = names.iter().position(|name| Some(*name) == self.status.active.as_ref());
+        let next = active.map_or(0, |i| (i + 1) % names.len());
+        Some(names[next].clone())
+    }
+
+    /// Returns the state of the active conversation.
+    fn active(&self) -> Option<&ConversationStatus> {
+        let active = self.status.active.as_ref()?;
+        self.status.conversations.iter().find(|c| &c.name == active)
+    }
+
+    /// Draws the whole screen.
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(1), Constraint::Length(3)]).areas(frame.area());
+        let [messages, sidebar] = Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)]).areas(main);
+        self.draw_messages(frame, messages);
+        self.draw_sidebar(frame, sidebar);
+        self.draw_status(frame, status);
+        self.draw_input(frame, input);
+    }
+
+    /// Draws the message pane, scrolled `scroll` rows up from the latest message.
+    fn draw_messages(&mut self, frame: &mut Frame, area: Rect) {
+        let inner = Block::bordered().inner(area);
+        let paragraph = Paragraph::new(self.lines.clone()).wrap(Wrap { trim: false });
+        let rows = paragraph.line_count(inner.width);
+        let height = usize::from(inner.height);
+        self.page = height;
+        self.scroll = self.scroll.min(rows.saturating_sub(height));
+        let top = rows.saturating_sub(height) - self.scroll;
+
+        let title = if self.scroll > 0 { " Messages (PgDn for newer) " } else { " Messages " };
+        let paragraph = paragraph
+            .block(Block::bordered().title(title))
+            .scroll((u16::try_from(top).unwrap_or(u16::MAX), 0));
+        frame.render_widget(paragraph, area);
+    }
+
+    /// Draws the list of conversations, with the members of rooms.
+    fn draw_sidebar(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = Vec::new();
+        for conversation in &self.status.conversations {
+            let active = self.status.active.as_ref() == Some(&conversation.name);
+            let (marker, name) = if active {
+                ("* ", conversation.name.clone().cyan().bold())
+            } else {
+                ("  ", conversation.name.clone().cyan())
+            };
+            lines.push(Line::from(vec![Span::raw(marker), name]));
+            lines.push(Line::from(format!("  {}", conversation.state).dim()));
+            for member in &conversation.members {
+                lines.push(Line::from(format!("  · {}", member)));
+            }
+        }
+        if lines.is_empty() {
+            lines.push(Line::from("No conversations".dim()));
+        }
+        let sidebar = Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(" Conversations "));
+        frame.render_widget(sidebar, area);
+    }
+
+    /// Draws the status bar: the active conversation, its state and its cipher.
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let mut spans = Vec::new();
+        match self.active() {
+            Some(conversation) => {
+                let color = if conversation.state.starts_with("reconnecting") {
+                    Color::Yellow
+                } else if conversation.state.contains("unverified") {
+                    Color::LightRed
+                } else {
+                    Color::LightGreen
+                };
+                spans.push(Span::raw(format!(" {} ", conversation.name)).bold());
+                spans.push(Span::raw(format!("│ {} ", conversation.state)).fg(color));
+                if let Some(cipher) = &conversation.cipher {
+                    spans.push(Span::raw(format!("│ 🔒 {} ", cipher)));
+                }
+            }
+            None => spans.push(Span::raw(" Not in a conversation ")),
+        }
+        spans.push(Span::raw("│ PgUp/PgDn scroll · Tab switch · Ctrl+C quit"));
+        let bar = Paragraph::new(Line::from(spans)).style(Style::new().bg(Color::DarkGray).fg(Color::White));
+        frame.render_widget(bar, area);
+    }
+
+    /// Draws the input line after the console's prompt, scrolled so the cursor stays
+    /// visible.
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered();
+        let inner = block.inner(area);
+        let prompt = styled(&console::prompt());
+        let available = usize::from(inner.width).saturating_sub(prompt.width() + 1);
+        let start = self.cursor.saturating_sub(available);
+        let before: String = self.input.chars().skip(start).take(self.cursor - start).collect();
+        let visible: String = self.input.chars().skip(start).take(available).collect();
+        let x = prompt.width() + Span::raw(before).width();
+
+        let mut line = prompt;
+        line.push_span(Span::raw(visible));
+        frame.render_widget(Paragraph::new(line).block(block), area);
+        frame.set_cursor_position(Position::new(inner.x + u16::try_from(x).unwrap_or(inner.width), inner.y));
+    }
+}
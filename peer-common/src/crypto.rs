@@ -1,36 +1,97 @@
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     XChaCha20Poly1305,
 };
+use aes_gcm::Aes256Gcm;
 use rand::{Rng, rngs::OsRng};
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use x25519_dalek::{PublicKey, StaticSecret};
 use sha2::{Sha256, Digest};
+use hkdf::Hkdf;
 use base64::{engine::general_purpose, Engine as _};
+use crate::error::CryptoError;
+use crate::CipherType;
 
 /// Generates a new ephemeral keypair for the X25519 elliptic curve Diffie-Hellman
 /// key exchange. This function is used to create a new set of keys for each chat
-/// session, ensuring forward secrecy.
-pub fn generate_keypair() -> (EphemeralSecret, PublicKey) {
-    let secret = EphemeralSecret::random_from_rng(OsRng);
+/// session, ensuring forward secrecy. The secret is reusable because it also serves
+/// as the first key of the session's Double Ratchet.
+pub fn generate_keypair() -> (StaticSecret, PublicKey) {
+    let secret = StaticSecret::random_from_rng(OsRng);
     let public = PublicKey::from(&secret);
     (secret, public)
 }
 
-/// Derives a shared secret from the local private key and the peer's public key.
-/// This shared secret is then hashed using SHA-256 to create a 32-byte session key.
-/// The session key is used for symmetric encryption of the chat messages.
-pub fn derive_shared_key(secret: EphemeralSecret, peer_pub: &PublicKey) -> [u8; 32] {
+/// The keys produced by the handshake key schedule. Each direction of the
+/// conversation has its own key, so a ciphertext sent by one peer can never be
+/// accepted when reflected back at it. The directional keys and the root key seed
+/// the session's Double Ratchet. The session ID identifies the session and is
+/// identical on both sides.
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub root_key: [u8; 32],
+    pub send_key: [u8; 32],
+    pub recv_key: [u8; 32],
+    pub session_id: [u8; 32],
+}
+
+/// Hashes the handshake transcript with SHA-256. Each part is length-prefixed so
+/// that different splits of the same bytes can never produce the same hash. The
+/// parts must be given in the same order on both sides.
+pub fn transcript_hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u32).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Derives the session keys from the local private key, the peer's public key and
+/// the handshake transcript hash. The X25519 shared secret is fed through HKDF-SHA256
+/// salted with the transcript hash, so any tampering with the handshake yields
+/// different keys on each side. `is_client` selects which of the two directional
+/// keys is used for sending.
+pub fn derive_session_keys(secret: &StaticSecret, peer_pub: &PublicKey, transcript: &[u8; 32], is_client: bool) -> SessionKeys {
     let shared_secret = secret.diffie_hellman(peer_pub);
-    let hash = Sha256::digest(shared_secret.as_bytes());
+    let hk = Hkdf::<Sha256>::new(Some(transcript), shared_secret.as_bytes());
+
+    let mut root_key = [0u8; 32];
+    let mut client_key = [0u8; 32];
+    let mut listener_key = [0u8; 32];
+    let mut session_id = [0u8; 32];
+    hk.expand(b"p2p-chat root key", &mut root_key)
+        .expect("32 bytes is a valid HKDF output length");
+    hk.expand(b"p2p-chat client->listener", &mut client_key)
+        .expect("32 bytes is a valid HKDF output length");
+    hk.expand(b"p2p-chat listener->client", &mut listener_key)
+        .expect("32 bytes is a valid HKDF output length");
+    hk.expand(b"p2p-chat session id", &mut session_id)
+        .expect("32 bytes is a valid HKDF output length");
+
+    let (send_key, recv_key) = if is_client {
+        (client_key, listener_key)
+    } else {
+        (listener_key, client_key)
+    };
+    SessionKeys { root_key, send_key, recv_key, session_id }
+}
+
+/// Derives a 32-byte key from a passphrase with Argon2id, which makes guessing the
+/// passphrase from data encrypted under the key slow. `salt` must be random and is
+/// stored alongside that data; it should be at least 16 bytes long.
+pub fn derive_passphrase_key(passphrase: &[u8], salt: &[u8]) -> Result<[u8; 32], CryptoError> {
     let mut key = [0u8; 32];
-    key.copy_from_slice(&hash);
-    key
+    argon2::Argon2::default()
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|_| CryptoError::KeyDerivationFailed)?;
+    Ok(key)
 }
 
 /// Encrypts a message using the XChaCha20-Poly1305 AEAD (Authenticated Encryption
-/// with Associated Data) algorithm. This function takes a 32-byte session key and
-/// a plaintext message, and returns the ciphertext and a 24-byte nonce.
-pub fn encrypt_message(key: &[u8; 32], plaintext: &[u8]) -> (Vec<u8>, [u8; 24]) {
+/// with Associated Data) algorithm. This function takes a 32-byte session key, a
+/// plaintext message and the associated data to authenticate alongside it, and
+/// returns the ciphertext and a 24-byte nonce.
+pub fn encrypt_message(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, [u8; 24]), CryptoError> {
     let cipher = XChaCha20Poly1305::new(key.into());
 
     let mut rng = rand::thread_rng();
@@ -38,21 +99,52 @@ pub fn encrypt_message(key: &[u8; 32], plaintext: &[u8]) -> (Vec<u8>, [u8; 24])
     rng.fill(&mut nonce_bytes);
     let nonce = &nonce_bytes.into();
 
-    let ciphertext = cipher.encrypt(nonce, plaintext)
-        .expect("encryption failure!");
+    let ciphertext = cipher.encrypt(nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| CryptoError::EncryptionFailed(CipherType::XChaCha20Poly1305))?;
 
-    (ciphertext, nonce_bytes)
+    Ok((ciphertext, nonce_bytes))
 }
 
 /// Decrypts a message using the XChaCha20-Poly1305 AEAD algorithm. This function
-/// takes a 32-byte session key, the ciphertext, and the 24-byte nonce that was
-/// used to encrypt the message. It returns the original plaintext message.
-pub fn decrypt_message(key: &[u8; 32], ciphertext: &[u8], nonce_bytes: &[u8; 24]) -> Vec<u8> {
+/// takes a 32-byte session key, the ciphertext, the 24-byte nonce that was used to
+/// encrypt the message and the associated data. It returns the original plaintext
+/// message, or `CryptoError::AuthenticationFailed` if the message does not
+/// authenticate.
+pub fn decrypt_message(key: &[u8; 32], ciphertext: &[u8], nonce_bytes: &[u8; 24], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
     let cipher = XChaCha20Poly1305::new(key.into());
     let nonce = (*nonce_bytes).into();
 
-    cipher.decrypt(&nonce, ciphertext)
-        .expect("decryption failure!")
+    cipher.decrypt(&nonce, Payload { msg: ciphertext, aad })
+        .map_err(|_| CryptoError::AuthenticationFailed)
+}
+
+/// Encrypts a message using the AES-256-GCM AEAD algorithm. This function takes a
+/// 32-byte session key, a plaintext message and the associated data, and returns
+/// the ciphertext and a 12-byte nonce.
+pub fn encrypt_message_aes(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, [u8; 12]), CryptoError> {
+    let cipher = Aes256Gcm::new(key.into());
+
+    let mut rng = rand::thread_rng();
+    let mut nonce_bytes = [0u8; 12];
+    rng.fill(&mut nonce_bytes);
+    let nonce = &nonce_bytes.into();
+
+    let ciphertext = cipher.encrypt(nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| CryptoError::EncryptionFailed(CipherType::AES256GCM))?;
+
+    Ok((ciphertext, nonce_bytes))
+}
+
+/// Decrypts a message using the AES-256-GCM AEAD algorithm. This function takes a
+/// 32-byte session key, the ciphertext, the 12-byte nonce that was used to encrypt
+/// the message and the associated data. It returns the original plaintext message,
+/// or `CryptoError::AuthenticationFailed` if the message does not authenticate.
+pub fn decrypt_message_aes(key: &[u8; 32], ciphertext: &[u8], nonce_bytes: &[u8; 12], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let cipher = Aes256Gcm::new(key.into());
+    let nonce = (*nonce_bytes).into();
+
+    cipher.decrypt(&nonce, Payload { msg: ciphertext, aad })
+        .map_err(|_| CryptoError::AuthenticationFailed)
 }
 
 /// Encodes a public key into a base64 string. This is used to transmit the public
@@ -63,9 +155,14 @@ pub fn pubkey_to_b64(pubkey: &PublicKey) -> String {
 
 /// Decodes a base64 string into a public key. This is used to receive a public
 /// key from a peer over the network.
-pub fn pubkey_from_b64(b64: &str) -> anyhow::Result<PublicKey> {
-    let bytes = general_purpose::STANDARD.decode(b64)?;
-    let array: [u8; 32] = bytes.try_into().map_err(|_| anyhow::anyhow!("Invalid public key length"))?;
+pub fn pubkey_from_b64(b64: &str) -> Result<PublicKey, CryptoError> {
+    let bytes = general_purpose::STANDARD.decode(b64).map_err(|_| CryptoError::BadKeyEncoding("public key"))?;
+    pubkey_from_bytes(&bytes)
+}
+
+/// Converts a raw 32-byte slice to a `PublicKey`.
+pub fn pubkey_from_bytes(bytes: &[u8]) -> Result<PublicKey, CryptoError> {
+    let array: [u8; 32] = bytes.try_into().map_err(|_| CryptoError::BadKeyEncoding("public key"))?;
     Ok(PublicKey::from(array))
 }
 
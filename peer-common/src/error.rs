@@ -0,0 +1,85 @@
+use crate::CipherType;
+use std::fmt;
+
+/// The errors that can occur while establishing or using a secure session. None of
+/// these are fatal to the process: a frame that fails to decrypt is simply dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CryptoError {
+    /// A nonce had the wrong length for the session's cipher.
+    BadNonceLength { expected: usize, actual: usize },
+    /// A frame failed AEAD authentication: it was tampered with, truncated, or
+    /// encrypted under a different key.
+    AuthenticationFailed,
+    /// Encryption with the session's cipher failed.
+    EncryptionFailed(CipherType),
+    /// The peer offered no cipher we support.
+    UnsupportedCipher(Vec<CipherType>),
+    /// A public key, identity key or signature was not valid base64 or had the wrong
+    /// length.
+    BadKeyEncoding(&'static str),
+    /// A signature did not verify against the peer's identity key.
+    BadSignature,
+    /// A frame's counter was already accepted.
+    Replay(u64),
+    /// A frame's counter is too old for the replay window.
+    StaleCounter(u64),
+    /// A ratchet header asked us to skip more message keys than allowed.
+    TooManySkipped(u32),
+    /// A ratchet header named a message key of the current chain that was already
+    /// used.
+    MessageKeyUsed(u32),
+    /// A frame was encrypted under a key epoch we no longer (or never) had.
+    UnknownEpoch(u32),
+    /// A rekey frame was for an unexpected key epoch.
+    UnexpectedRekey { epoch: u32, current: u32 },
+    /// A key could not be derived from a passphrase.
+    KeyDerivationFailed,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::BadNonceLength { expected, actual } => write!(f, "bad nonce length: expected {} bytes, got {}", expected, actual),
+            CryptoError::AuthenticationFailed => write!(f, "message authentication failed"),
+            CryptoError::EncryptionFailed(cipher) => write!(f, "{} encryption failed", cipher),
+            CryptoError::UnsupportedCipher(offered) => write!(f, "no supported cipher in {:?}", offered),
+            CryptoError::BadKeyEncoding(what) => write!(f, "invalid {} encoding", what),
+            CryptoError::BadSignature => write!(f, "signature verification failed"),
+            CryptoError::Replay(counter) => write!(f, "duplicate message counter {} (replay)", counter),
+            CryptoError::StaleCounter(counter) => write!(f, "message counter {} is outside the replay window", counter),
+            CryptoError::TooManySkipped(n) => write!(f, "too many skipped messages ({})", n),
+            CryptoError::MessageKeyUsed(n) => write!(f, "message key {} was already used", n),
+            CryptoError::UnknownEpoch(epoch) => write!(f, "frame for unknown or expired key epoch {}", epoch),
+            CryptoError::UnexpectedRekey { epoch, current } => write!(f, "unexpected rekey to epoch {} (current epoch {})", epoch, current),
+            CryptoError::KeyDerivationFailed => write!(f, "key derivation failed"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// The errors that can occur while encoding or decoding a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// A frame used a binary encoding version we do not know.
+    UnsupportedVersion(u8),
+    /// The peer only speaks protocol versions we no longer support.
+    UnsupportedProtocol(u32),
+    /// A message could not be encoded.
+    Encode(String),
+    /// A frame could not be decoded.
+    Decode(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::UnsupportedVersion(tag) => write!(f, "unsupported wire format version {}", tag),
+            WireError::UnsupportedProtocol(v) => write!(f, "unsupported protocol version {}", v),
+            WireError::Encode(e) => write!(f, "failed to encode frame: {}", e),
+            WireError::Decode(e) => write!(f, "failed to decode frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
@@ -0,0 +1,164 @@
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha512};
+use std::fmt;
+use crate::error::CryptoError;
+
+/// Domain separation labels for the two independent fingerprint hashes.
+const DIGITS_CONTEXT: &[u8] = b"p2p-chat-safety-number-v1";
+const EMOJI_CONTEXT: &[u8] = b"p2p-chat-safety-emoji-v1";
+
+/// Domain separation label for the fingerprint of a single identity key.
+const KEY_CONTEXT: &[u8] = b"p2p-chat-key-fingerprint-v1";
+
+/// The number of 4-digit hex groups in a key fingerprint.
+const KEY_GROUPS: usize = 8;
+
+/// The number of 5-digit groups in a safety number.
+const DIGIT_GROUPS: usize = 12;
+
+/// The number of emoji shown in the short form of a safety number.
+const EMOJI_COUNT: usize = 8;
+
+/// The 64 emoji (and their names) used for the short form of a safety number. Each
+/// emoji encodes 6 bits of the fingerprint hash.
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "Dog"), ("🐱", "Cat"), ("🦁", "Lion"), ("🐎", "Horse"),
+    ("🦄", "Unicorn"), ("🐷", "Pig"), ("🐘", "Elephant"), ("🐰", "Rabbit"),
+    ("🐼", "Panda"), ("🐓", "Rooster"), ("🐧", "Penguin"), ("🐢", "Turtle"),
+    ("🐟", "Fish"), ("🐙", "Octopus"), ("🦋", "Butterfly"), ("🌷", "Flower"),
+    ("🌳", "Tree"), ("🌵", "Cactus"), ("🍄", "Mushroom"), ("🌏", "Globe"),
+    ("🌙", "Moon"), ("☁️", "Cloud"), ("🔥", "Fire"), ("🍌", "Banana"),
+    ("🍎", "Apple"), ("🍓", "Strawberry"), ("🌽", "Corn"), ("🍕", "Pizza"),
+    ("🎂", "Cake"), ("❤️", "Heart"), ("😀", "Smiley"), ("🤖", "Robot"),
+    ("🎩", "Hat"), ("👓", "Glasses"), ("🔧", "Spanner"), ("🎅", "Santa"),
+    ("👍", "Thumbs Up"), ("☂️", "Umbrella"), ("⌛", "Hourglass"), ("⏰", "Clock"),
+    ("🎁", "Gift"), ("💡", "Light Bulb"), ("📕", "Book"), ("✏️", "Pencil"),
+    ("📎", "Paperclip"), ("✂️", "Scissors"), ("🔒", "Lock"), ("🔑", "Key"),
+    ("🔨", "Hammer"), ("☎️", "Telephone"), ("🏁", "Flag"), ("🚂", "Train"),
+    ("🚲", "Bicycle"), ("✈️", "Aeroplane"), ("🚀", "Rocket"), ("🏆", "Trophy"),
+    ("⚽", "Ball"), ("🎸", "Guitar"), ("🎺", "Trumpet"), ("🔔", "Bell"),
+    ("⚓", "Anchor"), ("🎧", "Headphones"), ("📁", "Folder"), ("📌", "Pin"),
+];
+
+/// A human-comparable fingerprint of the identity keys of two peers. Both peers
+/// compute the same safety number regardless of which side they are on, so it can
+/// be read aloud or compared in person to rule out a man-in-the-middle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    /// The numeric form, as twelve groups of five digits.
+    pub digits: Vec<String>,
+    /// The short form, as a list of emoji and their names.
+    pub emoji: Vec<(&'static str, &'static str)>,
+}
+
+impl SafetyNumber {
+    /// Computes the safety number for a pair of base64-encoded identity keys. The
+    /// order of the two keys does not matter.
+    pub fn new(local_b64: &str, remote_b64: &str) -> Result<Self, CryptoError> {
+        let decode = |b64: &str| general_purpose::STANDARD.decode(b64).map_err(|_| CryptoError::BadKeyEncoding("identity key"));
+        let mut keys = [decode(local_b64)?, decode(remote_b64)?];
+        keys.sort();
+
+        let digits_hash = hash_keys(DIGITS_CONTEXT, &keys);
+        let digits = digits_hash
+            .chunks(5)
+            .take(DIGIT_GROUPS)
+            .map(|chunk| {
+                let n = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+                format!("{:05}", n % 100_000)
+            })
+            .collect();
+
+        let emoji_hash = hash_keys(EMOJI_CONTEXT, &keys);
+        let bits = emoji_hash[..6].iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+        let emoji = (0..EMOJI_COUNT)
+            .map(|i| EMOJI[((bits >> (42 - 6 * i)) & 0x3f) as usize])
+            .collect();
+
+        Ok(SafetyNumber { digits, emoji })
+    }
+
+    /// Returns the short form as a single line of emoji followed by their names.
+    pub fn emoji_line(&self) -> String {
+        let icons: Vec<&str> = self.emoji.iter().map(|(icon, _)| *icon).collect();
+        let names: Vec<&str> = self.emoji.iter().map(|(_, name)| *name).collect();
+        format!("{}  ({})", icons.join(" "), names.join(", "))
+    }
+}
+
+impl fmt::Display for SafetyNumber {
+    /// Formats the numeric form as three rows of four groups.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows: Vec<String> = self.digits.chunks(4).map(|row| row.join(" ")).collect();
+        write!(f, "{}", rows.join("\n"))
+    }
+}
+
+/// Returns the fingerprint of a single base64-encoded identity key, as eight groups of
+/// four hex digits. It names the key in places where there is no second key to
+/// compute a safety number with, such as discovery announcements.
+pub fn key_fingerprint(identity_b64: &str) -> Result<String, CryptoError> {
+    let key = general_purpose::STANDARD.decode(identity_b64).map_err(|_| CryptoError::BadKeyEncoding("identity key"))?;
+    let mut hasher = Sha512::new();
+    hasher.update(KEY_CONTEXT);
+    hasher.update(&key);
+    let hash = hasher.finalize();
+    let groups: Vec<String> = hash
+        .chunks(2)
+        .take(KEY_GROUPS)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect();
+    Ok(groups.join(" "))
+}
+
+/// Hashes the (sorted) identity keys under the given context label.
+fn hash_keys(context: &[u8], keys: &[Vec<u8>; 2]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    hasher.update(context);
+    hasher.update(&keys[0]);
+    hasher.update(&keys[1]);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The identity keys `[1; 32]` and `[2; 32]`, base64-encoded.
+    const KEY_A: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+    const KEY_B: &str = "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=";
+
+    #[test]
+    fn both_peers_compute_the_same_safety_number() {
+        assert_eq!(SafetyNumber::new(KEY_A, KEY_B).unwrap(), SafetyNumber::new(KEY_B, KEY_A).unwrap());
+    }
+
+    #[test]
+    fn safety_number_is_stable_for_a_pair_of_keys() {
+        let safety = SafetyNumber::new(KEY_A, KEY_B).unwrap();
+        assert_eq!(
+            safety.digits,
+            ["62138", "91366", "85513", "63105", "12572", "09134", "40973", "78253", "02392", "14007", "21072", "33271"]
+        );
+        let names: Vec<&str> = safety.emoji.iter().map(|(_, name)| *name).collect();
+        assert_eq!(names, ["Tree", "Aeroplane", "Lock", "Heart", "Scissors", "Headphones", "Tree", "Pencil"]);
+        assert_eq!(safety.to_string(), "62138 91366 85513 63105\n12572 09134 40973 78253\n02392 14007 21072 33271");
+    }
+
+    #[test]
+    fn safety_number_differs_for_another_pair() {
+        assert_ne!(SafetyNumber::new(KEY_A, KEY_B).unwrap(), SafetyNumber::new(KEY_A, KEY_A).unwrap());
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        assert_eq!(SafetyNumber::new(KEY_A, "not base64!"), Err(CryptoError::BadKeyEncoding("identity key")));
+        assert_eq!(SafetyNumber::new("not base64!", KEY_B), Err(CryptoError::BadKeyEncoding("identity key")));
+        assert_eq!(key_fingerprint("not base64!"), Err(CryptoError::BadKeyEncoding("identity key")));
+    }
+
+    #[test]
+    fn key_fingerprint_is_stable() {
+        assert_eq!(key_fingerprint(KEY_A).unwrap(), "a4d9 b88b acc3 8c86 fc86 8d95 1793 4a9e");
+    }
+}
@@ -0,0 +1,130 @@
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use crate::error::CryptoError;
+
+/// Domain separation prefix mixed into every handshake signature, so a signature made
+/// by an identity key can never be replayed in a different context.
+const HANDSHAKE_CONTEXT: &[u8] = b"p2p-chat-handshake-v1";
+
+/// Domain separation prefix for in-session rekey signatures.
+const REKEY_CONTEXT: &[u8] = b"p2p-chat-rekey-v1";
+
+/// Domain separation prefix for signed discovery announcements.
+const ANNOUNCE_CONTEXT: &[u8] = b"p2p-chat-announce-v1";
+
+/// Represents the long-term Ed25519 identity of this install. Unlike the ephemeral
+/// X25519 keys, the identity key is persisted and reused across sessions so that
+/// peers can recognise each other and detect a man-in-the-middle.
+pub struct Identity {
+    signing: SigningKey,
+}
+
+impl Identity {
+    /// Generates a brand new random identity key.
+    pub fn generate() -> Self {
+        Identity {
+            signing: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Restores an identity from its base64-encoded 32-byte secret seed.
+    pub fn from_b64(b64: &str) -> Result<Self, CryptoError> {
+        let bytes = general_purpose::STANDARD.decode(b64.trim()).map_err(|_| CryptoError::BadKeyEncoding("identity key"))?;
+        let seed: [u8; 32] = bytes.try_into().map_err(|_| CryptoError::BadKeyEncoding("identity key"))?;
+        Ok(Identity {
+            signing: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// Encodes the secret seed as base64 so it can be written to disk.
+    pub fn to_b64(&self) -> String {
+        general_purpose::STANDARD.encode(self.signing.to_bytes())
+    }
+
+    /// Returns the base64-encoded public half of the identity. This is the value
+    /// that peers pin in their `PeerConfig::pubkey_b64`.
+    pub fn public_b64(&self) -> String {
+        general_purpose::STANDARD.encode(self.signing.verifying_key().as_bytes())
+    }
+
+    /// Signs an ephemeral handshake public key, proving that the sender owns this
+    /// identity. Returns the base64-encoded signature.
+    pub fn sign_handshake(&self, ephemeral_pub: &[u8]) -> String {
+        let sig = self.signing.sign(&handshake_message(ephemeral_pub));
+        general_purpose::STANDARD.encode(sig.to_bytes())
+    }
+
+    /// Signs a rekey ephemeral public key, binding it to the session being rekeyed and
+    /// the new key epoch. Returns the base64-encoded signature.
+    pub fn sign_rekey(&self, session_id: &[u8], epoch: u32, ephemeral_pub: &[u8]) -> String {
+        let sig = self.signing.sign(&rekey_message(session_id, epoch, ephemeral_pub));
+        general_purpose::STANDARD.encode(sig.to_bytes())
+    }
+
+    /// Signs a serialized discovery announcement, so peers that find it know it was
+    /// made by the owner of this identity. Returns the base64-encoded signature.
+    pub fn sign_announcement(&self, announcement: &[u8]) -> String {
+        let sig = self.signing.sign(&announcement_message(announcement));
+        general_purpose::STANDARD.encode(sig.to_bytes())
+    }
+}
+
+/// Verifies that `signature_b64` is a valid signature by `identity_b64` over the
+/// given ephemeral handshake public key.
+pub fn verify_handshake(identity_b64: &str, ephemeral_pub: &[u8], signature_b64: &str) -> Result<(), CryptoError> {
+    verify(identity_b64, &handshake_message(ephemeral_pub), signature_b64)
+}
+
+/// Verifies that `signature_b64` is a valid signature by `identity_b64` over the
+/// given rekey ephemeral public key, session ID and epoch.
+pub fn verify_rekey(identity_b64: &str, session_id: &[u8], epoch: u32, ephemeral_pub: &[u8], signature_b64: &str) -> Result<(), CryptoError> {
+    verify(identity_b64, &rekey_message(session_id, epoch, ephemeral_pub), signature_b64)
+}
+
+/// Verifies that `signature_b64` is a valid signature by `identity_b64` over the
+/// given serialized discovery announcement.
+pub fn verify_announcement(identity_b64: &str, announcement: &[u8], signature_b64: &str) -> Result<(), CryptoError> {
+    verify(identity_b64, &announcement_message(announcement), signature_b64)
+}
+
+/// Verifies an Ed25519 signature over `msg` made by the base64-encoded identity key.
+fn verify(identity_b64: &str, msg: &[u8], signature_b64: &str) -> Result<(), CryptoError> {
+    let key_bytes = general_purpose::STANDARD.decode(identity_b64).map_err(|_| CryptoError::BadKeyEncoding("identity key"))?;
+    let key_array: [u8; 32] = key_bytes.try_into().map_err(|_| CryptoError::BadKeyEncoding("identity key"))?;
+    let verifying = VerifyingKey::from_bytes(&key_array).map_err(|_| CryptoError::BadKeyEncoding("identity key"))?;
+
+    let sig_bytes = general_purpose::STANDARD.decode(signature_b64).map_err(|_| CryptoError::BadKeyEncoding("signature"))?;
+    let sig_array: [u8; 64] = sig_bytes.try_into().map_err(|_| CryptoError::BadKeyEncoding("signature"))?;
+    let signature = Signature::from_bytes(&sig_array);
+
+    verifying
+        .verify(msg, &signature)
+        .map_err(|_| CryptoError::BadSignature)
+}
+
+/// Builds the exact byte string that is signed during the handshake.
+fn handshake_message(ephemeral_pub: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(HANDSHAKE_CONTEXT.len() + ephemeral_pub.len());
+    msg.extend_from_slice(HANDSHAKE_CONTEXT);
+    msg.extend_from_slice(ephemeral_pub);
+    msg
+}
+
+/// Builds the exact byte string that is signed when rekeying a session.
+fn rekey_message(session_id: &[u8], epoch: u32, ephemeral_pub: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(REKEY_CONTEXT.len() + session_id.len() + 4 + ephemeral_pub.len());
+    msg.extend_from_slice(REKEY_CONTEXT);
+    msg.extend_from_slice(session_id);
+    msg.extend_from_slice(&epoch.to_be_bytes());
+    msg.extend_from_slice(ephemeral_pub);
+    msg
+}
+
+/// Builds the exact byte string that is signed for a discovery announcement.
+fn announcement_message(announcement: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(ANNOUNCE_CONTEXT.len() + announcement.len());
+    msg.extend_from_slice(ANNOUNCE_CONTEXT);
+    msg.extend_from_slice(announcement);
+    msg
+}
@@ -1,60 +1,168 @@
 pub mod crypto;
+pub mod error;
+pub mod fingerprint;
+pub mod identity;
+pub mod ratchet;
+pub mod rekey;
+pub mod replay;
 pub mod types;
+pub mod wire;
+pub use error::{CryptoError, WireError};
+pub use fingerprint::{key_fingerprint, SafetyNumber};
+pub use identity::Identity;
 pub use types::WireMessage;
-pub use x25519_dalek::{EphemeralSecret, PublicKey};
+pub use wire::Codec;
+pub use x25519_dalek::{PublicKey, StaticSecret};
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use crypto::SessionKeys;
+use ratchet::{Ratchet, RatchetHeader};
+use replay::ReplayWindow;
+
+/// The highest version of the wire protocol this build speaks. Version 8 sends every
+/// encrypted frame other than chat as a sealed frame with a payload in the link's
+/// codec; the versions before it used other frame formats and are no longer spoken.
+/// Peers agree on the lower of their two versions during the handshake.
+/// Both handshake messages, with the versions, ciphers and codecs each side offered,
+/// are bound into the handshake transcript along with the agreed version, so a
+/// handshake altered to force a lower version leaves the peers with different keys.
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// The lowest protocol version this build still talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 8;
 
 /// Represents the different types of symmetric encryption algorithms that can be used
 /// in a session. This allows for flexibility in the choice of encryption algorithm.
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CipherType {
     AES256GCM,
     XChaCha20Poly1305,
 }
 
-/// Represents a secure chat session between two peers. It holds the 32-byte session
-/// key and the selected cipher for encrypting and decrypting messages.
+impl CipherType {
+    /// All ciphers supported by this build, strongest first. XChaCha20-Poly1305 is
+    /// ranked above AES-256-GCM because its 192-bit nonces make random nonce
+    /// collisions negligible.
+    pub const SUPPORTED: [CipherType; 2] = [CipherType::XChaCha20Poly1305, CipherType::AES256GCM];
+
+    /// Picks the strongest cipher that appears in both our supported list and the
+    /// list advertised by the peer. Both sides compute the same answer, so no extra
+    /// round trip is needed.
+    pub fn negotiate(offered: &[CipherType]) -> Result<CipherType, CryptoError> {
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .find(|c| offered.contains(c))
+            .ok_or_else(|| CryptoError::UnsupportedCipher(offered.to_vec()))
+    }
+}
+
+impl fmt::Display for CipherType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherType::AES256GCM => write!(f, "AES-256-GCM"),
+            CipherType::XChaCha20Poly1305 => write!(f, "XChaCha20-Poly1305"),
+        }
+    }
+}
+
+/// Represents a secure chat session between two peers. It holds the Double Ratchet
+/// that derives a fresh key for every message in each direction, the session ID, and
+/// the selected cipher for encrypting and decrypting messages. Each direction also
+/// tracks its message counter: the send side numbers outgoing frames and the receive
+/// side rejects replayed ones.
 #[derive(Clone)]
 pub struct Session {
-    pub key: [u8; 32],
+    pub session_id: [u8; 32],
     pub cipher: CipherType,
+    pub version: u32,
+    ratchet: Ratchet,
+    send_counter: u64,
+    replay: ReplayWindow,
 }
 
 impl Session {
-    /// Creates a new `Session` from a 32-byte shared key. The default cipher used is
-    /// XChaCha20Poly1305.
-    pub fn new(key: [u8; 32]) -> Self {
+    /// Creates a new `Session` from the keys produced by the handshake key schedule,
+    /// our handshake secret, the peer's handshake public key and the cipher agreed
+    /// during the handshake, along with the agreed protocol version. The handshake keys
+    /// become the first ratchet keys.
+    pub fn new(keys: SessionKeys, handshake_secret: StaticSecret, peer_pub: PublicKey, cipher: CipherType, version: u32, is_client: bool) -> Self {
+        let ratchet = Ratchet::new(keys.root_key, keys.send_key, keys.recv_key, handshake_secret, peer_pub, is_client);
         Session {
-            key,
-            cipher: CipherType::XChaCha20Poly1305,
+            session_id: keys.session_id,
+            cipher,
+            version,
+            ratchet,
+            send_counter: 0,
+            replay: ReplayWindow::default(),
         }
     }
 
-    /// Encrypts a plaintext message using the selected cipher for the session. This
-    /// method returns the ciphertext and the nonce used for encryption.
-    pub fn encrypt(&self, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>) {
-        match self.cipher {
+    /// Returns a short hex rendering of the session ID, suitable for display.
+    pub fn short_id(&self) -> String {
+        self.session_id[..4].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Encrypts a plaintext message with the next message key of the ratchet using the
+    /// selected cipher for the session. The frame's counter, ratchet header and
+    /// `associated_data` are authenticated along with the ciphertext. This method
+    /// returns the ciphertext, the nonce used for encryption, the counter assigned to
+    /// the frame and the ratchet header to send with it.
+    pub fn encrypt(&mut self, plaintext: &[u8], associated_data: &[u8]) -> Result<(Vec<u8>, Vec<u8>, u64, RatchetHeader), CryptoError> {
+        self.send_counter += 1;
+        let counter = self.send_counter;
+        let (header, message_key) = self.ratchet.next_send_key();
+        let aad = self.aad(counter, &header, associated_data);
+        let (ct, nonce) = match self.cipher {
             CipherType::AES256GCM => {
-                panic!("AES256GCM not yet implemented");
+                let (ct, nonce) = crypto::encrypt_message_aes(&message_key, plaintext, &aad)?;
+                (ct, nonce.to_vec())
             }
             CipherType::XChaCha20Poly1305 => {
-                let (ct, nonce) = crypto::encrypt_message(&self.key, plaintext);
+                let (ct, nonce) = crypto::encrypt_message(&message_key, plaintext, &aad)?;
                 (ct, nonce.to_vec())
             }
-        }
+        };
+        Ok((ct, nonce, counter, header))
     }
 
-    /// Decrypts a ciphertext message using the selected cipher for the session. This
-    /// method takes the ciphertext and the nonce, and returns the original plaintext.
-    pub fn decrypt(&self, ciphertext: &[u8], nonce: &[u8]) -> Vec<u8> {
-        match self.cipher {
+    /// Decrypts a ciphertext message with the message key the ratchet derives for the
+    /// given header, using the selected cipher for the session. This method takes the
+    /// ciphertext, the nonce, the frame's counter, the ratchet header and the
+    /// associated data, and returns the original plaintext. Frames whose counter was
+    /// already seen or has fallen out of the replay window are rejected. The ratchet
+    /// only advances once the frame has been authenticated, so a bad frame leaves the
+    /// session untouched.
+    pub fn decrypt(&mut self, ciphertext: &[u8], nonce: &[u8], counter: u64, header: &RatchetHeader, associated_data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.replay.check(counter)?;
+        let (step, message_key) = self.ratchet.recv_key(header)?;
+        let aad = self.aad(counter, header, associated_data);
+        let pt = match self.cipher {
             CipherType::AES256GCM => {
-                panic!("AES256GCM not yet implemented");
+                let nonce_array: [u8; 12] = nonce.try_into()
+                    .map_err(|_| CryptoError::BadNonceLength { expected: 12, actual: nonce.len() })?;
+                crypto::decrypt_message_aes(&message_key, ciphertext, &nonce_array, &aad)?
             }
             CipherType::XChaCha20Poly1305 => {
-                let mut nonce_array = [0u8; 24];
-                nonce_array.copy_from_slice(&nonce[..24]);
-                crypto::decrypt_message(&self.key, ciphertext, &nonce_array)
+                let nonce_array: [u8; 24] = nonce.try_into()
+                    .map_err(|_| CryptoError::BadNonceLength { expected: 24, actual: nonce.len() })?;
+                crypto::decrypt_message(&message_key, ciphertext, &nonce_array, &aad)?
             }
-        }
+        };
+        self.ratchet.commit(step);
+        self.replay.accept(counter);
+        Ok(pt)
+    }
+
+    /// Builds the associated data for a frame: the session ID, the frame's counter,
+    /// the ratchet header and the caller-supplied associated data.
+    fn aad(&self, counter: u64, header: &RatchetHeader, associated_data: &[u8]) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(32 + 8 + 40 + associated_data.len());
+        aad.extend_from_slice(&self.session_id);
+        aad.extend_from_slice(&counter.to_be_bytes());
+        aad.extend_from_slice(&header.to_bytes());
+        aad.extend_from_slice(associated_data);
+        aad
     }
 }
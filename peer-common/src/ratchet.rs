@@ -0,0 +1,411 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::error::CryptoError;
+
+/// The maximum number of message keys that may be skipped in a single receiving
+/// chain. This bounds the work (and memory) a malicious peer can force on us by
+/// sending a header with a huge message index.
+pub const MAX_SKIP: u32 = 1000;
+
+/// The maximum number of skipped message keys kept around for late messages. Once
+/// the limit is reached, the oldest keys are dropped.
+const MAX_STORED_SKIPPED: usize = 2000;
+
+/// Identifies a skipped message key: the sender's ratchet public key and the
+/// message's index in that sender's chain.
+type SkippedId = ([u8; 32], u32);
+
+/// The header sent in clear with every ratchet message. It carries the sender's
+/// current ratchet public key, the length of the sender's previous sending chain and
+/// the index of this message in the current sending chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RatchetHeader {
+    pub dh: PublicKey,
+    pub pn: u32,
+    pub n: u32,
+}
+
+impl RatchetHeader {
+    /// Encodes the header so it can be authenticated as associated data.
+    pub fn to_bytes(&self) -> [u8; 40] {
+        let mut out = [0u8; 40];
+        out[..32].copy_from_slice(self.dh.as_bytes());
+        out[32..36].copy_from_slice(&self.pn.to_be_bytes());
+        out[36..].copy_from_slice(&self.n.to_be_bytes());
+        out
+    }
+}
+
+/// A Signal-style Double Ratchet. A Diffie-Hellman ratchet over X25519 mixes fresh
+/// key agreement into the root key every time the conversation changes direction,
+/// and a symmetric ratchet derives a new key for every message from the current
+/// chain key. Old message keys are deleted as soon as they are used, so a leaked
+/// state exposes neither past messages (forward secrecy) nor messages sent after
+/// the next DH step (post-compromise security).
+///
+/// The ratchet starts from the handshake: each side's handshake key is its first
+/// ratchet key and the directional keys from the key schedule are the first
+/// chains, so either side can speak first. The client performs the first DH step
+/// on its first send after hearing from the listener; from then on each side steps
+/// whenever it receives a new ratchet key from the other.
+#[derive(Clone)]
+pub struct Ratchet {
+    dh_self: StaticSecret,
+    dh_remote: PublicKey,
+    root_key: [u8; 32],
+    send_chain: [u8; 32],
+    recv_chain: [u8; 32],
+    send_n: u32,
+    recv_n: u32,
+    prev_send_n: u32,
+    skipped: HashMap<SkippedId, [u8; 32]>,
+    skipped_order: VecDeque<SkippedId>,
+    initiator_pending: bool,
+    send_step_pending: bool,
+}
+
+impl Ratchet {
+    /// Creates a ratchet from the handshake. `dh_self` is our handshake secret and
+    /// `dh_remote` the peer's handshake public key; `send_chain` and `recv_chain` are
+    /// the directional keys from the key schedule.
+    pub fn new(
+        root_key: [u8; 32],
+        send_chain: [u8; 32],
+        recv_chain: [u8; 32],
+        dh_self: StaticSecret,
+        dh_remote: PublicKey,
+        is_client: bool,
+    ) -> Self {
+        Ratchet {
+            dh_self,
+            dh_remote,
+            root_key,
+            send_chain,
+            recv_chain,
+            send_n: 0,
+            recv_n: 0,
+            prev_send_n: 0,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+            initiator_pending: is_client,
+            send_step_pending: false,
+        }
+    }
+
+    /// Advances the sending chain and returns the header and message key for the
+    /// next outgoing message.
+    pub fn next_send_key(&mut self) -> (RatchetHeader, [u8; 32]) {
+        if self.send_step_pending {
+            self.dh_self = StaticSecret::random_from_rng(OsRng);
+            self.send_chain = self.dh_step();
+            self.prev_send_n = self.send_n;
+            self.send_n = 0;
+            self.send_step_pending = false;
+        }
+
+        let (next_chain, message_key) = kdf_chain(&self.send_chain);
+        self.send_chain = next_chain;
+        let header = RatchetHeader {
+            dh: PublicKey::from(&self.dh_self),
+            pn: self.prev_send_n,
+            n: self.send_n,
+        };
+        self.send_n += 1;
+        (header, message_key)
+    }
+
+    /// Returns the message key for an incoming message and the changes receiving it
+    /// makes to the ratchet, performing a DH ratchet step if the header carries a new
+    /// ratchet key. Nothing changes until the step is passed to `commit`, which
+    /// callers only do once the message authenticates, so a forged header cannot
+    /// corrupt the ratchet.
+    pub fn recv_key(&self, header: &RatchetHeader) -> Result<(RecvStep, [u8; 32]), CryptoError> {
+        let id = (*header.dh.as_bytes(), header.n);
+        if let Some(key) = self.skipped.get(&id) {
+            return Ok((RecvStep { used_skipped: Some(id), chain: None }, *key));
+        }
+
+        let mut chain = ChainUpdate {
+            dh_remote: self.dh_remote,
+            root_key: self.root_key,
+            recv_chain: self.recv_chain,
+            recv_n: self.recv_n,
+            skipped: Vec::new(),
+            step_pending: self.initiator_pending,
+        };
+        if header.dh != self.dh_remote {
+            chain.skip_until(header.pn)?;
+            let shared = self.dh_self.diffie_hellman(&header.dh);
+            let (root_key, recv_chain) = kdf_root(&self.root_key, shared.as_bytes());
+            chain.dh_remote = header.dh;
+            chain.root_key = root_key;
+            chain.recv_chain = recv_chain;
+            chain.recv_n = 0;
+            chain.step_pending = true;
+        } else if header.n < self.recv_n {
+            return Err(CryptoError::MessageKeyUsed(header.n));
+        }
+
+        chain.skip_until(header.n)?;
+        let (next_chain, message_key) = kdf_chain(&chain.recv_chain);
+        chain.recv_chain = next_chain;
+        chain.recv_n += 1;
+        Ok((RecvStep { used_skipped: None, chain: Some(chain) }, message_key))
+    }
+
+    /// Applies the changes from `recv_key` once the message they belong to has been
+    /// authenticated.
+    pub fn commit(&mut self, step: RecvStep) {
+        if let Some(id) = step.used_skipped {
+            self.skipped.remove(&id);
+            self.skipped_order.retain(|k| *k != id);
+        }
+        let Some(chain) = step.chain else { return };
+        self.dh_remote = chain.dh_remote;
+        self.root_key = chain.root_key;
+        self.recv_chain = chain.recv_chain;
+        self.recv_n = chain.recv_n;
+        if chain.step_pending {
+            self.initiator_pending = false;
+            self.send_step_pending = true;
+        }
+        for (id, key) in chain.skipped {
+            self.skipped.insert(id, key);
+            self.skipped_order.push_back(id);
+        }
+        while self.skipped_order.len() > MAX_STORED_SKIPPED {
+            if let Some(oldest) = self.skipped_order.pop_front() {
+                self.skipped.remove(&oldest);
+            }
+        }
+    }
+
+    /// Mixes the DH output of our current ratchet key and the peer's into the root
+    /// key, returning the new chain key.
+    fn dh_step(&mut self) -> [u8; 32] {
+        let shared = self.dh_self.diffie_hellman(&self.dh_remote);
+        let (root_key, chain_key) = kdf_root(&self.root_key, shared.as_bytes());
+        self.root_key = root_key;
+        chain_key
+    }
+}
+
+/// The changes receiving one message makes to a ratchet, returned by
+/// `Ratchet::recv_key` and applied by `Ratchet::commit`.
+pub struct RecvStep {
+    /// The skipped message key that was used, to be deleted.
+    used_skipped: Option<SkippedId>,
+    /// The receiving chain after the message, unless a skipped key was used.
+    chain: Option<ChainUpdate>,
+}
+
+/// The receiving side of a ratchet after a message: only the values a message can
+/// change, so receiving does not copy the stored skipped keys.
+struct ChainUpdate {
+    dh_remote: PublicKey,
+    root_key: [u8; 32],
+    recv_chain: [u8; 32],
+    recv_n: u32,
+    /// The message keys skipped on the way, to be stored for late messages.
+    skipped: Vec<(SkippedId, [u8; 32])>,
+    /// Whether our next send must perform a DH step.
+    step_pending: bool,
+}
+
+impl ChainUpdate {
+    /// Collects the message keys of the receiving chain up to (but excluding) index
+    /// `until`, so messages that arrive late can still be decrypted.
+    fn skip_until(&mut self, until: u32) -> Result<(), CryptoError> {
+        if until <= self.recv_n {
+            return Ok(());
+        }
+        if until - self.recv_n > MAX_SKIP {
+            return Err(CryptoError::TooManySkipped(until - self.recv_n));
+        }
+        while self.recv_n < until {
+            let (next_chain, message_key) = kdf_chain(&self.recv_chain);
+            self.recv_chain = next_chain;
+            self.skipped.push(((*self.dh_remote.as_bytes(), self.recv_n), message_key));
+            self.recv_n += 1;
+        }
+        Ok(())
+    }
+}
+
+/// The root KDF: HKDF-SHA256 salted with the current root key over the DH output,
+/// producing the next root key and a new chain key.
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(Some(root_key), dh_out);
+    let mut okm = [0u8; 64];
+    hk.expand(b"p2p-chat ratchet", &mut okm)
+        .expect("64 bytes is a valid HKDF output length");
+    let mut next_root = [0u8; 32];
+    let mut chain_key = [0u8; 32];
+    next_root.copy_from_slice(&okm[..32]);
+    chain_key.copy_from_slice(&okm[32..]);
+    (next_root, chain_key)
+}
+
+/// The chain KDF: HMAC-SHA256 keyed with the chain key, producing the next chain key
+/// and a message key.
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hmac = |input: u8| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&[input]);
+        mac.finalize().into_bytes().into()
+    };
+    (hmac(0x02), hmac(0x01))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the two ends of a conversation as the handshake leaves them: the
+    /// client first, then the listener.
+    fn pair() -> (Ratchet, Ratchet) {
+        let root_key = [1u8; 32];
+        let client_to_listener = [2u8; 32];
+        let listener_to_client = [3u8; 32];
+        let client_secret = StaticSecret::random_from_rng(OsRng);
+        let listener_secret = StaticSecret::random_from_rng(OsRng);
+        let client_pub = PublicKey::from(&client_secret);
+        let listener_pub = PublicKey::from(&listener_secret);
+        let client = Ratchet::new(root_key, client_to_listener, listener_to_client, client_secret, listener_pub, true);
+        let listener = Ratchet::new(root_key, listener_to_client, client_to_listener, listener_secret, client_pub, false);
+        (client, listener)
+    }
+
+    /// Receives a message and commits the step, as a session does once the message
+    /// authenticates.
+    fn receive(ratchet: &mut Ratchet, header: &RatchetHeader) -> Result<[u8; 32], CryptoError> {
+        let (step, key) = ratchet.recv_key(header)?;
+        ratchet.commit(step);
+        Ok(key)
+    }
+
+    #[test]
+    fn in_order_delivery_in_both_directions() {
+        let (mut client, mut listener) = pair();
+        for _ in 0..3 {
+            for _ in 0..3 {
+                let (header, key) = client.next_send_key();
+                assert_eq!(receive(&mut listener, &header), Ok(key));
+            }
+            for _ in 0..3 {
+                let (header, key) = listener.next_send_key();
+                assert_eq!(receive(&mut client, &header), Ok(key));
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_order_delivery_uses_skipped_keys() {
+        let (mut client, mut listener) = pair();
+        let sent: Vec<_> = (0..4).map(|_| client.next_send_key()).collect();
+        for i in [2, 0, 3, 1] {
+            let (header, key) = sent[i];
+            assert_eq!(receive(&mut listener, &header), Ok(key));
+        }
+        assert!(listener.skipped.is_empty());
+
+        // A message that is overtaken by a DH step is still readable after it.
+        let (header, key) = listener.next_send_key();
+        assert_eq!(receive(&mut client, &header), Ok(key));
+        let early = client.next_send_key();
+        let overtaken = client.next_send_key();
+        assert_eq!(receive(&mut listener, &early.0), Ok(early.1));
+        let (header, key) = listener.next_send_key();
+        assert_eq!(receive(&mut client, &header), Ok(key));
+        let late = client.next_send_key();
+        assert_ne!(overtaken.0.dh, late.0.dh);
+        assert_eq!(receive(&mut listener, &late.0), Ok(late.1));
+        assert_eq!(receive(&mut listener, &overtaken.0), Ok(overtaken.1));
+        assert!(listener.skipped.is_empty());
+    }
+
+    #[test]
+    fn skipping_is_bounded_by_max_skip() {
+        let (mut client, mut listener) = pair();
+        let sent: Vec<_> = (0..=MAX_SKIP + 1).map(|_| client.next_send_key()).collect();
+
+        let too_far = sent[MAX_SKIP as usize + 1];
+        assert_eq!(listener.recv_key(&too_far.0).err(), Some(CryptoError::TooManySkipped(MAX_SKIP + 1)));
+        assert!(listener.skipped.is_empty());
+
+        let (header, key) = sent[MAX_SKIP as usize];
+        assert_eq!(receive(&mut listener, &header), Ok(key));
+        assert_eq!(listener.skipped.len(), MAX_SKIP as usize);
+        assert_eq!(receive(&mut listener, &too_far.0), Ok(too_far.1));
+    }
+
+    #[test]
+    fn stored_skipped_keys_are_bounded() {
+        let (mut client, mut listener) = pair();
+        for _ in 0..3 {
+            for _ in 0..MAX_SKIP {
+                client.next_send_key();
+            }
+            let (header, key) = client.next_send_key();
+            assert_eq!(receive(&mut listener, &header), Ok(key));
+        }
+        assert_eq!(listener.skipped.len(), MAX_STORED_SKIPPED);
+        assert_eq!(listener.skipped_order.len(), MAX_STORED_SKIPPED);
+    }
+
+    #[test]
+    fn used_message_keys_cannot_be_replayed() {
+        let (mut client, mut listener) = pair();
+        let first = client.next_send_key();
+        let second = client.next_send_key();
+        let third = client.next_send_key();
+        assert_eq!(receive(&mut listener, &first.0), Ok(first.1));
+        assert_eq!(receive(&mut listener, &third.0), Ok(third.1));
+        assert_eq!(receive(&mut listener, &second.0), Ok(second.1));
+
+        for (header, _) in [first, second, third] {
+            assert_eq!(listener.recv_key(&header).err(), Some(CryptoError::MessageKeyUsed(header.n)));
+        }
+    }
+
+    #[test]
+    fn uncommitted_steps_leave_the_ratchet_unchanged() {
+        let (mut client, mut listener) = pair();
+        let (header, key) = client.next_send_key();
+        let forged = RatchetHeader { n: 5, ..header };
+        assert!(listener.recv_key(&forged).is_ok());
+        assert!(listener.skipped.is_empty());
+        assert_eq!(receive(&mut listener, &header), Ok(key));
+    }
+
+    #[test]
+    fn compromised_state_is_locked_out_after_the_next_dh_step() {
+        let (mut client, mut listener) = pair();
+        let (header, key) = client.next_send_key();
+        assert_eq!(receive(&mut listener, &header), Ok(key));
+
+        // A copy of the listener's state keeps reading while the listener's own
+        // ratchet key stays the same...
+        let mut stolen = listener.clone();
+        let (header, key) = client.next_send_key();
+        assert_eq!(receive(&mut stolen, &header), Ok(key));
+        assert_eq!(receive(&mut listener, &header), Ok(key));
+        let (header, key) = listener.next_send_key();
+        assert_eq!(receive(&mut client, &header), Ok(key));
+        let (header, key) = client.next_send_key();
+        assert_eq!(receive(&mut stolen, &header), Ok(key));
+        assert_eq!(receive(&mut listener, &header), Ok(key));
+
+        // ...but not once the listener's next DH step mixes in a fresh key.
+        let (header, key) = listener.next_send_key();
+        assert_eq!(receive(&mut client, &header), Ok(key));
+        let (header, key) = client.next_send_key();
+        assert_eq!(receive(&mut listener, &header), Ok(key));
+        assert_ne!(stolen.recv_key(&header).map(|(_, k)| k), Ok(key));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::crypto::{derive_session_keys, generate_keypair, pubkey_from_b64, pubkey_to_b64, transcript_hash};
+use crate::error::CryptoError;
+use crate::identity::verify_rekey;
+use crate::ratchet::RatchetHeader;
+use crate::types::WireMessage;
+use crate::{Identity, Session};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use x25519_dalek::StaticSecret;
+
+/// An encrypted frame as returned by `Keyring::encrypt`: the key epoch, ciphertext,
+/// nonce, counter and ratchet header.
+pub type EpochFrame = (u32, Vec<u8>, Vec<u8>, u64, RatchetHeader);
+
+/// Controls when a session is rekeyed automatically and how long frames encrypted
+/// under the previous keys are still accepted after a rekey.
+#[derive(Clone, Debug)]
+pub struct RekeyPolicy {
+    /// Rekey after this many messages have been sent under the current keys.
+    pub max_messages: u64,
+    /// Rekey once the current keys are this old.
+    pub max_age: Duration,
+    /// How long the previous keys are kept for frames that were in flight.
+    pub grace: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_messages: 1000,
+            max_age: Duration::from_secs(60 * 60),
+            grace: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Holds the keys of a session across rekeys. Every rekey replaces the `Session`
+/// with a new one derived from a fresh ephemeral key exchange and bumps the key
+/// epoch. Frames carry the epoch they were encrypted under, so frames that were in
+/// flight during the switch can still be decrypted with the previous keys until the
+/// grace period runs out.
+///
+/// A rekey is a symmetric exchange of signed `Rekey` frames: the initiator sends its
+/// ephemeral key for the next epoch, and the responder answers with its own and
+/// switches immediately. The initiator switches when the answer arrives. If both
+/// sides initiate at once, each simply switches on receiving the other's frame.
+pub struct Keyring {
+    identity: Arc<Identity>,
+    peer_identity: String,
+    is_client: bool,
+    policy: RekeyPolicy,
+    current: Session,
+    epoch: u32,
+    previous: Option<(u32, Session, Instant)>,
+    pending: Option<(u32, StaticSecret)>,
+    sent: u64,
+    since: Instant,
+}
+
+impl Keyring {
+    /// Creates a keyring around the session established by the handshake.
+    pub fn new(session: Session, is_client: bool, identity: Arc<Identity>, peer_identity: String, policy: RekeyPolicy) -> Self {
+        Keyring {
+            identity,
+            peer_identity,
+            is_client,
+            policy,
+            current: session,
+            epoch: 0,
+            previous: None,
+            pending: None,
+            sent: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Returns the current session.
+    pub fn session(&self) -> &Session {
+        &self.current
+    }
+
+    /// Returns the current key epoch.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Encrypts a message with the current session. Returns the key epoch along with
+    /// the output of `Session::encrypt`.
+    pub fn encrypt(&mut self, plaintext: &[u8], associated_data: &[u8]) -> Result<EpochFrame, CryptoError> {
+        self.sent += 1;
+        let (ct, nonce, counter, header) = self.current.encrypt(plaintext, associated_data)?;
+        Ok((self.epoch, ct, nonce, counter, header))
+    }
+
+    /// Decrypts a frame with the session of the given key epoch. Frames from the
+    /// previous epoch are accepted until its grace period runs out.
+    pub fn decrypt(&mut self, epoch: u32, ciphertext: &[u8], nonce: &[u8], counter: u64, header: &RatchetHeader, associated_data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if epoch == self.epoch {
+            return self.current.decrypt(ciphertext, nonce, counter, header, associated_data);
+        }
+        if let Some((prev_epoch, prev, expires)) = &mut self.previous {
+            if *prev_epoch == epoch && Instant::now() < *expires {
+                return prev.decrypt(ciphertext, nonce, counter, header, associated_data);
+            }
+        }
+        Err(CryptoError::UnknownEpoch(epoch))
+    }
+
+    /// Returns true if the rekey policy calls for a rekey and none is in progress.
+    pub fn should_rekey(&self) -> bool {
+        self.pending.is_none()
+            && (self.sent >= self.policy.max_messages || self.since.elapsed() >= self.policy.max_age)
+    }
+
+    /// Starts a rekey, returning the `Rekey` frame to send. Returns `None` if a rekey
+    /// is already in progress.
+    pub fn start_rekey(&mut self) -> Option<WireMessage> {
+        if self.pending.is_some() {
+            return None;
+        }
+        let epoch = self.epoch + 1;
+        let (secret, frame) = self.rekey_frame(epoch);
+        self.pending = Some((epoch, secret));
+        Some(frame)
+    }
+
+    /// Handles a `Rekey` frame from the peer. The peer's key is verified against its
+    /// identity, then the keyring switches to the new epoch. If we did not initiate
+    /// this rekey, the returned frame must be sent back to the peer. The new epoch is
+    /// derived before the answer is sent, so frames sent after it use the new keys.
+    pub fn handle_rekey(&mut self, epoch: u32, pubkey: &str, signature: &str) -> Result<Option<WireMessage>, CryptoError> {
+        if epoch != self.epoch + 1 {
+            return Err(CryptoError::UnexpectedRekey { epoch, current: self.epoch });
+        }
+        let peer_pub = pubkey_from_b64(pubkey)?;
+        verify_rekey(&self.peer_identity, &self.current.session_id, epoch, peer_pub.as_bytes(), signature)?;
+
+        let (secret, reply) = match self.pending.take() {
+            Some((pending_epoch, secret)) if pending_epoch == epoch => (secret, None),
+            _ => {
+                let (secret, frame) = self.rekey_frame(epoch);
+                (secret, Some(frame))
+            }
+        };
+
+        let my_pub = pubkey_to_b64(&x25519_dalek::PublicKey::from(&secret));
+        let (client_pub, listener_pub) = if self.is_client {
+            (my_pub.as_str(), pubkey)
+        } else {
+            (pubkey, my_pub.as_str())
+        };
+        let transcript = transcript_hash(&[
+            &self.current.version.to_be_bytes(),
+            &self.current.session_id,
+            &epoch.to_be_bytes(),
+            client_pub.as_bytes(),
+            listener_pub.as_bytes(),
+            self.current.cipher.to_string().as_bytes(),
+        ]);
+        let keys = derive_session_keys(&secret, &peer_pub, &transcript, self.is_client);
+        let next = Session::new(keys, secret, peer_pub, self.current.cipher, self.current.version, self.is_client);
+
+        let old = std::mem::replace(&mut self.current, next);
+        self.previous = Some((self.epoch, old, Instant::now() + self.policy.grace));
+        self.epoch = epoch;
+        self.sent = 0;
+        self.since = Instant::now();
+        Ok(reply)
+    }
+
+    /// Generates a fresh ephemeral key for `epoch` and the signed `Rekey` frame
+    /// announcing it.
+    fn rekey_frame(&self, epoch: u32) -> (StaticSecret, WireMessage) {
+        let (secret, public) = generate_keypair();
+        let signature = self.identity.sign_rekey(&self.current.session_id, epoch, public.as_bytes());
+        let frame = WireMessage::Rekey {
+            epoch,
+            pubkey: pubkey_to_b64(&public),
+            signature,
+        };
+        (secret, frame)
+    }
+}
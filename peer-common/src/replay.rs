@@ -0,0 +1,126 @@
+use crate::error::CryptoError;
+
+/// The number of counters behind the highest one seen that are still accepted. Frames
+/// older than this are rejected even if they were never seen.
+pub const WINDOW_SIZE: u64 = 64;
+
+/// A sliding-window replay filter for the message counters of one direction of a
+/// session. It remembers the highest counter accepted so far and, in a bitmap, which
+/// of the `WINDOW_SIZE` counters below it have been seen.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayWindow {
+    highest: u64,
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    /// Returns an error if `counter` was already accepted or has fallen out of the
+    /// window. Does not record the counter; call `accept` once the frame has been
+    /// authenticated, so forged frames cannot move the window.
+    pub fn check(&self, counter: u64) -> Result<(), CryptoError> {
+        if counter == 0 {
+            return Err(CryptoError::StaleCounter(counter));
+        }
+        if counter > self.highest {
+            return Ok(());
+        }
+        let offset = self.highest - counter;
+        if offset >= WINDOW_SIZE {
+            return Err(CryptoError::StaleCounter(counter));
+        }
+        if self.bitmap & (1 << offset) != 0 {
+            return Err(CryptoError::Replay(counter));
+        }
+        Ok(())
+    }
+
+    /// Records `counter` as accepted, sliding the window forward if needed.
+    pub fn accept(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.bitmap = if shift >= WINDOW_SIZE { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.highest = counter;
+        } else {
+            self.bitmap |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks a counter and accepts it if it passes, as a session does once the frame
+    /// authenticates.
+    fn receive(window: &mut ReplayWindow, counter: u64) -> Result<(), CryptoError> {
+        window.check(counter)?;
+        window.accept(counter);
+        Ok(())
+    }
+
+    #[test]
+    fn in_order_counters_are_accepted() {
+        let mut window = ReplayWindow::default();
+        for counter in 1..=200 {
+            assert_eq!(receive(&mut window, counter), Ok(()));
+        }
+    }
+
+    #[test]
+    fn zero_is_never_accepted() {
+        let mut window = ReplayWindow::default();
+        assert_eq!(receive(&mut window, 0), Err(CryptoError::StaleCounter(0)));
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let mut window = ReplayWindow::default();
+        for counter in 1..=10 {
+            receive(&mut window, counter).unwrap();
+        }
+        assert_eq!(receive(&mut window, 10), Err(CryptoError::Replay(10)));
+        assert_eq!(receive(&mut window, 3), Err(CryptoError::Replay(3)));
+    }
+
+    #[test]
+    fn out_of_order_counters_inside_the_window_are_accepted_once() {
+        let mut window = ReplayWindow::default();
+        receive(&mut window, 100).unwrap();
+        for counter in [99, 37, 60, 98] {
+            assert_eq!(receive(&mut window, counter), Ok(()));
+        }
+        for counter in [99, 37, 60, 98, 100] {
+            assert_eq!(receive(&mut window, counter), Err(CryptoError::Replay(counter)));
+        }
+    }
+
+    #[test]
+    fn counters_older_than_the_window_are_rejected() {
+        let mut window = ReplayWindow::default();
+        receive(&mut window, 100).unwrap();
+        assert_eq!(receive(&mut window, 100 - WINDOW_SIZE + 1), Ok(()));
+        assert_eq!(receive(&mut window, 100 - WINDOW_SIZE), Err(CryptoError::StaleCounter(100 - WINDOW_SIZE)));
+        assert_eq!(receive(&mut window, 1), Err(CryptoError::StaleCounter(1)));
+    }
+
+    #[test]
+    fn a_large_jump_forward_clears_the_window() {
+        let mut window = ReplayWindow::default();
+        for counter in 1..=10 {
+            receive(&mut window, counter).unwrap();
+        }
+        assert_eq!(receive(&mut window, 1_000_000), Ok(()));
+        assert_eq!(receive(&mut window, 1_000_000), Err(CryptoError::Replay(1_000_000)));
+        assert_eq!(receive(&mut window, 999_999), Ok(()));
+        assert_eq!(receive(&mut window, 10), Err(CryptoError::StaleCounter(10)));
+    }
+
+    #[test]
+    fn checking_a_counter_does_not_move_the_window() {
+        let mut window = ReplayWindow::default();
+        receive(&mut window, 5).unwrap();
+        assert_eq!(window.check(500), Ok(()));
+        assert_eq!(receive(&mut window, 4), Ok(()));
+    }
+}
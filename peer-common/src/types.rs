@@ -1,23 +1,343 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use crate::wire::Codec;
+use crate::CipherType;
 
 /// Represents all the possible messages that can be exchanged between peers. This enum
-/// is the core data structure for all communication. Messages are serialized to JSON
-/// and sent over the wire with a 4-byte big-endian length prefix.
+/// is the core data structure for all communication. Messages are encoded with one of
+/// the codecs in `wire` and sent over the wire with a 4-byte big-endian length prefix.
+/// Byte fields are raw bytes in the binary encoding and base64 strings in JSON.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum WireMessage {
     /// Used to exchange public keys and establish a secure session. The `pubkey` field
-    /// contains the base64-encoded public key of the sender.
-    Handshake { pubkey: String },
+    /// contains the base64-encoded ephemeral public key of the sender, `identity` its
+    /// long-term Ed25519 identity key, and `signature` the identity key's signature
+    /// over the ephemeral key. `ciphers` lists the ciphers the sender supports.
+    /// `version` is the highest protocol version the sender speaks and `codecs` the
+    /// wire encodings it accepts; a handshake without a version is taken as version 1,
+    /// which is refused.
+    Handshake {
+        pubkey: String,
+        identity: String,
+        signature: String,
+        ciphers: Vec<CipherType>,
+        #[serde(default = "legacy_version")]
+        version: u32,
+        #[serde(default)]
+        codecs: Vec<Codec>,
+    },
 
     /// Used to send encrypted chat messages. The `payload` field contains the
-    /// base64-encoded ciphertext of the message, and the `nonce` field contains the
-    /// base64-encoded 24-byte nonce that was used to encrypt the message.
-    Chat { sender_id: String, timestamp: u64, payload: String, nonce: String },
+    /// ciphertext of the message, and the `nonce` field contains the nonce that was
+    /// used to encrypt the message. `counter` numbers the frames of each direction; it
+    /// is authenticated together with `sender_id` and `timestamp` (see
+    /// `chat_associated_data`). `ratchet_key`, `prev_chain_len` and `message_index`
+    /// form the Double Ratchet header: the sender's ratchet public key, the length of
+    /// its previous sending chain and the index of the message in the current chain.
+    /// `epoch` is the key epoch the frame was encrypted under (see `Rekey`). `id` is
+    /// the message's unique ID, acknowledged by the receiver with an `Ack`.
+    /// `sender_id` is the identity key of the message's author, which is not the
+    /// sending peer's when a room host relays a message.
+    Chat {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        id: String,
+        epoch: u32,
+        sender_id: String,
+        timestamp: u64,
+        counter: u64,
+        #[serde(with = "bytes")]
+        ratchet_key: Vec<u8>,
+        prev_chain_len: u32,
+        message_index: u32,
+        #[serde(with = "bytes")]
+        payload: Vec<u8>,
+        #[serde(with = "bytes")]
+        nonce: Vec<u8>,
+    },
 
-    /// Used to acknowledge the receipt of a message. The `id` field contains the ID
-    /// of the message being acknowledged.
-    Ack { id: String },
+    /// Used to rekey an established session. The `pubkey` field contains the sender's
+    /// base64-encoded ephemeral public key for the new key `epoch`, and `signature` its
+    /// identity key's signature over that key, the session ID and the epoch.
+    Rekey { epoch: u32, pubkey: String, signature: String },
+
+    /// Used for every encrypted frame other than `Chat`: delivery receipts, room
+    /// events, statuses and file transfers. `kind` says what the decrypted payload
+    /// is, and is bound to it through the associated data (see
+    /// `SealedKind::associated_data`).
+    Sealed { kind: SealedKind, envelope: SealedEnvelope },
 
     /// Used to keep the connection alive and check if the peer is still responsive.
-    Ping,
+    /// The peer answers with a `Pong` carrying the same `seq`.
+    Ping { seq: u64 },
+
+    /// The answer to a `Ping`.
+    Pong { seq: u64 },
+}
+
+/// The protocol version assumed for a handshake that carries no version, so that it
+/// is refused as too old rather than as malformed.
+fn legacy_version() -> u32 {
+    1
+}
+
+/// Builds the associated data that binds the plaintext header fields of a `Chat`
+/// frame to its ciphertext, so they cannot be altered in transit. The message ID is
+/// only included when there is one.
+pub fn chat_associated_data(id: &str, sender_id: &str, timestamp: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(4 + sender_id.len() + 8 + 4 + id.len());
+    aad.extend_from_slice(&(sender_id.len() as u32).to_be_bytes());
+    aad.extend_from_slice(sender_id.as_bytes());
+    aad.extend_from_slice(&timestamp.to_be_bytes());
+    if !id.is_empty() {
+        aad.extend_from_slice(&(id.len() as u32).to_be_bytes());
+        aad.extend_from_slice(id.as_bytes());
+    }
+    aad
+}
+
+/// What a `Sealed` frame carries. Apart from an `Ack`, the payload is encoded with
+/// the link's codec (see `wire::encode`).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SealedKind {
+    /// The ID of a chat message being acknowledged.
+    Ack,
+    /// An encoded `RoomEvent`, sent by a room host.
+    Room,
+    /// An encoded `StatusUpdate`.
+    Status,
+    /// An encoded `FileOffer`. The peer answers with a `FileAccept`, or declines
+    /// with a `FileComplete`.
+    FileOffer,
+    /// An encoded `FileAccept`.
+    FileAccept,
+    /// An encoded `FileChunk`.
+    FileChunk,
+    /// An encoded `FileComplete`.
+    FileComplete,
+}
+
+impl SealedKind {
+    /// The associated data a frame of this kind is encrypted with. Each kind has its
+    /// own, so no frame can be accepted as one of another kind, or as a `Chat` frame.
+    pub fn associated_data(self) -> &'static [u8] {
+        match self {
+            SealedKind::Ack => b"p2p-chat-ack",
+            SealedKind::Room => b"p2p-chat-room",
+            SealedKind::Status => b"p2p-chat-status",
+            SealedKind::FileOffer => b"p2p-chat-file-offer",
+            SealedKind::FileAccept => b"p2p-chat-file-accept",
+            SealedKind::FileChunk => b"p2p-chat-file-chunk",
+            SealedKind::FileComplete => b"p2p-chat-file-complete",
+        }
+    }
+}
+
+/// The encrypted payload of a `Sealed` frame and the fields needed to decrypt it,
+/// which mean the same as in `Chat`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SealedEnvelope {
+    pub epoch: u32,
+    pub counter: u64,
+    #[serde(with = "bytes")]
+    pub ratchet_key: Vec<u8>,
+    pub prev_chain_len: u32,
+    pub message_index: u32,
+    #[serde(with = "bytes")]
+    pub payload: Vec<u8>,
+    #[serde(with = "bytes")]
+    pub nonce: Vec<u8>,
+}
+
+/// A change in the membership of a room, sent by the host in a `Room` frame. Members
+/// are named by their identity keys.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RoomEvent {
+    /// Sent to a member once it has joined: the room's name and everyone in it,
+    /// including the host and the new member.
+    Welcome { room: String, members: Vec<String> },
+    /// Another member joined the room.
+    Joined { identity: String },
+    /// A member left the room.
+    Left { identity: String },
+}
+
+/// A file offered in a `FileOffer` frame. `transfer_id` names the transfer in the
+/// frames that follow. `name` is the file's name on the sender's side, which the
+/// receiver must sanitise before saving under it, and `sha256` the hex-encoded
+/// SHA-256 hash of the whole file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileOffer {
+    pub transfer_id: String,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Accepts an offered file from byte `offset` on. The offset is not zero when the
+/// receiver already has the start of the file from an interrupted transfer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileAccept {
+    pub transfer_id: String,
+    pub offset: u64,
+}
+
+/// A piece of a file, starting at byte `offset`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileChunk {
+    pub transfer_id: String,
+    pub offset: u64,
+    #[serde(with = "bytes")]
+    pub data: Vec<u8>,
+}
+
+/// Ends a file transfer. The sender sends it after the last chunk, and the receiver
+/// answers with its own once the file has been checked and saved. `error` says why a
+/// transfer was given up instead, e.g. because the receiver declined the offer or the
+/// hash did not match.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileComplete {
+    pub transfer_id: String,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// The decrypted payload of a file frame.
+#[derive(Debug, Clone)]
+pub enum FileMessage {
+    Offer(FileOffer),
+    Accept(FileAccept),
+    Chunk(FileChunk),
+    Complete(FileComplete),
+}
+
+impl FileMessage {
+    /// The kind of `Sealed` frame the message is sent in.
+    pub fn kind(&self) -> SealedKind {
+        match self {
+            FileMessage::Offer(_) => SealedKind::FileOffer,
+            FileMessage::Accept(_) => SealedKind::FileAccept,
+            FileMessage::Chunk(_) => SealedKind::FileChunk,
+            FileMessage::Complete(_) => SealedKind::FileComplete,
+        }
+    }
+}
+
+/// Whether a peer is around to chat.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Availability {
+    #[default]
+    Available,
+    Away,
+    Busy,
+}
+
+impl fmt::Display for Availability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Availability::Available => write!(f, "available"),
+            Availability::Away => write!(f, "away"),
+            Availability::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+impl Availability {
+    /// Parses an availability as it is displayed, ignoring case.
+    pub fn parse(s: &str) -> Option<Availability> {
+        match s.to_ascii_lowercase().as_str() {
+            "available" => Some(Availability::Available),
+            "away" => Some(Availability::Away),
+            "busy" => Some(Availability::Busy),
+            _ => None,
+        }
+    }
+}
+
+/// The status a peer shows to others: its availability and, optionally, a text of
+/// its own, such as "at lunch".
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct PeerStatus {
+    pub availability: Availability,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl fmt::Display for PeerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.text {
+            Some(text) => write!(f, "{} ({})", self.availability, text),
+            None => write!(f, "{}", self.availability),
+        }
+    }
+}
+
+/// The status of the member with the identity key `identity`, sent in a `Status`
+/// frame. Peers send their own status; a room host also relays the statuses of the
+/// other members, under the identity keys of their authenticated sessions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusUpdate {
+    pub identity: String,
+    pub status: PeerStatus,
+}
+
+/// Generates a random 128-bit message ID, hex-encoded.
+pub fn new_message_id() -> String {
+    let bytes: [u8; 16] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Serializes byte fields as raw bytes in binary encodings and as base64 strings in
+/// human-readable ones, so JSON frames stay readable and compatible with version 1.
+mod bytes {
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&general_purpose::STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(BytesVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a byte string or a base64 string")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            general_purpose::STANDARD.decode(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut out = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(b) = seq.next_element()? {
+                out.push(b);
+            }
+            Ok(out)
+        }
+    }
 }
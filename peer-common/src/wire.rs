@@ -0,0 +1,164 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use crate::error::WireError;
+use crate::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// The version tag that starts every binary frame. JSON frames always start with
+/// `{`, so a receiver can tell the encodings apart without any extra negotiation.
+pub const BINARY_VERSION: u8 = 1;
+
+/// The encodings a `WireMessage` can be sent in. The binary encoding (a version tag
+/// followed by CBOR) carries byte fields as raw bytes and is the default. JSON is
+/// kept for debugging.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    Binary,
+    Json,
+}
+
+impl Codec {
+    /// All codecs supported by this build, preferred first.
+    pub const SUPPORTED: [Codec; 2] = [Codec::Binary, Codec::Json];
+
+    /// Picks the preferred codec that appears in both `ours` and the list advertised
+    /// by the peer. Every peer understands JSON, so it is the fallback.
+    pub fn negotiate(ours: &[Codec], offered: &[Codec]) -> Codec {
+        ours.iter()
+            .copied()
+            .find(|c| offered.contains(c))
+            .unwrap_or(Codec::Json)
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Binary => write!(f, "binary v{}", BINARY_VERSION),
+            Codec::Json => write!(f, "JSON"),
+        }
+    }
+}
+
+/// Agrees on the protocol version to use with a peer that speaks up to `offered`:
+/// the lower of the two versions, as long as we still support it.
+pub fn negotiate_version(offered: u32) -> Result<u32, WireError> {
+    let version = offered.min(PROTOCOL_VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(WireError::UnsupportedProtocol(offered));
+    }
+    Ok(version)
+}
+
+/// Encodes a value with the given codec: a `WireMessage` as a frame body, without the
+/// length prefix, or what an encrypted frame carries, such as a `RoomEvent`, so the
+/// payload of a binary frame holds raw bytes as well.
+pub fn encode<T: Serialize>(value: &T, codec: Codec) -> Result<Vec<u8>, WireError> {
+    match codec {
+        Codec::Binary => {
+            let mut out = vec![BINARY_VERSION];
+            ciborium::into_writer(value, &mut out).map_err(|e| WireError::Encode(e.to_string()))?;
+            Ok(out)
+        }
+        Codec::Json => serde_json::to_vec(value).map_err(|e| WireError::Encode(e.to_string())),
+    }
+}
+
+/// Decodes a value encoded by `encode`, such as a frame body, detecting its codec
+/// from the first byte.
+pub fn decode<T: DeserializeOwned>(body: &[u8]) -> Result<T, WireError> {
+    match body.first() {
+        Some(b'{') => serde_json::from_slice(body).map_err(|e| WireError::Decode(e.to_string())),
+        Some(&BINARY_VERSION) => ciborium::from_reader(&body[1..]).map_err(|e| WireError::Decode(e.to_string())),
+        Some(&tag) => Err(WireError::UnsupportedVersion(tag)),
+        None => Err(WireError::Decode("empty frame".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{FileChunk, PeerStatus, SealedEnvelope, SealedKind, WireMessage};
+
+    fn sealed() -> WireMessage {
+        WireMessage::Sealed {
+            kind: SealedKind::FileChunk,
+            envelope: SealedEnvelope {
+                epoch: 2,
+                counter: 41,
+                ratchet_key: vec![7; 32],
+                prev_chain_len: 3,
+                message_index: 9,
+                payload: (0..=255).collect(),
+                nonce: vec![1; 24],
+            },
+        }
+    }
+
+    fn assert_sealed(wm: WireMessage) {
+        let WireMessage::Sealed { kind, envelope } = wm else {
+            panic!("decoded {:?}", wm);
+        };
+        assert_eq!(kind, SealedKind::FileChunk);
+        assert_eq!((envelope.epoch, envelope.counter, envelope.prev_chain_len, envelope.message_index), (2, 41, 3, 9));
+        assert_eq!(envelope.ratchet_key, vec![7; 32]);
+        assert_eq!(envelope.payload, (0..=255).collect::<Vec<u8>>());
+        assert_eq!(envelope.nonce, vec![1; 24]);
+    }
+
+    #[test]
+    fn frames_round_trip_in_binary() {
+        let body = encode(&sealed(), Codec::Binary).unwrap();
+        assert_eq!(body[0], BINARY_VERSION);
+        assert_sealed(decode(&body).unwrap());
+    }
+
+    #[test]
+    fn frames_round_trip_in_json() {
+        let body = encode(&sealed(), Codec::Json).unwrap();
+        assert_eq!(body[0], b'{');
+        assert_sealed(decode(&body).unwrap());
+    }
+
+    #[test]
+    fn payloads_round_trip_in_both_codecs() {
+        let chunk = FileChunk { transfer_id: "ab".repeat(16), offset: 1 << 40, data: vec![0, 255, b'{', 1] };
+        let status = PeerStatus::default();
+        for codec in Codec::SUPPORTED {
+            let decoded: FileChunk = decode(&encode(&chunk, codec).unwrap()).unwrap();
+            assert_eq!((decoded.transfer_id, decoded.offset, decoded.data), (chunk.transfer_id.clone(), chunk.offset, chunk.data.clone()));
+            assert_eq!(decode::<PeerStatus>(&encode(&status, codec).unwrap()).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn binary_carries_bytes_unencoded() {
+        let binary = encode(&sealed(), Codec::Binary).unwrap();
+        let json = encode(&sealed(), Codec::Json).unwrap();
+        assert!(binary.len() < json.len());
+    }
+
+    #[test]
+    fn unknown_tags_and_empty_bodies_are_rejected() {
+        assert_eq!(decode::<WireMessage>(&[2, 0xa0]).unwrap_err(), WireError::UnsupportedVersion(2));
+        assert_eq!(decode::<WireMessage>(b"[]").unwrap_err(), WireError::UnsupportedVersion(b'['));
+        assert!(matches!(decode::<WireMessage>(&[]), Err(WireError::Decode(_))));
+        assert!(matches!(decode::<WireMessage>(&[BINARY_VERSION, 0xff]), Err(WireError::Decode(_))));
+        assert!(matches!(decode::<WireMessage>(b"{\"Ping\":"), Err(WireError::Decode(_))));
+    }
+
+    #[test]
+    fn codec_negotiation_prefers_ours_and_falls_back_to_json() {
+        assert_eq!(Codec::negotiate(&Codec::SUPPORTED, &[Codec::Json, Codec::Binary]), Codec::Binary);
+        assert_eq!(Codec::negotiate(&Codec::SUPPORTED, &[Codec::Json]), Codec::Json);
+        assert_eq!(Codec::negotiate(&[Codec::Json], &Codec::SUPPORTED), Codec::Json);
+        assert_eq!(Codec::negotiate(&Codec::SUPPORTED, &[]), Codec::Json);
+    }
+
+    #[test]
+    fn version_negotiation_picks_the_lower_supported_version() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 5), Ok(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), Ok(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), Err(WireError::UnsupportedProtocol(MIN_PROTOCOL_VERSION - 1)));
+    }
+}
@@ -0,0 +1,187 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use anyhow::{anyhow, bail};
+
+/// The port listened on and connected to when an address does not include one.
+pub const DEFAULT_PORT: u16 = 12345;
+
+/// Parses an address given on the command line or saved with a peer: an IP address
+/// or a host name, with or without a port. IPv6 addresses with a port are written in
+/// brackets, `[fe80::1%eth0]:12345`; link-local ones need the scope after a `%`, as
+/// an interface name or index. `default_port` is used when there is no port.
+/// Returns the address in a form `TcpStream::connect` accepts, with the scope as an
+/// interface index.
+pub fn parse_addr(addr: &str, default_port: u16) -> anyhow::Result<String> {
+    let addr = addr.trim();
+    let bracketed = addr.starts_with('[');
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(|| anyhow!("missing ']' in address '{}'", addr))?;
+        match rest.strip_prefix(':') {
+            Some(port) => (host, Some(port)),
+            None if rest.is_empty() => (host, None),
+            None => bail!("invalid address '{}'", addr),
+        }
+    } else if addr.matches(':').count() > 1 {
+        // An IPv6 address without brackets, which cannot have a port
+        (addr, None)
+    } else {
+        match addr.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (addr, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| anyhow!("invalid port '{}' in address '{}'", port, addr))?,
+        None => default_port,
+    };
+
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => {
+            let scope_id = match scope {
+                Some(scope) => interface_index(scope)?,
+                None if is_link_local(ip) => bail!("the link-local address '{}' needs a scope, e.g. {}%eth0", addr, ip),
+                None => 0,
+            };
+            Ok(SocketAddrV6::new(ip, port, 0, scope_id).to_string())
+        }
+        Ok(ip) if scope.is_none() => Ok(SocketAddr::new(ip, port).to_string()),
+        Ok(_) => bail!("only IPv6 addresses have a scope: '{}'", addr),
+        Err(_) if scope.is_none() && !bracketed && !host.is_empty() && !host.contains(':') => Ok(format!("{}:{}", host, port)),
+        Err(_) => bail!("invalid address '{}'", addr),
+    }
+}
+
+/// Returns the index of the network interface named `name`, or `name` itself if it
+/// is an index already.
+pub(crate) fn interface_index(name: &str) -> anyhow::Result<u32> {
+    if let Ok(index) = name.parse() {
+        return Ok(index);
+    }
+    if_addrs::get_if_addrs()?
+        .into_iter()
+        .find(|interface| interface.name == name)
+        .and_then(|interface| interface.index)
+        .ok_or_else(|| anyhow!("unknown network interface '{}'", name))
+}
+
+/// Returns the scope link-local IPv6 addresses are used with: the index of the
+/// interface that has the address `interface`, if given, or else of the only
+/// interface with a link-local IPv6 address. Returns 0, which leaves the choice to
+/// the system, if there is no such interface or several.
+pub(crate) fn link_local_scope(interface: Option<IpAddr>) -> u32 {
+    let Ok(interfaces) = if_addrs::get_if_addrs() else { return 0 };
+    let mut indexes: Vec<u32> = interfaces
+        .iter()
+        .filter(|i| match interface {
+            Some(ip) => i.ip() == ip,
+            None => matches!(i.ip(), IpAddr::V6(ip) if is_link_local(ip)),
+        })
+        .filter_map(|i| i.index)
+        .collect();
+    indexes.dedup();
+    match indexes[..] {
+        [index] => index,
+        _ => 0,
+    }
+}
+
+/// Returns the addresses a listener bound to `ip` accepts connections on: `ip`
+/// itself, or if it is unspecified, the addresses of all interfaces but loopback, of
+/// both families for `[::]`.
+pub(crate) fn listening_addresses(ip: IpAddr) -> Vec<IpAddr> {
+    if !ip.is_unspecified() {
+        return vec![ip];
+    }
+    let Ok(interfaces) = if_addrs::get_if_addrs() else { return Vec::new() };
+    interfaces
+        .iter()
+        .filter(|i| !i.is_loopback())
+        .map(|i| i.ip())
+        .filter(|addr| ip.is_ipv6() || addr.is_ipv4())
+        .collect()
+}
+
+/// Returns the socket address for `ip` and `port`, with `scope` if `ip` is a
+/// link-local IPv6 address or multicast group, which can only be used on one
+/// interface.
+pub(crate) fn scoped(ip: IpAddr, port: u16, scope: u32) -> SocketAddr {
+    match ip {
+        IpAddr::V6(ip) if is_link_local(ip) => SocketAddrV6::new(ip, port, 0, scope).into(),
+        ip => SocketAddr::new(ip, port),
+    }
+}
+
+/// Returns true for link-local IPv6 unicast addresses (`fe80::/10`) and link-local
+/// multicast groups (`ff02::/16`).
+pub(crate) fn is_link_local(ip: Ipv6Addr) -> bool {
+    ip.is_unicast_link_local() || (ip.is_multicast() && ip.segments()[0] & 0x000f == 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(addr: &str) -> anyhow::Result<String> {
+        parse_addr(addr, DEFAULT_PORT)
+    }
+
+    #[test]
+    fn ipv4_with_and_without_a_port() {
+        assert_eq!(parse("192.168.1.5").unwrap(), "192.168.1.5:12345");
+        assert_eq!(parse(" 192.168.1.5:8080 ").unwrap(), "192.168.1.5:8080");
+    }
+
+    #[test]
+    fn bracketed_ipv6_with_and_without_a_port() {
+        assert_eq!(parse("[::1]:8080").unwrap(), "[::1]:8080");
+        assert_eq!(parse("[2001:db8::1]").unwrap(), "[2001:db8::1]:12345");
+    }
+
+    #[test]
+    fn ipv6_without_brackets_uses_the_default_port() {
+        assert_eq!(parse("::1").unwrap(), "[::1]:12345");
+        assert_eq!(parse("2001:db8::1").unwrap(), "[2001:db8::1]:12345");
+    }
+
+    #[test]
+    fn link_local_addresses_need_a_scope() {
+        assert!(parse("fe80::1").is_err());
+        assert!(parse("[fe80::1]:8080").is_err());
+        assert_eq!(parse("fe80::1%3").unwrap(), "[fe80::1%3]:12345");
+        assert_eq!(parse("[fe80::1%3]:8080").unwrap(), "[fe80::1%3]:8080");
+        assert!(parse("fe80::1%no-such-interface").is_err());
+    }
+
+    #[test]
+    fn only_ipv6_addresses_have_a_scope() {
+        assert!(parse("127.0.0.1%1").is_err());
+        assert!(parse("localhost%1").is_err());
+    }
+
+    #[test]
+    fn host_names_are_passed_through() {
+        assert_eq!(parse("localhost").unwrap(), "localhost:12345");
+        assert_eq!(parse("peer.example.com:4000").unwrap(), "peer.example.com:4000");
+    }
+
+    #[test]
+    fn invalid_ports_are_rejected() {
+        assert!(parse("10.0.0.1:65536").is_err());
+        assert!(parse("10.0.0.1:http").is_err());
+        assert!(parse("localhost:").is_err());
+        assert!(parse("[::1]:").is_err());
+        assert!(parse("[::1]:-1").is_err());
+    }
+
+    #[test]
+    fn malformed_addresses_are_rejected() {
+        assert!(parse("[::1]junk").is_err());
+        assert!(parse("[::1").is_err());
+        assert!(parse("[not-an-ip]:80").is_err());
+        assert!(parse("").is_err());
+        assert!(parse(":8080").is_err());
+    }
+}
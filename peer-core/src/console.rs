@@ -0,0 +1,263 @@
+use std::io::Write;
+use std::sync::{Mutex, OnceLock, Weak};
+use tokio::sync::mpsc;
+use crate::outbox::Pending;
+use crate::persistence::{HistoryEntry, Persist};
+use crate::sessions::SessionManager;
+
+#[cfg(feature = "notify")]
+use notify_rust::Notification;
+
+/// The prompt shown while waiting for input. Background tasks print it again after
+/// writing a line, so the user can see that input is still expected.
+static PROMPT: Mutex<String> = Mutex::new(String::new());
+
+/// The name our own messages are shown with, if not "You".
+static NICKNAME: OnceLock<String> = OnceLock::new();
+
+/// The lines of user input, see `stdin_lines`.
+static LINES: OnceLock<tokio::sync::Mutex<mpsc::UnboundedReceiver<String>>> = OnceLock::new();
+
+/// Where output goes when a frontend is attached, see `attach`.
+static FRONTEND: OnceLock<mpsc::UnboundedSender<String>> = OnceLock::new();
+
+/// The session manager whose conversations `status` reports.
+static SESSIONS: Mutex<Weak<SessionManager>> = Mutex::new(Weak::new());
+
+/// The saved peers `display_name` looks aliases up in, loaded when first needed and
+/// again after `refresh_contacts`.
+static CONTACTS: Mutex<Option<Persist>> = Mutex::new(None);
+
+/// Prints a line of output, like `println!`, or hands it to the attached frontend.
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        $crate::console::write_line(format!($($arg)*), false)
+    };
+}
+
+/// Prints a line of error output, like `eprintln!`, or hands it to the attached
+/// frontend.
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        $crate::console::write_line(format!($($arg)*), true)
+    };
+}
+
+pub(crate) use {errln, outln};
+
+/// The state of one conversation, as shown by a frontend.
+#[derive(Debug, Clone)]
+pub struct ConversationStatus {
+    /// The name used to `/switch` to the conversation.
+    pub name: String,
+    /// What `/list` shows about it, e.g. `connected, unverified`.
+    pub state: String,
+    /// The cipher of the session with the peer, while connected.
+    pub cipher: Option<String>,
+    /// The names of the room's members, if the conversation is a room.
+    pub members: Vec<String>,
+}
+
+/// The conversations of the running chat session, as shown by a frontend.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub conversations: Vec<ConversationStatus>,
+    /// The name of the active conversation, which typed messages go to.
+    pub active: Option<String>,
+}
+
+/// Attaches a frontend that draws the console itself, such as a full-screen terminal
+/// UI. Every line of output is sent on `output` instead of being printed, and the
+/// lines sent on the returned channel are handled as if they were typed on stdin,
+/// which is no longer read. Must be called before the chat session starts; returns
+/// `None` if a frontend is already attached.
+pub fn attach(output: mpsc::UnboundedSender<String>) -> Option<mpsc::UnboundedSender<String>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    FRONTEND.set(output).ok()?;
+    LINES.set(tokio::sync::Mutex::new(rx)).ok()?;
+    Some(tx)
+}
+
+/// Returns the conversations of the running chat session.
+pub fn status() -> Status {
+    match SESSIONS.lock().unwrap().upgrade() {
+        Some(sessions) => sessions.status(),
+        None => Status::default(),
+    }
+}
+
+/// Sets the name we go by. Our own messages are shown under it instead of "You", and
+/// discovery backends that can carry it announce it.
+pub fn set_nickname(nickname: &str) {
+    let _ = NICKNAME.set(nickname.to_string());
+}
+
+/// Returns the name we go by, if one was set.
+pub(crate) fn nickname() -> Option<String> {
+    NICKNAME.get().cloned()
+}
+
+/// Returns the name our own messages are shown with.
+fn own_name() -> &'static str {
+    NICKNAME.get().map_or("You", String::as_str)
+}
+
+/// Returns the current input prompt. It may contain color codes.
+pub fn prompt() -> String {
+    PROMPT.lock().unwrap().clone()
+}
+
+/// Makes `status` report the conversations of a session manager.
+pub(crate) fn watch(sessions: Weak<SessionManager>) {
+    *SESSIONS.lock().unwrap() = sessions;
+}
+
+/// Writes a line of output, see `outln` and `errln`. An attached frontend gets one
+/// line at a time, without the blank lines meant to move past the prompt.
+pub(crate) fn write_line(line: String, error: bool) {
+    match FRONTEND.get() {
+        Some(frontend) => {
+            for line in line.trim_start_matches('\n').lines() {
+                let _ = frontend.send(line.to_string());
+            }
+        }
+        None if error => eprintln!("{}", line),
+        None => println!("{}", line),
+    }
+}
+
+/// Returns the lines typed on stdin. A single task reads stdin for the whole process
+/// and hands the lines to whichever session holds the lock, so no input is lost when
+/// a session ends while waiting for a line. With a frontend attached, the lines come
+/// from the frontend instead.
+pub(crate) fn stdin_lines() -> &'static tokio::sync::Mutex<mpsc::UnboundedReceiver<String>> {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    LINES.get_or_init(|| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut stdin = BufReader::new(tokio::io::stdin());
+            loop {
+                let mut line = String::new();
+                match stdin.read_line(&mut line).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        tokio::sync::Mutex::new(rx)
+    })
+}
+
+/// Waits for the next line typed on stdin, or in the frontend. Returns `None` once
+/// stdin is closed.
+pub async fn read_line() -> Option<String> {
+    stdin_lines().lock().await.recv().await
+}
+
+/// Replaces the input prompt.
+pub(crate) fn set_prompt(prompt: String) {
+    *PROMPT.lock().unwrap() = prompt;
+}
+
+/// Prints the input prompt, unless a frontend shows it.
+pub(crate) fn reprompt() {
+    if FRONTEND.get().is_some() {
+        return;
+    }
+    print!("{}", PROMPT.lock().unwrap());
+    let _ = std::io::stdout().flush();
+}
+
+/// Returns the name to show for an identity key: the alias of the saved peer with
+/// that key, or the start of the key.
+pub(crate) fn display_name(identity: &str) -> String {
+    let mut contacts = CONTACTS.lock().unwrap();
+    if contacts.is_none() {
+        *contacts = Persist::load().ok();
+    }
+    match contacts.as_ref().and_then(|c| c.get_peer_by_key(identity)) {
+        Some(peer) => peer.name.clone(),
+        None => format!("{}…", identity.chars().take(8).collect::<String>()),
+    }
+}
+
+/// Makes `display_name` load the saved peers again, after an alias was saved or a key
+/// pinned or verified.
+pub(crate) fn refresh_contacts() {
+    *CONTACTS.lock().unwrap() = None;
+}
+
+/// Prints a received chat message, tagged with the conversation it belongs to, if
+/// given.
+pub(crate) fn print_message(conversation: Option<&str>, sender: &str, text: &str) {
+    use colored::Colorize;
+
+    let timestamp = chrono::Local::now().format("%H:%M:%S");
+    outln!("\n{} {}{}: {}", timestamp.to_string().dimmed(), tag(conversation), sender.yellow(), text);
+    #[cfg(feature = "notify")]
+    let _ = Notification::new().summary(&format!("New message from {}", sender)).body(text).show();
+}
+
+/// Prints a chat message we sent, tagged with the conversation it was sent to, if
+/// given. `status` follows the message, e.g. `(sending)`.
+pub(crate) fn print_own_message(conversation: Option<&str>, text: &str, status: &str) {
+    use colored::Colorize;
+
+    let timestamp = chrono::Local::now().format("%H:%M:%S");
+    let status = if status.is_empty() { String::new() } else { format!(" {}", status.dimmed()) };
+    outln!("{} {}{}: {}{}", timestamp.to_string().dimmed(), tag(conversation), own_name().green(), text, status);
+}
+
+/// Prints earlier messages of a conversation from the history.
+pub(crate) fn print_scrollback(conversation: &str, entries: &[HistoryEntry], own_id: &str) {
+    use colored::Colorize;
+
+    outln!("{}", format!("── Earlier messages with {} ──", conversation).dimmed());
+    for entry in entries {
+        let time = chrono::DateTime::from_timestamp(entry.timestamp as i64, 0)
+            .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let sender = if entry.sender_id == own_id { own_name().green() } else { display_name(&entry.sender_id).yellow() };
+        outln!("{} {}: {}", time.dimmed(), sender, entry.text);
+    }
+    outln!("{}", "──".dimmed());
+}
+
+/// Formats the conversation tag in front of a chat line.
+fn tag(conversation: Option<&str>) -> String {
+    use colored::Colorize;
+
+    match conversation {
+        Some(name) => format!("{} ", format!("[{}]", name).cyan()),
+        None => String::new(),
+    }
+}
+
+/// Reports the messages among `messages` written by `own_id` as not delivered to
+/// `name`. Those a room host relays for others are not ours to report.
+pub(crate) fn print_undelivered(name: &str, own_id: &str, messages: &[Pending]) {
+    use colored::Colorize;
+
+    let mine: Vec<_> = messages.iter().filter(|p| p.sender_id == own_id).collect();
+    for p in &mine {
+        outln!("\n{} {}", format!("✗ Not delivered to {}:", name).red(), preview(&p.text));
+    }
+    if !mine.is_empty() {
+        reprompt();
+    }
+}
+
+/// Shortens a message for delivery status lines.
+pub(crate) fn preview(text: &str) -> String {
+    const MAX: usize = 40;
+    if text.chars().count() <= MAX {
+        return text.to_string();
+    }
+    let short: String = text.chars().take(MAX).collect();
+    format!("{}…", short)
+}
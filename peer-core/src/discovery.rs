@@ -1,41 +1,458 @@
 use tokio::net::UdpSocket;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use peer_common::identity::verify_announcement;
+use peer_common::types::PeerStatus;
+use peer_common::{key_fingerprint, CipherType, Identity};
+use crate::addr::{is_link_local, link_local_scope, scoped};
+use crate::console::errln;
+use crate::mdns::MdnsDiscovery;
 
-const DISCOVERY_MSG: &str = "p2p-chat-discovery";
+/// The prefix of a broadcast announcement, followed by a `SignedAnnouncement` in JSON.
+const ANNOUNCE_PREFIX: &str = "p2p-chat-announce:";
 
-/// Continuously broadcasts a UDP message to the local network to announce the peer's
-/// presence. The message includes a discovery string and the port the peer is
-/// listening on. This allows other peers to discover and connect to this peer.
-pub async fn broadcast_presence(listen_port: u16) -> anyhow::Result<()> {
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.set_broadcast(true)?;
-    let broadcast_addr = "255.255.255.255:8888".parse::<SocketAddr>()?;
+/// The link-local all-nodes multicast group, which every IPv6 host receives.
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
 
-    let msg = format!("{}:{}", DISCOVERY_MSG, listen_port);
+/// How many of its intervals a peer that announces itself periodically may go
+/// unheard before it is taken to be gone, and how old its announcements may be.
+const MISSED_ANNOUNCEMENTS: u32 = 3;
 
-    loop {
-        socket.send_to(msg.as_bytes(), &broadcast_addr).await?;
-        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+/// The longest announcement interval a peer is taken at its word for.
+const MAX_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How far the clocks of two peers may be apart for their announcements to be
+/// accepted.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// The ways peers can be announced and found on the local network.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryBackend {
+    /// A UDP broadcast or multicast of the listening port, sent to every `targets`
+    /// address.
+    #[default]
+    Broadcast,
+    /// A `_p2pchat._tcp.local` DNS-SD service, advertised and browsed over multicast
+    /// DNS. It carries the nickname, key fingerprint and protocol version of the peer.
+    Mdns,
+}
+
+/// Settings for announcing this peer on the local network and finding other peers.
+/// They are saved in the configuration file under `discovery`; missing fields take
+/// their default values.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Whether the listener announces itself and `discover` searches for peers.
+    pub enabled: bool,
+    /// How peers are announced and found.
+    pub backend: DiscoveryBackend,
+    /// The UDP port announcements are sent to and received on. Only used by the
+    /// broadcast backend; multicast DNS always uses port 5353.
+    pub port: u16,
+    /// How often the listener announces itself, in seconds. Only used by the
+    /// broadcast backend; multicast DNS answers queries instead.
+    pub interval_secs: u64,
+    /// The addresses announcements are sent to: IPv4 broadcast addresses, IPv6
+    /// multicast groups, or the unicast addresses of peers on networks that do not
+    /// forward either. A listener only announces to the addresses of the families it
+    /// accepts connections on. Only used by the broadcast backend.
+    pub targets: Vec<IpAddr>,
+    /// The address of the local interface to announce on, or `None` to let the
+    /// system choose. Link-local IPv6 addresses are used on this interface, too. With
+    /// the broadcast backend, announcements are received on every interface.
+    pub interface: Option<IpAddr>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            enabled: true,
+            backend: DiscoveryBackend::default(),
+            port: 8888,
+            interval_secs: 5,
+            targets: vec![IpAddr::V4(Ipv4Addr::BROADCAST), IpAddr::V6(ALL_NODES)],
+            interface: None,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    /// Returns the time between two announcements.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    /// Creates the discovery backend these settings select.
+    pub fn start(&self) -> anyhow::Result<Box<dyn Discovery>> {
+        Ok(match self.backend {
+            DiscoveryBackend::Broadcast => Box::new(BroadcastDiscovery::new(self.clone())),
+            DiscoveryBackend::Mdns => Box::new(MdnsDiscovery::new(self)?),
+        })
     }
 }
 
-/// Listens for UDP broadcast messages from other peers. When a valid discovery
-/// message is received, it extracts the peer's address and port and returns it.
-/// This function is used by the `discover` command to find peers on the network.
-pub async fn listen_for_peers() -> anyhow::Result<SocketAddr> {
-    let socket = UdpSocket::bind("0.0.0.0:8888").await?;
-    let mut buf = [0; 1024];
+/// The details a listening peer announces about itself. Announcements are signed
+/// with the peer's identity key, so a peer cannot be announced by anyone else.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    /// The name the peer goes by, if it set one.
+    pub nickname: Option<String>,
+    /// The base64-encoded public identity key of the peer, which signs the
+    /// announcement.
+    pub identity: String,
+    /// The highest protocol version the peer speaks.
+    pub version: u32,
+    /// The ciphers the peer supports, strongest first.
+    pub ciphers: Vec<CipherType>,
+    /// The TCP port the peer listens on.
+    pub port: u16,
+    /// The addresses the peer accepts connections on.
+    pub addresses: Vec<IpAddr>,
+    /// The status the peer shows, if it announced one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<PeerStatus>,
+    /// When the announcement was signed, in milliseconds since the Unix epoch. It
+    /// keeps an announcement from being replayed once it is old.
+    pub timestamp: u64,
+    /// How often the peer announces itself, in seconds.
+    pub interval_secs: u64,
+}
+
+impl Announcement {
+    /// Returns how often the peer announces itself, at most `MAX_ANNOUNCE_INTERVAL`.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1)).min(MAX_ANNOUNCE_INTERVAL)
+    }
+
+    /// Returns whether two announcements tell the same about a peer, whenever they
+    /// were signed.
+    pub fn same_details(&self, other: &Announcement) -> bool {
+        *self == Announcement { timestamp: self.timestamp, ..other.clone() }
+    }
 
-    loop {
-        let (len, addr) = socket.recv_from(&mut buf).await?;
-        let msg = String::from_utf8_lossy(&buf[..len]);
+    /// Returns whether the announcement was signed recently enough, allowing for
+    /// `MAX_CLOCK_SKEW`: at most `MISSED_ANNOUNCEMENTS` intervals ago, and not in
+    /// the future.
+    pub(crate) fn is_fresh(&self) -> bool {
+        let now = now_millis();
+        let max_age = (self.interval() * MISSED_ANNOUNCEMENTS + MAX_CLOCK_SKEW).as_millis() as u64;
+        self.timestamp.saturating_add(max_age) >= now && self.timestamp <= now + MAX_CLOCK_SKEW.as_millis() as u64
+    }
+
+    /// Serializes the announcement and signs it with `identity`, which must be the
+    /// identity it names.
+    pub fn sign(&self, identity: &Identity) -> anyhow::Result<SignedAnnouncement> {
+        let announcement = serde_json::to_string(self)?;
+        let signature = identity.sign_announcement(announcement.as_bytes());
+        Ok(SignedAnnouncement { announcement, signature })
+    }
+}
+
+/// A serialized `Announcement` and the signature of the identity key it names.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedAnnouncement {
+    pub announcement: String,
+    pub signature: String,
+}
+
+impl SignedAnnouncement {
+    /// Parses the announcement and checks that it was signed by the identity key it
+    /// names.
+    pub fn verify(&self) -> anyhow::Result<Announcement> {
+        let announcement: Announcement = serde_json::from_str(&self.announcement)?;
+        verify_announcement(&announcement.identity, self.announcement.as_bytes(), &self.signature)?;
+        Ok(announcement)
+    }
+}
+
+/// What a listening peer announces about itself, and the identity to sign it with.
+/// The status in `announcement` is replaced with the latest one from `status`, and
+/// announced again as soon as it changes.
+#[derive(Clone)]
+pub struct Presence {
+    pub announcement: Announcement,
+    pub identity: Arc<Identity>,
+    pub status: watch::Receiver<PeerStatus>,
+    /// Whether the peer accepts connections over IPv4.
+    pub ipv4: bool,
+    /// Whether the peer accepts connections over IPv6.
+    pub ipv6: bool,
+}
+
+/// A peer found on the local network, with its verified announcement. A link-local
+/// IPv6 address includes its scope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundPeer {
+    /// The address to connect to.
+    pub addr: SocketAddr,
+    pub announcement: Announcement,
+    /// The fingerprint of the peer's identity key, see `key_fingerprint`.
+    pub fingerprint: String,
+}
+
+impl FoundPeer {
+    /// Returns the peer found at `addr` with a verified `announcement`.
+    pub(crate) fn new(addr: SocketAddr, announcement: Announcement) -> anyhow::Result<Self> {
+        let fingerprint = key_fingerprint(&announcement.identity)?;
+        Ok(FoundPeer { addr, announcement, fingerprint })
+    }
+}
+
+/// A way of announcing this peer on the local network and finding other peers. The
+/// backend is picked with `DiscoveryConfig::start`.
+#[async_trait]
+pub trait Discovery: Send + Sync {
+    /// Announces `presence` until the future is dropped, and again whenever its
+    /// status changes. Returns only on errors that stop every announcement; a target
+    /// that cannot be reached is reported and skipped.
+    async fn announce(&self, presence: &Presence) -> anyhow::Result<()>;
+
+    /// Waits for the next announcement from a peer with a valid signature, or for the
+    /// news that a peer has left; other announcements are ignored. The search starts
+    /// on the first call and keeps running between calls, so the same peer may be
+    /// seen again and again.
+    async fn next_sighting(&self) -> anyhow::Result<Sighting>;
+
+    /// Returns how long the peer that made `announcement` stays listed if it is not
+    /// seen again, or `None` if the backend reports peers that left itself.
+    fn expiry(&self, announcement: &Announcement) -> Option<Duration>;
+}
+
+/// What a search learned about a peer.
+#[derive(Debug, Clone)]
+pub enum Sighting {
+    /// A peer announced itself.
+    Seen(FoundPeer),
+    /// The peer with the identity key `identity` listening on `port` has left.
+    Gone { identity: String, port: u16 },
+}
 
-        if let Some(port_str) = msg.strip_prefix(&format!("{}:", DISCOVERY_MSG)) {
-            if let Ok(port) = port_str.parse::<u16>() {
-                let mut peer_addr = addr;
-                peer_addr.set_port(port);
-                return Ok(peer_addr);
+/// Discovery by UDP broadcast or multicast. A signed announcement is sent to every
+/// target once per interval.
+struct BroadcastDiscovery {
+    config: DiscoveryConfig,
+    /// The sockets announcements are received on, bound on the first search.
+    sockets: tokio::sync::OnceCell<Receivers>,
+    /// The timestamp of the latest announcement accepted from each identity key and
+    /// port. Older ones are replays, or copies that arrived on another socket.
+    latest: Mutex<HashMap<(String, u16), u64>>,
+}
+
+/// The sockets a search receives IPv4 and IPv6 announcements on. A family the host
+/// has no addresses for has no socket.
+struct Receivers {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl BroadcastDiscovery {
+    fn new(config: DiscoveryConfig) -> Self {
+        BroadcastDiscovery { config, sockets: tokio::sync::OnceCell::new(), latest: Mutex::new(HashMap::new()) }
+    }
+
+    /// Binds the sockets to receive announcements on, and joins the IPv6 multicast
+    /// groups among the targets.
+    fn bind_receivers(&self) -> anyhow::Result<Receivers> {
+        let port = self.config.port;
+        let v4 = bind_reusable(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+        let v6 = bind_reusable(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port));
+        if let Ok(socket) = &v6 {
+            let scope = link_local_scope(self.config.interface);
+            for target in &self.config.targets {
+                if let IpAddr::V6(group) = target {
+                    if group.is_multicast() && *group != ALL_NODES {
+                        socket.join_multicast_v6(group, scope)?;
+                    }
+                }
+            }
+        }
+        match (v4, v6) {
+            (Err(e), Err(_)) => Err(e),
+            (v4, v6) => Ok(Receivers { v4: v4.ok(), v6: v6.ok() }),
+        }
+    }
+
+    /// Binds the socket to announce to the targets of one family from, on the
+    /// configured interface if it is of that family.
+    async fn bind_sender(&self, unspecified: IpAddr) -> anyhow::Result<UdpSocket> {
+        let interface = match self.config.interface {
+            Some(ip) if ip.is_ipv4() == unspecified.is_ipv4() => ip,
+            _ => unspecified,
+        };
+        let scope = link_local_scope(self.config.interface);
+        let socket = UdpSocket::bind(scoped(interface, 0, scope)).await?;
+        socket.set_broadcast(true)?;
+        Ok(socket)
+    }
+}
+
+#[async_trait]
+impl Discovery for BroadcastDiscovery {
+    /// Continuously sends a UDP message to the local network to announce the peer's
+    /// presence. The message is the announcement prefix and the signed announcement.
+    /// This allows other peers to discover and connect to this peer. A change of
+    /// status is sent right away, without waiting for the interval. Link-local
+    /// targets are sent to on the configured interface.
+    async fn announce(&self, presence: &Presence) -> anyhow::Result<()> {
+        let scope = link_local_scope(self.config.interface);
+        let targets: Vec<SocketAddr> = self
+            .config
+            .targets
+            .iter()
+            .filter(|ip| if ip.is_ipv4() { presence.ipv4 } else { presence.ipv6 })
+            .map(|ip| scoped(*ip, self.config.port, scope))
+            .collect();
+        if targets.is_empty() {
+            anyhow::bail!("none of the discovery targets is reachable from the listening address");
+        }
+        let v4 = if targets.iter().any(SocketAddr::is_ipv4) {
+            Some(self.bind_sender(IpAddr::V4(Ipv4Addr::UNSPECIFIED)).await?)
+        } else {
+            None
+        };
+        let v6 = if targets.iter().any(SocketAddr::is_ipv6) {
+            Some(self.bind_sender(IpAddr::V6(Ipv6Addr::UNSPECIFIED)).await?)
+        } else {
+            None
+        };
+
+        let mut status = presence.status.clone();
+        let mut failing = HashSet::new();
+        loop {
+            let announcement = Announcement {
+                status: Some(status.borrow_and_update().clone()),
+                timestamp: now_millis(),
+                interval_secs: self.config.interval().as_secs(),
+                ..presence.announcement.clone()
+            };
+            let signed = announcement.sign(&presence.identity)?;
+            let msg = format!("{}{}", ANNOUNCE_PREFIX, serde_json::to_string(&signed)?);
+            for target in &targets {
+                let socket = if target.is_ipv4() { &v4 } else { &v6 };
+                let Some(socket) = socket else { continue };
+                // One unreachable target must not silence the others. Each failure is
+                // reported once, until the target works again.
+                match socket.send_to(msg.as_bytes(), target).await {
+                    Ok(_) => {
+                        failing.remove(target);
+                    }
+                    Err(e) => {
+                        if failing.insert(*target) {
+                            errln!("could not announce to {}: {}", target, e);
+                        }
+                    }
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.config.interval()) => {}
+                Ok(()) = status.changed() => {}
+            }
+        }
+    }
+
+    /// Listens for UDP broadcast and multicast messages from other peers. When an
+    /// announcement with a valid signature is received, it returns the peer with the
+    /// address to connect to, see `peer_addr`. Announcements that are stale, or not
+    /// newer than the last one from the same peer, are ignored, so a recorded one
+    /// cannot be replayed. Broadcasts do not tell when a peer leaves; it expires
+    /// instead. The sockets allow the address to be reused, so several peers on one
+    /// host can search at the same time.
+    async fn next_sighting(&self) -> anyhow::Result<Sighting> {
+        let receivers = self.sockets.get_or_try_init(|| async { self.bind_receivers() }).await?;
+        let mut buf_v4 = [0; 4096];
+        let mut buf_v6 = [0; 4096];
+
+        loop {
+            let (msg, addr) = tokio::select! {
+                received = recv_from(receivers.v4.as_ref(), &mut buf_v4) => {
+                    let (len, addr) = received?;
+                    (String::from_utf8_lossy(&buf_v4[..len]), addr)
+                }
+                received = recv_from(receivers.v6.as_ref(), &mut buf_v6) => {
+                    let (len, addr) = received?;
+                    (String::from_utf8_lossy(&buf_v6[..len]), addr)
+                }
+            };
+
+            let Some(signed) = msg.strip_prefix(ANNOUNCE_PREFIX) else { continue };
+            let Ok(signed) = serde_json::from_str::<SignedAnnouncement>(signed) else { continue };
+            let Ok(announcement) = signed.verify() else { continue };
+            if !announcement.is_fresh() {
+                continue;
+            }
+            let key = (announcement.identity.clone(), announcement.port);
+            let mut latest = self.latest.lock().unwrap();
+            if latest.get(&key).is_some_and(|&seen| seen >= announcement.timestamp) {
+                continue;
             }
+            latest.insert(key, announcement.timestamp);
+            drop(latest);
+            return Ok(Sighting::Seen(FoundPeer::new(peer_addr(&announcement, addr), announcement)?));
         }
     }
+
+    /// Peers announce themselves once per interval of their own, so one that missed
+    /// `MISSED_ANNOUNCEMENTS` is taken to be gone.
+    fn expiry(&self, announcement: &Announcement) -> Option<Duration> {
+        Some(announcement.interval() * MISSED_ANNOUNCEMENTS)
+    }
+}
+
+/// Picks the address to connect to a peer whose announcement came from `from`. That
+/// is the sender's address if the peer announced it. Otherwise, since anyone can
+/// resend a signed announcement, it is one of the announced addresses, of the
+/// sender's family if there is one, and link-local ones get the sender's scope.
+fn peer_addr(announcement: &Announcement, from: SocketAddr) -> SocketAddr {
+    let (ip, scope) = match from {
+        SocketAddr::V4(v4) => (IpAddr::V4(*v4.ip()), 0),
+        SocketAddr::V6(v6) => (v6.ip().to_canonical(), v6.scope_id()),
+    };
+    if announcement.addresses.is_empty() || announcement.addresses.contains(&ip) {
+        return scoped(ip, announcement.port, scope);
+    }
+    let routable = |a: &&IpAddr| !matches!(a, IpAddr::V6(v6) if is_link_local(*v6));
+    let same_family = |a: &&IpAddr| a.is_ipv4() == ip.is_ipv4();
+    let announced = announcement
+        .addresses
+        .iter()
+        .filter(same_family)
+        .find(routable)
+        .or_else(|| announcement.addresses.iter().find(same_family))
+        .unwrap_or(&announcement.addresses[0]);
+    scoped(*announced, announcement.port, scope)
+}
+
+/// Receives a datagram on `socket`, or waits forever if there is no socket.
+async fn recv_from(socket: Option<&UdpSocket>, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Binds a UDP socket that other sockets can bind to the same address, too. An IPv6
+/// socket only receives IPv6 traffic, so an IPv4 socket can share its port.
+fn bind_reusable(addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
 }
@@ -0,0 +1,241 @@
+use peer_common::{wire, Codec, WireError, WireMessage};
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Limits applied to every connection. The frame size is checked before anything is
+/// allocated, so a peer cannot make us reserve memory with a bogus length prefix.
+#[derive(Clone, Copy, Debug)]
+pub struct FramingConfig {
+    /// The largest frame body, in bytes, that we accept or send.
+    pub max_frame_size: usize,
+    /// How long the peer has to complete the handshake.
+    pub handshake_timeout: Duration,
+    /// How long an established session may go without receiving a frame. Heartbeats
+    /// keep an idle but healthy session busy.
+    pub idle_timeout: Duration,
+}
+
+impl Default for FramingConfig {
+    fn default() -> Self {
+        FramingConfig {
+            max_frame_size: 1024 * 1024,
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(2 * 60 * 60),
+        }
+    }
+}
+
+/// The errors that can occur while reading or writing a frame.
+#[derive(Debug)]
+pub enum FrameError {
+    /// A frame announced (or would need) more bytes than `max_frame_size`.
+    TooLarge { len: usize, max: usize },
+    /// No frame arrived within the given time.
+    Timeout(Duration),
+    /// The frame body could not be encoded or decoded. The connection is still in
+    /// sync, so the frame can be skipped.
+    Wire(WireError),
+    /// The connection failed.
+    Io(std::io::Error),
+}
+
+impl FrameError {
+    /// Returns true if the connection can no longer be used after this error.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, FrameError::Wire(_))
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => write!(f, "frame of {} bytes exceeds the {} byte limit", len, max),
+            FrameError::Timeout(after) => write!(f, "timed out after {}s", after.as_secs()),
+            FrameError::Wire(e) => write!(f, "{}", e),
+            FrameError::Io(e) => write!(f, "connection error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<WireError> for FrameError {
+    fn from(e: WireError) -> Self {
+        FrameError::Wire(e)
+    }
+}
+
+/// Reads length-prefixed `WireMessage` frames: a 4-byte big-endian length followed by
+/// a frame body in any codec.
+pub struct FrameReader<R> {
+    inner: R,
+    config: FramingConfig,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R, config: FramingConfig) -> Self {
+        FrameReader { inner, config }
+    }
+
+    /// Reads the peer's handshake, waiting at most `handshake_timeout`.
+    pub async fn read_handshake(&mut self) -> Result<WireMessage, FrameError> {
+        let timeout = self.config.handshake_timeout;
+        match self.read_within(timeout).await? {
+            Some(wm) => Ok(wm),
+            None => Err(FrameError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    /// Reads the next frame of an established session, waiting at most
+    /// `idle_timeout`. Returns `None` once the peer has closed the connection.
+    pub async fn read(&mut self) -> Result<Option<WireMessage>, FrameError> {
+        let timeout = self.config.idle_timeout;
+        self.read_within(timeout).await
+    }
+
+    async fn read_within(&mut self, timeout: Duration) -> Result<Option<WireMessage>, FrameError> {
+        match tokio::time::timeout(timeout, self.read_frame()).await {
+            Ok(result) => result,
+            Err(_) => Err(FrameError::Timeout(timeout)),
+        }
+    }
+
+    /// Reads one frame. The connection may only end between frames; one that ends
+    /// within a length prefix or a body is an error.
+    async fn read_frame(&mut self) -> Result<Option<WireMessage>, FrameError> {
+        let mut len_buf = [0u8; 4];
+        let mut filled = 0;
+        while filled < len_buf.len() {
+            match self.inner.read(&mut len_buf[filled..]).await? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(FrameError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+                n => filled += n,
+            }
+        }
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len > self.config.max_frame_size {
+            return Err(FrameError::TooLarge { len, max: self.config.max_frame_size });
+        }
+        let mut buf = vec![0u8; len];
+        self.inner.read_exact(&mut buf).await?;
+        Ok(Some(wire::decode(&buf)?))
+    }
+}
+
+/// Writes length-prefixed `WireMessage` frames in the current codec. The handshake is
+/// always written as JSON; the codec is switched once it has been negotiated.
+pub struct FrameWriter<W> {
+    inner: W,
+    codec: Codec,
+    max_frame_size: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(inner: W, config: FramingConfig) -> Self {
+        FrameWriter { inner, codec: Codec::Json, max_frame_size: config.max_frame_size }
+    }
+
+    /// Returns the codec used for outgoing frames.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Switches the codec used for subsequent frames.
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Encodes and writes a frame.
+    pub async fn write(&mut self, wm: &WireMessage) -> Result<(), FrameError> {
+        let body = wire::encode(wm, self.codec)?;
+        if body.len() > self.max_frame_size {
+            return Err(FrameError::TooLarge { len: body.len(), max: self.max_frame_size });
+        }
+        let mut frame = Vec::with_capacity(4 + body.len());
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&body);
+        self.inner.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration whose frames may be at most `max_frame_size` bytes long.
+    fn config(max_frame_size: usize) -> FramingConfig {
+        FramingConfig { max_frame_size, ..FramingConfig::default() }
+    }
+
+    /// Returns the length-prefixed frame of `body`.
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[tokio::test]
+    async fn frame_of_exactly_the_maximum_size_is_written_and_read() {
+        let body = wire::encode(&WireMessage::Ping { seq: 7 }, Codec::Json).unwrap();
+        let mut written = Vec::new();
+        FrameWriter::new(&mut written, config(body.len())).write(&WireMessage::Ping { seq: 7 }).await.unwrap();
+        assert_eq!(written, frame(&body));
+
+        let mut reader = FrameReader::new(written.as_slice(), config(body.len()));
+        assert!(matches!(reader.read().await, Ok(Some(WireMessage::Ping { seq: 7 }))));
+        assert!(matches!(reader.read().await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn frame_one_byte_over_the_maximum_is_refused() {
+        let body = wire::encode(&WireMessage::Ping { seq: 7 }, Codec::Json).unwrap();
+        let max = body.len() - 1;
+        let mut written = Vec::new();
+        let result = FrameWriter::new(&mut written, config(max)).write(&WireMessage::Ping { seq: 7 }).await;
+        assert!(matches!(result, Err(FrameError::TooLarge { len, max: m }) if len == max + 1 && m == max));
+        assert!(written.is_empty());
+
+        let incoming = frame(&body);
+        let result = FrameReader::new(incoming.as_slice(), config(max)).read().await;
+        assert!(matches!(result, Err(FrameError::TooLarge { len, max: m }) if len == max + 1 && m == max));
+    }
+
+    #[tokio::test]
+    async fn bogus_length_is_refused_before_reading_the_body() {
+        let incoming = u32::MAX.to_be_bytes();
+        let result = FrameReader::new(&incoming[..], FramingConfig::default()).read().await;
+        assert!(matches!(result, Err(FrameError::TooLarge { len, .. }) if len == u32::MAX as usize));
+    }
+
+    #[tokio::test]
+    async fn truncated_length_prefix_is_an_error() {
+        let incoming = [0u8, 0];
+        let result = FrameReader::new(&incoming[..], FramingConfig::default()).read().await;
+        assert!(matches!(result, Err(FrameError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn truncated_body_is_an_error() {
+        let mut incoming = frame(br#"{"Ping":{"seq":7}}"#);
+        incoming.truncate(incoming.len() - 3);
+        let result = FrameReader::new(incoming.as_slice(), FramingConfig::default()).read().await;
+        assert!(matches!(result, Err(FrameError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn undecodable_body_can_be_skipped() {
+        let mut incoming = frame(b"not a frame");
+        incoming.extend(frame(br#"{"Ping":{"seq":7}}"#));
+        let mut reader = FrameReader::new(incoming.as_slice(), FramingConfig::default());
+        assert!(matches!(reader.read().await, Err(e @ FrameError::Wire(_)) if !e.is_fatal()));
+        assert!(matches!(reader.read().await, Ok(Some(WireMessage::Ping { seq: 7 }))));
+    }
+}
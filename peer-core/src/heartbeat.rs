@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+/// How often a `Ping` is sent to the peer.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How many pings in a row may go unanswered before the peer is declared dead.
+pub const MAX_MISSED_BEATS: u32 = 3;
+
+/// Tracks the heartbeat of a session: the ping in flight, the latest round-trip time
+/// and how many pings in a row went unanswered.
+#[derive(Default)]
+pub struct Heartbeat {
+    next_seq: u64,
+    outstanding: Option<(u64, Instant)>,
+    rtt: Option<Duration>,
+    missed: u32,
+}
+
+impl Heartbeat {
+    /// Starts a new ping and returns its sequence number. A ping that is still
+    /// unanswered when the next one starts counts as missed.
+    pub fn ping(&mut self) -> u64 {
+        if self.outstanding.is_some() {
+            self.missed += 1;
+        }
+        self.next_seq += 1;
+        self.outstanding = Some((self.next_seq, Instant::now()));
+        self.next_seq
+    }
+
+    /// Handles a `Pong`. Returns the round-trip time if it answers the ping in flight.
+    pub fn pong(&mut self, seq: u64) -> Option<Duration> {
+        match self.outstanding {
+            Some((expected, sent)) if expected == seq => {
+                let rtt = sent.elapsed();
+                self.outstanding = None;
+                self.rtt = Some(rtt);
+                self.missed = 0;
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the latest round-trip time, if any ping has been answered yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Returns how many pings in a row went unanswered.
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    /// Returns true once `MAX_MISSED_BEATS` pings in a row went unanswered.
+    pub fn is_dead(&self) -> bool {
+        self.missed >= MAX_MISSED_BEATS
+    }
+}
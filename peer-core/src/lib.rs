@@ -1,6 +1,22 @@
+pub mod addr;
+pub mod console;
+pub mod framing;
+pub mod heartbeat;
+mod link;
 pub mod net;
+pub mod outbox;
+pub mod peer_list;
 pub mod persistence;
+mod room;
+mod sessions;
+pub mod status;
+mod transfer;
 pub mod discovery;
+mod mdns;
 
+pub use addr::{parse_addr, DEFAULT_PORT};
+pub use framing::FramingConfig;
 pub use net::{start_listener, start_client};
-pub use discovery::{broadcast_presence, listen_for_peers};
\ No newline at end of file
+pub use discovery::{Announcement, Discovery, DiscoveryBackend, DiscoveryConfig, FoundPeer, Presence, Sighting, SignedAnnouncement};
+pub use peer_list::{DiscoveryService, PeerEvent, SeenPeer};
+pub use persistence::load_or_create_identity;
\ No newline at end of file
@@ -0,0 +1,623 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use peer_common::types::{
+    chat_associated_data, new_message_id, FileChunk, FileMessage, FileOffer, PeerStatus, RoomEvent, SealedEnvelope, SealedKind,
+    StatusUpdate, WireMessage,
+};
+use peer_common::crypto::pubkey_from_bytes;
+use peer_common::ratchet::RatchetHeader;
+use peer_common::rekey::{Keyring, RekeyPolicy};
+use peer_common::wire::{decode, encode};
+use peer_common::{CipherType, Codec, Identity, Session};
+use crate::console::{self, display_name, errln, outln, preview};
+use crate::framing::{FrameError, FrameReader, FrameWriter};
+use crate::heartbeat::{Heartbeat, HEARTBEAT_INTERVAL};
+use crate::outbox::{Outbox, Pending};
+use crate::status;
+use crate::transfer::{self, Transfers, CHUNKS_IN_FLIGHT};
+
+/// How often the session checks whether the rekey policy calls for a rekey.
+const REKEY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How often unacknowledged messages are checked for the `ACK_TIMEOUT`.
+const RECEIPT_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The ID of the next link started in this process.
+static NEXT_LINK_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Describes the remote end of an established session: the saved alias it resolved
+/// to (if any), its identity key, and whether that key has been verified.
+pub(crate) struct PeerInfo {
+    pub name: Option<String>,
+    pub identity: String,
+    pub verified: bool,
+}
+
+/// A connection whose handshake has completed, ready to be started as a `Link`.
+pub(crate) struct Connection {
+    pub reader: FrameReader<OwnedReadHalf>,
+    pub writer: FrameWriter<OwnedWriteHalf>,
+    pub session: Session,
+    pub peer: PeerInfo,
+    pub is_client: bool,
+}
+
+/// What a link hands to whoever owns it.
+pub(crate) enum LinkEvent {
+    /// A chat message arrived on the link with the given ID. `sender_id` is the
+    /// identity key of its author and `sender` the name to show for it;
+    /// retransmissions are not reported twice.
+    Chat { link: u64, id: String, sender_id: String, sender: String, text: String },
+    /// The room host on the link reported a membership change.
+    Room { link: u64, event: RoomEvent },
+    /// The peer on the link offered a file, which waits for `/accept` or `/reject`.
+    /// Offers that resume an accepted transfer are not reported.
+    FileOffer { link: u64, sender: String, offer: FileOffer },
+    /// The peer on the link changed its status, or, if it hosts a room, relayed a
+    /// change of status of another member. `previous` is the status reported for the
+    /// same member before, if any.
+    Status { link: u64, update: StatusUpdate, previous: Option<PeerStatus> },
+}
+
+/// The sending side of the secure channel to one peer. Background tasks read and
+/// write its frames, rekey it, report undelivered messages, keep the peer up to date
+/// with our status and check the peer's heartbeat; the `LinkTasks` returned by `Link::start` own them. Received messages
+/// are reported as `LinkEvent`s, tagged with the link's `id`, which is unique within
+/// the process. A `Link` is cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Link {
+    pub id: u64,
+    pub peer: Arc<PeerInfo>,
+    pub cipher: CipherType,
+    /// The codec frames are written in, which also encodes what encrypted frames
+    /// carry.
+    codec: Codec,
+    verified: Arc<AtomicBool>,
+    keyring: Arc<Mutex<Keyring>>,
+    out_tx: mpsc::UnboundedSender<WireMessage>,
+    outbox: Arc<Mutex<Outbox>>,
+    /// Our identity key, to tell our own messages from those relayed for others.
+    own_id: String,
+    transfers: Arc<Mutex<Transfers>>,
+    chunk_window: Arc<Semaphore>,
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    /// The latest status reported on the link for each identity key.
+    statuses: Arc<Mutex<HashMap<String, PeerStatus>>>,
+    /// Whether the peer hosts the room we are in on this link. Every listener hosts
+    /// a room, so that is the case whenever we connected to the peer.
+    hosts_room: bool,
+    /// The identity keys of the members of the room, as the host last reported them.
+    members: Arc<Mutex<Vec<String>>>,
+}
+
+/// The background tasks of a `Link`.
+pub(crate) struct LinkTasks {
+    writer: JoinHandle<()>,
+    reader: JoinHandle<()>,
+    heartbeat: JoinHandle<()>,
+    rekey: JoinHandle<()>,
+    receipts: JoinHandle<()>,
+    status: JoinHandle<()>,
+}
+
+impl Link {
+    /// Starts the background tasks for an established connection. Messages left
+    /// unacknowledged by an earlier session with the same peer, or queued while
+    /// reconnecting, are sent first, and unfinished file transfers are offered again.
+    pub fn start(
+        conn: Connection,
+        identity: Arc<Identity>,
+        outbox: Arc<Mutex<Outbox>>,
+        transfers: Arc<Mutex<Transfers>>,
+        events: mpsc::UnboundedSender<LinkEvent>,
+    ) -> anyhow::Result<(Link, LinkTasks)> {
+        use colored::Colorize;
+
+        let Connection { mut reader, mut writer, session, peer, is_client } = conn;
+        outln!("🔒 Secure channel established ({}, {}). You can type messages now.", session.cipher, writer.codec());
+        if !peer.verified {
+            outln!("{}", "⚠️  This peer's identity is not verified. Type /verify to compare safety numbers.".yellow());
+        }
+        let cipher = session.cipher;
+        let codec = writer.codec();
+        let keyring = Keyring::new(session, is_client, identity.clone(), peer.identity.clone(), RekeyPolicy::default());
+
+        // Every outgoing frame goes through this channel, so frames from different
+        // tasks are written whole and in the order they were encrypted.
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel::<WireMessage>();
+        let link = Link {
+            id: NEXT_LINK_ID.fetch_add(1, Ordering::SeqCst),
+            verified: Arc::new(AtomicBool::new(peer.verified)),
+            peer: Arc::new(peer),
+            cipher,
+            codec,
+            keyring: Arc::new(Mutex::new(keyring)),
+            out_tx,
+            outbox,
+            own_id: identity.public_b64(),
+            transfers,
+            chunk_window: Arc::new(Semaphore::new(CHUNKS_IN_FLIGHT)),
+            heartbeat: Arc::new(Mutex::new(Heartbeat::default())),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+            hosts_room: is_client,
+            members: Arc::new(Mutex::new(Vec::new())),
+        };
+        let name = link.name();
+        let own_id = link.own_id.clone();
+
+        let chunk_window = link.chunk_window.clone();
+        let writer_task = tokio::spawn(async move {
+            while let Some(wm) = out_rx.recv().await {
+                let result = writer.write(&wm).await;
+                if matches!(wm, WireMessage::Sealed { kind: SealedKind::FileChunk, .. }) {
+                    chunk_window.add_permits(1);
+                }
+                match result {
+                    Ok(()) => {}
+                    Err(e @ FrameError::Io(_)) => {
+                        errln!("send err: {}", e);
+                        break;
+                    }
+                    Err(e) => errln!("\nframe not sent: {}", e),
+                }
+            }
+        });
+
+        let keyring_timer = link.keyring.clone();
+        let out_tx_timer = link.out_tx.clone();
+        let rekey_task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REKEY_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let mut keyring = keyring_timer.lock().unwrap();
+                if keyring.should_rekey() {
+                    if let Some(frame) = keyring.start_rekey() {
+                        let _ = out_tx_timer.send(frame);
+                    }
+                }
+            }
+        });
+
+        // Messages resent on too many sessions already are given up on.
+        let given_up = link.outbox.lock().unwrap().give_up();
+        console::print_undelivered(&name, &own_id, &given_up);
+        let resend = link.outbox.lock().unwrap().resend();
+        for p in resend {
+            let wm = seal_chat(&mut link.keyring.lock().unwrap(), &p.id, &p.sender_id, &p.text)?;
+            link.out_tx.send(wm)?;
+            outln!("{} {}", "↻ Resending:".yellow(), preview(&p.text));
+        }
+        transfer::resume(&link);
+
+        let outbox_timer = link.outbox.clone();
+        let name_timer = name.clone();
+        let own_id_timer = own_id.clone();
+        let receipt_task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECEIPT_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let expired = outbox_timer.lock().unwrap().expire();
+                console::print_undelivered(&name_timer, &own_id_timer, &expired);
+            }
+        });
+
+        let heartbeat_timer = link.heartbeat.clone();
+        let out_tx_heartbeat = link.out_tx.clone();
+        let name_heartbeat = name.clone();
+        let heartbeat_task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let mut heartbeat = heartbeat_timer.lock().unwrap();
+                let seq = heartbeat.ping();
+                if heartbeat.is_dead() {
+                    outln!("\n{}", format!("{} is not responding; closing the session.", name_heartbeat).red());
+                    break;
+                }
+                let _ = out_tx_heartbeat.send(WireMessage::Ping { seq });
+            }
+        });
+
+        // Our status is sent once the link is up, and again whenever it changes.
+        let tx_status = link.clone();
+        let own_id_status = own_id.clone();
+        let status_task = tokio::spawn(async move {
+            let mut own_status = status::subscribe();
+            loop {
+                let update = StatusUpdate { identity: own_id_status.clone(), status: own_status.borrow_and_update().clone() };
+                if let Err(e) = tx_status.send_status(&update) {
+                    errln!("\nstatus not sent: {}", e);
+                }
+                if own_status.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let rx = link.clone();
+        let reader_task = tokio::spawn(async move {
+            loop {
+                let wm = match reader.read().await {
+                    Ok(Some(wm)) => wm,
+                    Ok(None) => break,
+                    Err(e) if e.is_fatal() => {
+                        errln!("\nrecv err: {}", e);
+                        break;
+                    }
+                    Err(e) => {
+                        errln!("\ndropped malformed frame: {}", e);
+                        continue;
+                    }
+                };
+                match wm {
+                    WireMessage::Chat { ref id, ref sender_id, .. } => {
+                        // A frame that fails to decrypt is dropped; it must not take
+                        // the whole session down with it.
+                        let mut keyring = rx.keyring.lock().unwrap();
+                        match open_frame(&mut keyring, &wm) {
+                            Ok(pt) => {
+                                // Retransmissions are acknowledged again, since our
+                                // earlier Ack may have been lost, but not shown twice.
+                                if !id.is_empty() {
+                                    match seal(&mut keyring, SealedKind::Ack, id.as_bytes()) {
+                                        Ok(ack) => { let _ = rx.out_tx.send(ack); }
+                                        Err(e) => errln!("\nack not sent: {}", e),
+                                    }
+                                }
+                                drop(keyring);
+                                if id.is_empty() || rx.outbox.lock().unwrap().mark_seen(id) {
+                                    let text = String::from_utf8_lossy(&pt).to_string();
+                                    let sender_id = if rx.is_member(sender_id) { sender_id.clone() } else { rx.peer.identity.clone() };
+                                    let sender = rx.sender_name(&sender_id);
+                                    let _ = events.send(LinkEvent::Chat { link: rx.id, id: id.clone(), sender_id, sender, text });
+                                }
+                                continue;
+                            }
+                            Err(e) => errln!("\ndropped bad frame: {}", e),
+                        }
+                    }
+                    WireMessage::Sealed { kind: SealedKind::Ack, .. } => {
+                        let opened = open_frame(&mut rx.keyring.lock().unwrap(), &wm);
+                        match opened {
+                            Ok(id) => {
+                                let delivered = rx.outbox.lock().unwrap().ack(&String::from_utf8_lossy(&id));
+                                match delivered {
+                                    Some(Pending { sender_id, text, .. }) if sender_id == own_id => {
+                                        outln!("\n{} {}", format!("✓ Delivered to {}:", name).green(), preview(&text));
+                                    }
+                                    _ => continue,
+                                }
+                            }
+                            Err(e) => errln!("\ndropped bad frame: {}", e),
+                        }
+                    }
+                    WireMessage::Sealed { kind: SealedKind::Room, .. } => {
+                        let opened = open_frame(&mut rx.keyring.lock().unwrap(), &wm);
+                        match opened.and_then(|pt| Ok(decode::<RoomEvent>(&pt)?)) {
+                            Ok(event) => {
+                                rx.update_members(&event);
+                                let _ = events.send(LinkEvent::Room { link: rx.id, event });
+                                continue;
+                            }
+                            Err(e) => errln!("\ndropped bad frame: {}", e),
+                        }
+                    }
+                    WireMessage::Sealed {
+                        kind: kind @ (SealedKind::FileOffer | SealedKind::FileAccept | SealedKind::FileChunk | SealedKind::FileComplete),
+                        ..
+                    } => {
+                        let opened = open_frame(&mut rx.keyring.lock().unwrap(), &wm);
+                        match opened.and_then(|pt| parse_file_message(kind, &pt)) {
+                            Ok(message) => {
+                                transfer::handle_message(&rx, message, &events).await;
+                                continue;
+                            }
+                            Err(e) => errln!("\ndropped bad frame: {}", e),
+                        }
+                    }
+                    WireMessage::Sealed { kind: SealedKind::Status, .. } => {
+                        let opened = open_frame(&mut rx.keyring.lock().unwrap(), &wm);
+                        match opened.and_then(|pt| Ok(decode::<StatusUpdate>(&pt)?)) {
+                            // Only the peer's own status is taken, or, from a
+                            // verified room host, that of a member of the room.
+                            Ok(update) if update.identity != rx.peer.identity && !(rx.verified() && rx.is_member(&update.identity)) => continue,
+                            Ok(update) => {
+                                let previous = rx.statuses.lock().unwrap().insert(update.identity.clone(), update.status.clone());
+                                if previous.as_ref() != Some(&update.status) {
+                                    let _ = events.send(LinkEvent::Status { link: rx.id, update, previous });
+                                }
+                                continue;
+                            }
+                            Err(e) => errln!("\ndropped bad frame: {}", e),
+                        }
+                    }
+                    WireMessage::Rekey { epoch, pubkey, signature } => {
+                        // The reply is queued while the keyring is locked, so it is sent
+                        // before any frame encrypted under the new epoch.
+                        let mut keyring = rx.keyring.lock().unwrap();
+                        match keyring.handle_rekey(epoch, &pubkey, &signature) {
+                            Ok(reply) => {
+                                if let Some(reply) = reply {
+                                    let _ = rx.out_tx.send(reply);
+                                }
+                                outln!("\n🔄 Session with {} rekeyed (epoch {}, session {})", name, keyring.epoch(), keyring.session().short_id());
+                            }
+                            Err(e) => errln!("\nrekey rejected: {}", e),
+                        }
+                    }
+                    WireMessage::Ping { seq } => {
+                        let _ = rx.out_tx.send(WireMessage::Pong { seq });
+                        continue;
+                    }
+                    WireMessage::Pong { seq } => {
+                        rx.heartbeat.lock().unwrap().pong(seq);
+                        continue;
+                    }
+                    _ => continue,
+                }
+                console::reprompt();
+            }
+        });
+
+        let tasks = LinkTasks {
+            writer: writer_task,
+            reader: reader_task,
+            heartbeat: heartbeat_task,
+            rekey: rekey_task,
+            receipts: receipt_task,
+            status: status_task,
+        };
+        Ok((link, tasks))
+    }
+
+    /// Returns the name to show for the peer.
+    pub fn name(&self) -> String {
+        match &self.peer.name {
+            Some(name) => name.clone(),
+            None => display_name(&self.peer.identity),
+        }
+    }
+
+    /// Returns the name to show for the author of a message received on this link,
+    /// whose identity key `sender_id` has been checked with `is_member`. An unverified
+    /// host could list anyone as a member, so the messages it relays are shown as
+    /// "name via host" rather than under a saved peer's alias alone.
+    fn sender_name(&self, sender_id: &str) -> String {
+        if sender_id == self.peer.identity {
+            self.name()
+        } else if self.verified() {
+            display_name(sender_id)
+        } else {
+            format!("{} via {}", display_name(sender_id), self.name())
+        }
+    }
+
+    /// Returns true if the peer hosts the room we are in and reported `identity` as
+    /// one of its members, other than the host itself. A room host relays other
+    /// members' messages and statuses under their identity keys; anyone else can
+    /// only speak for itself.
+    fn is_member(&self, identity: &str) -> bool {
+        self.hosts_room && identity != self.peer.identity && self.members.lock().unwrap().iter().any(|m| m == identity)
+    }
+
+    /// Keeps track of the members of the room the peer hosts.
+    fn update_members(&self, event: &RoomEvent) {
+        if !self.hosts_room {
+            return;
+        }
+        let mut members = self.members.lock().unwrap();
+        match event {
+            RoomEvent::Welcome { members: everyone, .. } => *members = everyone.clone(),
+            RoomEvent::Joined { identity } => members.push(identity.clone()),
+            RoomEvent::Left { identity } => members.retain(|m| m != identity),
+        }
+    }
+
+    /// Returns true once the peer's identity key has been verified.
+    pub fn verified(&self) -> bool {
+        self.verified.load(Ordering::SeqCst)
+    }
+
+    /// Records that the peer's identity key has been verified.
+    pub fn set_verified(&self) {
+        self.verified.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the latest status reported on the link for the identity key
+    /// `identity`, if any.
+    pub fn status_of(&self, identity: &str) -> Option<PeerStatus> {
+        self.statuses.lock().unwrap().get(identity).cloned()
+    }
+
+    /// Returns the file transfers with the peer.
+    pub fn transfers(&self) -> Arc<Mutex<Transfers>> {
+        self.transfers.clone()
+    }
+
+    /// Sends a chat message written by the member with the identity key `sender_id`.
+    /// `id` is the message's ID when relaying one, or `None` for a new message. The
+    /// message is added to the outbox before it is sent, so an Ack can never arrive
+    /// for a message we are not tracking yet.
+    pub fn send_chat(&self, id: Option<&str>, sender_id: &str, text: &str) -> anyhow::Result<()> {
+        let id = match id {
+            Some(id) if !id.is_empty() => id.to_string(),
+            _ => new_message_id(),
+        };
+        let dropped = self.outbox.lock().unwrap().push(id.clone(), sender_id.to_string(), text.to_string());
+        console::print_undelivered(&self.name(), &self.own_id, dropped.as_slice());
+        let mut keyring = self.keyring.lock().unwrap();
+        let wm = seal_chat(&mut keyring, &id, sender_id, text)?;
+        self.out_tx.send(wm)?;
+        if keyring.should_rekey() {
+            if let Some(frame) = keyring.start_rekey() {
+                self.out_tx.send(frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a room event.
+    pub fn send_room_event(&self, event: &RoomEvent) -> anyhow::Result<()> {
+        let wm = seal(&mut self.keyring.lock().unwrap(), SealedKind::Room, &encode(event, self.codec)?)?;
+        self.out_tx.send(wm)?;
+        Ok(())
+    }
+
+    /// Sends the status of a member.
+    pub fn send_status(&self, update: &StatusUpdate) -> anyhow::Result<()> {
+        let wm = seal(&mut self.keyring.lock().unwrap(), SealedKind::Status, &encode(update, self.codec)?)?;
+        self.out_tx.send(wm)?;
+        Ok(())
+    }
+
+    /// Sends a file transfer message. File chunks count towards the rekey policy like
+    /// chat messages.
+    pub fn send_file_message(&self, message: &FileMessage) -> anyhow::Result<()> {
+        let mut keyring = self.keyring.lock().unwrap();
+        let plaintext = match message {
+            FileMessage::Offer(offer) => encode(offer, self.codec)?,
+            FileMessage::Accept(accept) => encode(accept, self.codec)?,
+            FileMessage::Chunk(chunk) => encode(chunk, self.codec)?,
+            FileMessage::Complete(complete) => encode(complete, self.codec)?,
+        };
+        let wm = seal(&mut keyring, message.kind(), &plaintext)?;
+        self.out_tx.send(wm)?;
+        if keyring.should_rekey() {
+            if let Some(frame) = keyring.start_rekey() {
+                self.out_tx.send(frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a piece of a file. It waits while `CHUNKS_IN_FLIGHT` chunks are still
+    /// waiting to be written, so a file is not read faster than it can be sent.
+    pub async fn send_file_chunk(&self, chunk: FileChunk) -> anyhow::Result<()> {
+        self.chunk_window.acquire().await?.forget();
+        self.send_file_message(&FileMessage::Chunk(chunk))
+    }
+
+    /// Starts a rekey. Returns false if one is already in progress.
+    pub fn rekey(&self) -> bool {
+        match self.keyring.lock().unwrap().start_rekey() {
+            Some(frame) => self.out_tx.send(frame).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Describes the latest round-trip time to the peer, for the `/ping` command.
+    pub fn ping_status(&self) -> String {
+        let heartbeat = self.heartbeat.lock().unwrap();
+        let mut status = match heartbeat.rtt() {
+            Some(rtt) => format!("{} ms", rtt.as_millis()),
+            None => "no round-trip time measured yet".to_string(),
+        };
+        if heartbeat.missed() > 0 {
+            status.push_str(&format!(" ({} ping(s) in a row unanswered)", heartbeat.missed()));
+        }
+        status
+    }
+
+    /// Returns the number of messages the peer has not acknowledged.
+    pub fn pending_count(&self) -> usize {
+        self.outbox.lock().unwrap().pending_count()
+    }
+}
+
+impl LinkTasks {
+    /// Waits until the peer disconnects or stops answering pings. Cancel-safe, so it
+    /// can be used in `tokio::select!`.
+    pub async fn closed(&mut self) {
+        tokio::select! {
+            _ = &mut self.reader => {}
+            _ = &mut self.heartbeat => {}
+        }
+    }
+
+    /// Stops the link. Frames already queued are written before the connection is
+    /// closed; `link` must be the last handle to it apart from those held by file
+    /// transfers, which are stopped.
+    pub async fn shutdown(self, link: Link) {
+        self.rekey.abort();
+        self.receipts.abort();
+        self.status.abort();
+        self.heartbeat.abort();
+        self.reader.abort();
+        link.transfers.lock().unwrap().stop_sending();
+        drop(link);
+        let _ = self.writer.await;
+    }
+}
+
+/// Encrypts a chat message into a `Chat` frame. `id` is empty for peers without
+/// delivery receipts.
+fn seal_chat(keyring: &mut Keyring, id: &str, sender_id: &str, text: &str) -> anyhow::Result<WireMessage> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let aad = chat_associated_data(id, sender_id, timestamp);
+    let (epoch, ct, nonce, counter, header) = keyring.encrypt(text.as_bytes(), &aad)?;
+    Ok(WireMessage::Chat {
+        id: id.to_string(),
+        epoch,
+        sender_id: sender_id.to_string(),
+        timestamp,
+        counter,
+        ratchet_key: header.dh.as_bytes().to_vec(),
+        prev_chain_len: header.pn,
+        message_index: header.n,
+        payload: ct,
+        nonce,
+    })
+}
+
+/// Encrypts a payload into a `Sealed` frame of the given kind.
+fn seal(keyring: &mut Keyring, kind: SealedKind, plaintext: &[u8]) -> anyhow::Result<WireMessage> {
+    let (epoch, payload, nonce, counter, header) = keyring.encrypt(plaintext, kind.associated_data())?;
+    let envelope = SealedEnvelope {
+        epoch,
+        counter,
+        ratchet_key: header.dh.as_bytes().to_vec(),
+        prev_chain_len: header.pn,
+        message_index: header.n,
+        payload,
+        nonce,
+    };
+    Ok(WireMessage::Sealed { kind, envelope })
+}
+
+/// Parses the decrypted payload of a file frame.
+fn parse_file_message(kind: SealedKind, plaintext: &[u8]) -> anyhow::Result<FileMessage> {
+    Ok(match kind {
+        SealedKind::FileOffer => FileMessage::Offer(decode(plaintext)?),
+        SealedKind::FileAccept => FileMessage::Accept(decode(plaintext)?),
+        SealedKind::FileChunk => FileMessage::Chunk(decode(plaintext)?),
+        SealedKind::FileComplete => FileMessage::Complete(decode(plaintext)?),
+        _ => anyhow::bail!("not a file frame"),
+    })
+}
+
+/// Decrypts the payload of an encrypted frame (`Chat` or `Sealed`) with the keyring,
+/// checking its counter, ratchet header and authenticated header fields.
+fn open_frame(keyring: &mut Keyring, wm: &WireMessage) -> anyhow::Result<Vec<u8>> {
+    match wm {
+        WireMessage::Chat { id, epoch, sender_id, timestamp, counter, ratchet_key, prev_chain_len, message_index, payload, nonce } => {
+            let header = ratchet_header(ratchet_key, *prev_chain_len, *message_index)?;
+            let aad = chat_associated_data(id, sender_id, *timestamp);
+            Ok(keyring.decrypt(*epoch, payload, nonce, *counter, &header, &aad)?)
+        }
+        WireMessage::Sealed { kind, envelope } => {
+            let header = ratchet_header(&envelope.ratchet_key, envelope.prev_chain_len, envelope.message_index)?;
+            Ok(keyring.decrypt(envelope.epoch, &envelope.payload, &envelope.nonce, envelope.counter, &header, kind.associated_data())?)
+        }
+        _ => anyhow::bail!("not an encrypted frame"),
+    }
+}
+
+/// Rebuilds the ratchet header sent with an encrypted frame.
+fn ratchet_header(ratchet_key: &[u8], pn: u32, n: u32) -> anyhow::Result<RatchetHeader> {
+    Ok(RatchetHeader { dh: pubkey_from_bytes(ratchet_key)?, pn, n })
+}
@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use async_trait::async_trait;
+use mdns_sd::{IfKind, Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+use peer_common::identity::verify_announcement;
+use peer_common::{key_fingerprint, Identity};
+use crate::addr::{is_link_local, link_local_scope, scoped};
+use crate::discovery::{now_millis, Announcement, Discovery, DiscoveryConfig, FoundPeer, Presence, Sighting};
+
+/// The DNS-SD service type peers advertise.
+const SERVICE_TYPE: &str = "_p2pchat._tcp.local.";
+
+/// The TXT record holding the signature over all the others.
+const SIGNATURE_KEY: &str = "signature";
+
+/// Starts the bytes a service's signature covers, so they cannot be mistaken for a
+/// broadcast announcement.
+const TXT_CONTEXT: &[u8] = b"p2p-chat mdns txt v1";
+
+/// The longest a DNS label may be, in bytes, which bounds the instance name.
+const MAX_LABEL_LEN: usize = 63;
+
+/// The longest a TXT string may be, in bytes, which bounds each `key=value`.
+const MAX_TXT_LEN: usize = 255;
+
+/// Discovery by multicast DNS. A listening peer registers a `_p2pchat._tcp.local`
+/// service with its announcement in TXT records, signed as they are sent (see
+/// `signed_bytes`) and signed again every interval so that the timestamp stays
+/// fresh; searching browses for that service type. The daemon that answers queries
+/// runs on a thread of its own.
+pub(crate) struct MdnsDiscovery {
+    daemon: ServiceDaemon,
+    interface: Option<IpAddr>,
+    /// The scope of the link-local IPv6 addresses peers resolve to.
+    scope: u32,
+    /// The events of the browse, started on the first search.
+    browser: tokio::sync::OnceCell<Receiver<ServiceEvent>>,
+    /// The identity key and port of each resolved service instance, by its full name,
+    /// to tell which peer a removed instance was.
+    instances: Mutex<HashMap<String, (String, u16)>>,
+}
+
+/// Unregisters a service when dropped, which tells the network it is gone.
+struct Registration {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+    }
+}
+
+impl MdnsDiscovery {
+    /// Starts the multicast DNS daemon, on the interface set in `config` or on all of
+    /// them.
+    pub fn new(config: &DiscoveryConfig) -> anyhow::Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        if let Some(ip) = config.interface {
+            daemon.disable_interface(IfKind::All)?;
+            daemon.enable_interface(IfKind::Addr(ip))?;
+        }
+        Ok(MdnsDiscovery {
+            daemon,
+            interface: config.interface,
+            scope: link_local_scope(config.interface),
+            browser: tokio::sync::OnceCell::new(),
+            instances: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Builds the service that advertises `announcement`, signed with `identity`. A
+    /// nickname too long for a TXT record is shortened, and a status too long for
+    /// one is left out.
+    fn service_info(&self, announcement: &Announcement, identity: &Identity) -> anyhow::Result<ServiceInfo> {
+        let fingerprint = key_fingerprint(&announcement.identity)?;
+        let id: String = fingerprint.chars().filter(|c| !c.is_whitespace()).take(8).collect();
+        let instance = instance_name(announcement.nickname.as_deref(), &id);
+        let host = format!("p2p-chat-{}.local.", id);
+        let mut properties = BTreeMap::new();
+        properties.insert("identity".to_string(), announcement.identity.clone());
+        properties.insert("fingerprint".to_string(), fingerprint);
+        properties.insert("version".to_string(), announcement.version.to_string());
+        properties.insert("ciphers".to_string(), serde_json::to_string(&announcement.ciphers)?);
+        properties.insert("port".to_string(), announcement.port.to_string());
+        properties.insert("timestamp".to_string(), announcement.timestamp.to_string());
+        properties.insert("interval".to_string(), announcement.interval_secs.to_string());
+        if let Some(nickname) = &announcement.nickname {
+            let nickname = truncate(nickname, MAX_TXT_LEN - "nickname=".len());
+            properties.insert("nickname".to_string(), nickname.to_string());
+        }
+        if let Some(status) = &announcement.status {
+            let status = serde_json::to_string(status)?;
+            if "status=".len() + status.len() <= MAX_TXT_LEN {
+                properties.insert("status".to_string(), status);
+            }
+        }
+        if let Some(key) = properties.keys().find(|key| key.len() + 1 + properties[*key].len() > MAX_TXT_LEN) {
+            anyhow::bail!("the TXT record '{}' is longer than {} bytes", key, MAX_TXT_LEN);
+        }
+        let signed: Vec<(String, &[u8])> = properties.iter().map(|(k, v)| (k.clone(), v.as_bytes())).collect();
+        let signature = identity.sign_announcement(&signed_bytes(&signed));
+        let mut properties: HashMap<String, String> = properties.into_iter().collect();
+        properties.insert(SIGNATURE_KEY.to_string(), signature);
+
+        let port = announcement.port;
+        Ok(match self.interface {
+            Some(ip) => ServiceInfo::new(SERVICE_TYPE, &instance, &host, ip, port, properties)?,
+            None => ServiceInfo::new(SERVICE_TYPE, &instance, &host, (), port, properties)?.enable_addr_auto(),
+        })
+    }
+}
+
+#[async_trait]
+impl Discovery for MdnsDiscovery {
+    /// Registers the service until the future is dropped. The instance is named after
+    /// the nickname and the start of the fingerprint, which keeps it unique on the
+    /// network. Only addresses of the families the peer accepts are advertised. The
+    /// service is registered again with newly signed records every interval, so that
+    /// searches can tell them from old ones, and at once when the status changes.
+    async fn announce(&self, presence: &Presence) -> anyhow::Result<()> {
+        if !presence.ipv4 {
+            self.daemon.disable_interface(IfKind::IPv4)?;
+        }
+        if !presence.ipv6 {
+            self.daemon.disable_interface(IfKind::IPv6)?;
+        }
+        let mut status = presence.status.clone();
+        let mut registration = None;
+        loop {
+            // The addresses come from the address records, so they are left out of
+            // the signed announcement.
+            let announcement = Announcement {
+                addresses: Vec::new(),
+                status: Some(status.borrow_and_update().clone()),
+                timestamp: now_millis(),
+                ..presence.announcement.clone()
+            };
+            let info = self.service_info(&announcement, &presence.identity)?;
+            let fullname = info.get_fullname().to_string();
+            self.daemon.register(info)?;
+            // Registering the same instance again updates it, so it is unregistered
+            // only once, when the future is dropped.
+            registration.get_or_insert_with(|| Registration { daemon: self.daemon.clone(), fullname });
+            tokio::select! {
+                _ = tokio::time::sleep(announcement.interval()) => {}
+                Ok(()) = status.changed() => {}
+            }
+        }
+    }
+
+    /// Returns the next service instance that resolved to an address and carries a
+    /// valid signature, or that was removed. IPv4 addresses are preferred. Link-local
+    /// IPv6 addresses get the scope of the configured interface, and are skipped if
+    /// it is not known.
+    async fn next_sighting(&self) -> anyhow::Result<Sighting> {
+        let browser = self
+            .browser
+            .get_or_try_init(|| async { self.daemon.browse(SERVICE_TYPE) })
+            .await?;
+
+        loop {
+            let info = match browser.recv_async().await? {
+                ServiceEvent::ServiceResolved(info) => info,
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    let Some((identity, port)) = self.instances.lock().unwrap().remove(&fullname) else { continue };
+                    return Ok(Sighting::Gone { identity, port });
+                }
+                _ => continue,
+            };
+            let Some(announcement) = verified_announcement(&info) else { continue };
+            let mut addresses: Vec<IpAddr> = info
+                .get_addresses()
+                .iter()
+                .copied()
+                .filter(|ip| self.scope != 0 || !matches!(ip, IpAddr::V6(v6) if is_link_local(*v6)))
+                .collect();
+            addresses.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+            let Some(ip) = addresses.first() else { continue };
+            self.instances
+                .lock()
+                .unwrap()
+                .insert(info.get_fullname().to_string(), (announcement.identity.clone(), announcement.port));
+            return Ok(Sighting::Seen(FoundPeer::new(scoped(*ip, info.get_port(), self.scope), announcement)?));
+        }
+    }
+
+    /// Removed services are reported when they say goodbye or their records expire.
+    fn expiry(&self, _announcement: &Announcement) -> Option<Duration> {
+        None
+    }
+}
+
+/// Returns the announcement in the TXT records of a service if the signature covers
+/// them all and is valid, and the records were signed recently (see
+/// `Announcement::is_fresh`). Nothing is parsed before the signature is checked, and
+/// the signed port must be the one the service record points to.
+fn verified_announcement(info: &ServiceInfo) -> Option<Announcement> {
+    let mut signed: Vec<(String, &[u8])> = info
+        .get_properties()
+        .iter()
+        .filter(|p| !p.key().eq_ignore_ascii_case(SIGNATURE_KEY))
+        .map(|p| (p.key().to_ascii_lowercase(), p.val().unwrap_or_default()))
+        .collect();
+    signed.sort();
+    if signed.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return None;
+    }
+    let identity = info.get_property_val_str("identity")?;
+    verify_announcement(identity, &signed_bytes(&signed), info.get_property_val_str(SIGNATURE_KEY)?).ok()?;
+
+    let port = info.get_property_val_str("port")?.parse().ok()?;
+    if port != info.get_port() {
+        return None;
+    }
+    let announcement = Announcement {
+        nickname: info.get_property_val_str("nickname").map(str::to_string),
+        identity: identity.to_string(),
+        version: info.get_property_val_str("version")?.parse().ok()?,
+        ciphers: serde_json::from_str(info.get_property_val_str("ciphers")?).ok()?,
+        port,
+        addresses: Vec::new(),
+        status: match info.get_property_val_str("status") {
+            Some(status) => Some(serde_json::from_str(status).ok()?),
+            None => None,
+        },
+        timestamp: info.get_property_val_str("timestamp")?.parse().ok()?,
+        interval_secs: info.get_property_val_str("interval")?.parse().ok()?,
+    };
+    announcement.is_fresh().then_some(announcement)
+}
+
+/// Returns the name of the service instance for a peer with `nickname` and the
+/// fingerprint prefix `id`. Dots, backslashes and control characters are left out of
+/// the nickname, and it is shortened so that the name fits in one DNS label.
+fn instance_name(nickname: Option<&str>, id: &str) -> String {
+    let nickname: String = nickname
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '.' && *c != '\\')
+        .collect();
+    let nickname = truncate(nickname.trim(), MAX_LABEL_LEN - 1 - id.len()).trim_end();
+    format!("{}-{}", if nickname.is_empty() { "peer" } else { nickname }, id)
+}
+
+/// Returns the longest start of `text` that is at most `max_len` bytes long and ends
+/// on a character boundary.
+fn truncate(text: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Returns the bytes the signature of a service covers: `TXT_CONTEXT`, then each TXT
+/// property other than the signature, given sorted by lowercase key, as its key and
+/// value, each preceded by its length.
+fn signed_bytes(properties: &[(String, &[u8])]) -> Vec<u8> {
+    let mut out = TXT_CONTEXT.to_vec();
+    for (key, value) in properties {
+        out.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        out.extend_from_slice(key.as_bytes());
+        out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        out.extend_from_slice(value);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peer_common::types::PeerStatus;
+    use peer_common::CipherType;
+
+    fn announcement(identity: &Identity) -> Announcement {
+        Announcement {
+            nickname: Some("alice".to_string()),
+            identity: identity.public_b64(),
+            version: 8,
+            ciphers: CipherType::SUPPORTED.to_vec(),
+            port: 12345,
+            addresses: Vec::new(),
+            status: Some(PeerStatus::default()),
+            timestamp: now_millis(),
+            interval_secs: 5,
+        }
+    }
+
+    fn service(announcement: &Announcement, identity: &Identity) -> anyhow::Result<ServiceInfo> {
+        let interface = Some(IpAddr::from([127, 0, 0, 1]));
+        MdnsDiscovery::new(&DiscoveryConfig { interface, ..DiscoveryConfig::default() })?.service_info(announcement, identity)
+    }
+
+    #[test]
+    fn signed_records_round_trip() {
+        let identity = Identity::generate();
+        let sent = announcement(&identity);
+        let received = verified_announcement(&service(&sent, &identity).unwrap()).unwrap();
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn stale_and_future_records_are_rejected() {
+        let identity = Identity::generate();
+        let stale = Announcement { timestamp: now_millis() - 3_600_000, ..announcement(&identity) };
+        assert!(verified_announcement(&service(&stale, &identity).unwrap()).is_none());
+        let future = Announcement { timestamp: now_millis() + 3_600_000, ..announcement(&identity) };
+        assert!(verified_announcement(&service(&future, &identity).unwrap()).is_none());
+    }
+
+    #[test]
+    fn long_nicknames_are_shortened_and_long_statuses_left_out() {
+        let identity = Identity::generate();
+        let status = PeerStatus { text: Some("x".repeat(300)), ..PeerStatus::default() };
+        let sent = Announcement { nickname: Some("é".repeat(200)), status: Some(status), ..announcement(&identity) };
+        let info = service(&sent, &identity).unwrap();
+        for property in info.get_properties().iter() {
+            assert!(property.key().len() + 1 + property.val().unwrap_or_default().len() <= MAX_TXT_LEN);
+        }
+        assert!(info.get_fullname().split('.').next().unwrap().len() <= MAX_LABEL_LEN);
+        let received = verified_announcement(&info).unwrap();
+        assert_eq!(received.nickname.unwrap(), "é".repeat(123));
+        assert_eq!(received.status, None);
+    }
+
+    #[test]
+    fn instance_names_fit_in_one_label() {
+        assert_eq!(instance_name(Some("alice"), "a4d9b88b"), "alice-a4d9b88b");
+        assert_eq!(instance_name(None, "a4d9b88b"), "peer-a4d9b88b");
+        assert_eq!(instance_name(Some("evil._tcp.local\u{0}\n"), "a4d9b88b"), "evil_tcplocal-a4d9b88b");
+        assert_eq!(instance_name(Some(" ..\\\u{1b} "), "a4d9b88b"), "peer-a4d9b88b");
+        let long = instance_name(Some(&"ü".repeat(100)), "a4d9b88b");
+        assert!(long.len() <= MAX_LABEL_LEN);
+        assert!(long.ends_with("ü-a4d9b88b"));
+    }
+}
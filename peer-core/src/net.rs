@@ -1,215 +1,379 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use serde_json;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::IpAddr;
 use std::sync::Arc;
 use peer_common::types::WireMessage;
-use peer_common::crypto::{generate_keypair, derive_shared_key, pubkey_to_b64, pubkey_from_b64};
-use peer_common::Session;
-use base64::{engine::general_purpose, Engine as _};
-
-#[cfg(feature = "notify")]
-use notify_rust::Notification;
-
-/// Serializes a `WireMessage` to JSON, prefixes it with a 4-byte big-endian length,
-/// and writes it to a `TcpStream`. This function is used to send messages to a peer.
-async fn write_msg(stream: &mut TcpStream, wm: &WireMessage) -> anyhow::Result<()> {
-    let v = serde_json::to_vec(wm)?;
-    let len = (v.len() as u32).to_be_bytes();
-    stream.write_all(&len).await?;
-    stream.write_all(&v).await?;
-    Ok(())
-}
+use peer_common::crypto::{generate_keypair, derive_session_keys, transcript_hash, pubkey_to_b64, pubkey_from_b64};
+use peer_common::identity::verify_handshake;
+use peer_common::wire::negotiate_version;
+use peer_common::{CipherType, Codec, Identity, SafetyNumber, Session, PROTOCOL_VERSION};
+use crate::console::{self, errln, outln};
+use crate::addr::listening_addresses;
+use crate::discovery::{Announcement, DiscoveryConfig, Presence};
+use crate::framing::{FrameError, FrameReader, FrameWriter, FramingConfig};
+use crate::link::{Connection, Link, PeerInfo};
+use crate::persistence::{History, Persist, TrustStatus};
+use crate::sessions::SessionManager;
+use crate::status;
 
-/// Reads a length-prefixed JSON message from a `TcpStream` and deserializes it into
-/// a `WireMessage`. This function is used to receive messages from a peer.
-async fn read_msg(stream: &mut TcpStream) -> anyhow::Result<WireMessage> {
-    let mut len_buf = [0u8;4];
-    stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf) as usize;
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf).await?;
-    let wm: WireMessage = serde_json::from_slice(&buf)?;
-    Ok(wm)
-}
+/// Set to `json` to only offer the JSON codec, which makes frames readable in a
+/// packet capture.
+const WIRE_ENV: &str = "P2P_CHAT_WIRE";
 
-/// Starts a TCP listener on the given address. For each incoming connection, it
-/// spawns a new task to handle the connection. It also starts a background task to
-/// broadcast the peer's presence on the network.
-pub async fn start_listener(bind_addr: &str) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(bind_addr).await?;
-    let port = listener.local_addr()?.port();
-    println!("Listening on {}", bind_addr);
-
-    tokio::spawn(async move {
-        if let Err(e) = crate::discovery::broadcast_presence(port).await {
-            eprintln!("broadcast error: {:?}", e);
-        }
-    });
+/// Starts a TCP listener on the given address that hosts the room `room_name`. For
+/// each incoming connection, it spawns a new task that handles the handshake and adds
+/// the peer to the room. Listening on `[::]` accepts both IPv6 and IPv4 connections.
+/// It also starts a background task to announce the peer's presence on the network
+/// with the backend set in `discovery`, unless it is disabled. The host can connect to
+/// other peers from the console, too. Messages are saved to `history`, if given.
+/// Returns once the host types `/quit`.
+pub async fn start_listener(
+    bind_addr: &str,
+    identity: Arc<Identity>,
+    framing: FramingConfig,
+    room_name: &str,
+    history: Option<History>,
+    discovery: &DiscoveryConfig,
+) -> anyhow::Result<()> {
+    let listener = bind_listener(bind_addr).await?;
+    let local_addr = listener.local_addr()?;
+    outln!("Listening on {}", bind_addr);
+    let (manager, events) = SessionManager::new(identity.clone(), framing, history);
+    let room = manager.host(room_name);
+    let outboxes = manager.outboxes();
+    let transfers = manager.transfers();
+    let console = manager.run(events);
+    tokio::pin!(console);
+
+    if discovery.enabled {
+        let announcement = Announcement {
+            nickname: crate::console::nickname(),
+            identity: identity.public_b64(),
+            version: PROTOCOL_VERSION,
+            ciphers: CipherType::SUPPORTED.to_vec(),
+            port: local_addr.port(),
+            addresses: listening_addresses(local_addr.ip()),
+            status: Some(status::current()),
+            timestamp: 0,
+            interval_secs: discovery.interval().as_secs(),
+        };
+        let presence = Presence {
+            announcement,
+            identity: identity.clone(),
+            ipv4: local_addr.is_ipv4() || local_addr.ip().is_unspecified(),
+            ipv6: local_addr.is_ipv6(),
+            status: status::subscribe(),
+        };
+        let discovery = discovery.start()?;
+        tokio::spawn(async move {
+            if let Err(e) = discovery.announce(&presence).await {
+                errln!("discovery error: {:?}", e);
+            }
+        });
+    }
 
     loop {
-        let (socket, peer_addr) = listener.accept().await?;
-        println!("Accepted connection from {}", peer_addr);
+        let (socket, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            result = &mut console => return result,
+        };
+        // IPv4 peers of a dual-stack listener have IPv4-mapped IPv6 addresses
+        let peer_addr = match peer_addr.ip().to_canonical() {
+            ip @ std::net::IpAddr::V4(_) => std::net::SocketAddr::new(ip, peer_addr.port()),
+            _ => peer_addr,
+        };
+        outln!("\nAccepted connection from {}", peer_addr);
+        let identity = identity.clone();
+        let outboxes = outboxes.clone();
+        let transfers = transfers.clone();
+        let room = room.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_conn(socket, true).await {
-                eprintln!("connection error: {:?}", e);
+            let result = async {
+                let conn = handshake(socket, true, &identity, None, None, framing).await?;
+                let outbox = outboxes.for_peer(&conn.peer.identity);
+                let transfers = transfers.for_peer(&conn.peer.identity);
+                room.join(conn, outbox, transfers).await
+            };
+            if let Err(e) = result.await {
+                errln!("connection error: {:?}", e);
             }
         });
     }
 }
 
-/// Connects to a peer at the given address and then calls `handle_conn` to handle
-/// the connection.
-pub async fn start_client(target: &str) -> anyhow::Result<()> {
-    let stream = TcpStream::connect(target).await?;
-    println!("Connected to {}", target);
-    handle_conn(stream, false).await?;
-    Ok(())
+/// Connects to a peer at the given address and opens a conversation with it in the
+/// console. If `alias` is given, the peer's identity key is checked against (or
+/// pinned to) that saved peer; otherwise a saved peer with the same address is used,
+/// if any. More conversations can be opened from the console.
+///
+/// If the connection is lost, the client reconnects with exponential backoff until
+/// the conversation is closed. Messages typed in the meantime are queued and sent,
+/// along with any unacknowledged ones, once the session is re-established. The peer
+/// must present the same identity key as before.
+///
+/// Messages are saved to `history`, if given, and the latest ones exchanged with a
+/// peer are shown when a conversation with it opens.
+pub async fn start_client(
+    target: &str,
+    identity: Arc<Identity>,
+    alias: Option<&str>,
+    framing: FramingConfig,
+    history: Option<History>,
+) -> anyhow::Result<()> {
+    let (manager, events) = SessionManager::new(identity, framing, history);
+    manager.connect(target, alias.map(|a| a.to_string())).await?;
+    manager.run(events).await
 }
 
-/// Handles the cryptographic handshake to establish a secure session, and then
-/// enters the `chat_loop`. This function is called for both the listener and the
-/// client.
-async fn handle_conn(mut stream: TcpStream, is_listener: bool) -> anyhow::Result<()> {
-    let (my_secret, my_pub) = generate_keypair();
-    let my_pub_b64 = pubkey_to_b64(&my_pub);
+/// Binds a TCP listener to `bind_addr`. A listener on the unspecified IPv6 address
+/// accepts IPv4 connections, too, whatever the system default is.
+async fn bind_listener(bind_addr: &str) -> anyhow::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
 
-    if is_listener {
-        let incoming = read_msg(&mut stream).await?;
-        match incoming {
-            WireMessage::Handshake { pubkey } => {
-                let peer_pub = pubkey_from_b64(&pubkey)?;
-                let hm = WireMessage::Handshake { pubkey: my_pub_b64.clone() };
-                write_msg(&mut stream, &hm).await?;
-                let shared_key = derive_shared_key(my_secret, &peer_pub);
-                let session = Session::new(shared_key);
-                println!("🔐 Session key derived (listener)");
-                chat_loop(stream, session, false).await?;
+    let addr = tokio::net::lookup_host(bind_addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no address to listen on for {}", bind_addr))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Returns true if a connection error is worth retrying: network and framing
+/// errors are, while a failed identity check or handshake is not.
+pub(crate) fn is_transient(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<FrameError>().is_some() || e.downcast_ref::<std::io::Error>().is_some()
+}
+
+/// Compares the identity key presented by a peer with the key pinned in the
+/// persisted data. A saved peer without a pinned key gets the presented key pinned
+/// (trust on first use); a saved peer presenting a different key is rejected. When
+/// reconnecting, the key must also match the one from the previous session.
+/// `remote_ip` is the address a listener was connected from: a listener has no alias
+/// to check, so it warns when an unknown key connects from the address of a saved
+/// peer with a pinned key. Other peers can share that address (behind NAT, on the
+/// same host, or on a reused lease), so the connection is not refused.
+fn check_peer_identity(
+    alias: Option<&str>,
+    identity_b64: &str,
+    expected: Option<&str>,
+    remote_ip: Option<IpAddr>,
+) -> anyhow::Result<PeerInfo> {
+    use colored::Colorize;
+
+    if let Some(expected) = expected {
+        if expected != identity_b64 {
+            errln!("{}", "WARNING: PEER IDENTITY KEY CHANGED SINCE THE LAST CONNECTION!".red().bold());
+            errln!("  previous key:  {}", expected);
+            errln!("  presented key: {}", identity_b64);
+            anyhow::bail!("identity key changed on reconnect");
+        }
+    }
+
+    let persist = Persist::load()?;
+    match alias {
+        Some(name) => match persist.check_peer_key(name, identity_b64) {
+            TrustStatus::FirstUse => {
+                Persist::update(|p| {
+                    p.pin_peer_key(name, identity_b64.to_string());
+                    true
+                })?;
+                console::refresh_contacts();
+                outln!("📌 Pinned identity key for '{}' (first use)", name);
             }
-            _ => {
-                eprintln!("expected handshake");
+            TrustStatus::Trusted => {
+                outln!("✅ Identity of '{}' matches the pinned key", name);
             }
-        }
-    } else {
-        let hm = WireMessage::Handshake { pubkey: my_pub_b64.clone() };
-        write_msg(&mut stream, &hm).await?;
-        let reply = read_msg(&mut stream).await?;
-        match reply {
-            WireMessage::Handshake { pubkey } => {
-                let peer_pub = pubkey_from_b64(&pubkey)?;
-                let shared_key = derive_shared_key(my_secret, &peer_pub);
-                let session = Session::new(shared_key);
-                println!("🔐 Session key derived (client)");
-                chat_loop(stream, session, true).await?;
+            TrustStatus::Mismatch(pinned) => {
+                warn_key_mismatch(name, &pinned, identity_b64);
+                anyhow::bail!("identity key mismatch for '{}'", name);
             }
-            _ => eprintln!("expected handshake reply"),
-        }
+        },
+        None => match persist.get_peer_by_key(identity_b64) {
+            Some(peer) => outln!("✅ Peer identified as '{}'", peer.name),
+            None => {
+                outln!("❔ Unknown peer identity {}", identity_b64);
+                if let Some(peer) = remote_ip.and_then(|ip| persist.get_pinned_peer_at(ip)) {
+                    errln!(
+                        "{}",
+                        format!("⚠️  This key connected from the address of '{}', but is not the key pinned for them.", peer.name).yellow()
+                    );
+                    errln!("If it claims to be '{}', it may be someone else; compare safety numbers with /verify.", peer.name);
+                }
+            }
+        },
     }
-    Ok(())
+
+    let saved = match alias {
+        Some(name) => persist.get_peer(name),
+        None => persist.get_peer_by_key(identity_b64),
+    };
+    Ok(PeerInfo {
+        name: saved.map(|p| p.name.clone()),
+        identity: identity_b64.to_string(),
+        verified: saved.is_some_and(|p| p.verified),
+    })
 }
 
-/// Handles the interactive chat session. It splits the `TcpStream` into a reader and
-/// a writer, and then spawns a task to read incoming messages and another loop to
-/// read user input from stdin.
-async fn chat_loop(stream: TcpStream, session: Session, _pause_read: bool) -> anyhow::Result<()> {
-    use tokio::io::{AsyncBufReadExt, BufReader};
-    use std::io::{stdout, Write};
+/// Warns that the saved peer `name` presented a key other than the one pinned for it.
+fn warn_key_mismatch(name: &str, pinned: &str, presented: &str) {
     use colored::Colorize;
 
-    println!("🔒 Secure channel established. You can type messages now.");
-    let (r, mut w) = stream.into_split();
-    let mut reader = BufReader::new(r);
-    let session = Arc::new(session);
-
-    let session_rx = session.clone();
-    let reader_task = tokio::spawn(async move {
-        loop {
-            match read_msg_from_reader(&mut reader, &session_rx).await {
-                Ok(Some(text)) => {
-                    let timestamp = chrono::Local::now().format("%H:%M:%S");
-                    println!("\n{} {}: {}", timestamp.to_string().dimmed(), "Peer".yellow(), text);
-                    #[cfg(feature = "notify")]
-                    let _ = Notification::new().summary("New message").body(&text).show();
-                    print!("> ");
-                    let _ = std::io::stdout().flush();
-                }
-                Ok(None) => {
-                    println!("\nPeer disconnected.");
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("recv err: {:?}", e);
-                    break;
-                }
-            }
-        }
-    });
+    errln!("{}", "WARNING: PEER IDENTITY KEY HAS CHANGED!".red().bold());
+    errln!("Someone may be intercepting this connection (man-in-the-middle),");
+    errln!("or '{}' has reinstalled the application.", name);
+    errln!("  pinned key:    {}", pinned);
+    errln!("  presented key: {}", presented);
+    errln!("If you trust the new key, re-add the peer with `add-peer` to forget the old one.");
+}
 
-    let mut stdin_reader = BufReader::new(tokio::io::stdin());
-    let mut input = String::new();
-    loop {
-        input.clear();
-        print!("> ");
-        stdout().flush()?;
-        let n = stdin_reader.read_line(&mut input).await?;
-        if n == 0 { break; }
-        let text = input.trim_end().to_string();
-        if text.is_empty() { continue; }
-        if text == "/quit" { break; }
-
-        let (ct, nonce) = session.encrypt(text.as_bytes());
-        let b64_ct = general_purpose::STANDARD.encode(&ct);
-        let b64_nonce = general_purpose::STANDARD.encode(&nonce);
-
-        let wm = WireMessage::Chat {
-            sender_id: "me".to_string(),
-            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
-            payload: b64_ct,
-            nonce: b64_nonce,
-        };
-        write_msg_raw(&mut w, &wm).await?;
-        let timestamp = chrono::Local::now().format("%H:%M:%S");
-        println!("{} {}: {}", timestamp.to_string().dimmed(), "You".green(), text);
+/// Handles the `/verify` chat command for the peer of `link`. Without an argument, it
+/// prints the safety number for this session; `/verify confirm` marks the peer as
+/// verified.
+pub(crate) fn handle_verify_command(arg: &str, identity: &Identity, link: &Link) -> anyhow::Result<()> {
+    let peer = &link.peer;
+    let safety = SafetyNumber::new(&identity.public_b64(), &peer.identity)?;
+    if arg != "confirm" {
+        outln!("Safety number:\n{}", safety);
+        outln!("{}", safety.emoji_line());
+        outln!("Compare this with your peer out-of-band, then type `/verify confirm`.");
+        return Ok(());
     }
 
-    let _ = reader_task.await;
+    let Some(name) = &peer.name else {
+        outln!("This peer is not saved. Save it with `add-peer` to mark it as verified.");
+        return Ok(());
+    };
+    if Persist::update(|p| p.mark_verified(name))? {
+        console::refresh_contacts();
+        link.set_verified();
+        outln!("✅ '{}' marked as verified.", name);
+    } else {
+        outln!("Could not mark '{}' as verified.", name);
+    }
     Ok(())
 }
 
-/// A helper function that reads a `WireMessage` from a reader that implements
-/// `AsyncBufRead`, and decrypts chat messages.
-async fn read_msg_from_reader<R: tokio::io::AsyncBufRead + Unpin>(reader: &mut R, session: &Session) -> anyhow::Result<Option<String>> {
-    let mut lenb = [0u8;4];
-    if let Err(e) = reader.read_exact(&mut lenb).await {
-        return if e.kind() == std::io::ErrorKind::UnexpectedEof {
-            Ok(None)
-        } else {
-            Err(e.into())
-        };
-    }
-    let len = u32::from_be_bytes(lenb) as usize;
-    let mut buf = vec![0u8; len];
-    reader.read_exact(&mut buf).await?;
-    let wm: WireMessage = serde_json::from_slice(&buf)?;
-    match wm {
-        WireMessage::Chat { sender_id: _, timestamp: _, payload, nonce } => {
-            let data = general_purpose::STANDARD.decode(&payload)?;
-            let nonce_bytes = general_purpose::STANDARD.decode(&nonce)?;
-            let pt = session.decrypt(&data, &nonce_bytes);
-            let s = String::from_utf8_lossy(&pt).to_string();
-            Ok(Some(s))
-        }
-        WireMessage::Ping => Ok(None),
-        _ => Ok(None),
+/// Returns the codecs we offer in the handshake, preferred first.
+fn local_codecs() -> Vec<Codec> {
+    match std::env::var(WIRE_ENV) {
+        Ok(v) if v == "json" => vec![Codec::Json],
+        _ => Codec::SUPPORTED.to_vec(),
     }
 }
 
-/// A helper function that writes a `WireMessage` to a writer that implements
-/// `AsyncWrite`.
-async fn write_msg_raw<W: tokio::io::AsyncWrite + Unpin>(writer: &mut W, wm: &WireMessage) -> anyhow::Result<()> {
-    let v = serde_json::to_vec(wm)?;
-    let len = (v.len() as u32).to_be_bytes();
-    writer.write_all(&len).await?;
-    writer.write_all(&v).await?;
-    Ok(())
+/// Hashes the handshake transcript that the key schedule is bound to: both
+/// `Handshake` messages as a whole, client first, followed by the agreed protocol
+/// version and cipher. Since the versions, ciphers and codecs each side offered are
+/// covered, a man-in-the-middle who strips offers to force a weaker choice leaves the
+/// two sides with different keys.
+fn handshake_transcript(client_hello: &WireMessage, listener_hello: &WireMessage, version: u32, cipher: CipherType) -> anyhow::Result<[u8; 32]> {
+    let mut parts = hello_parts(client_hello)?;
+    parts.extend(hello_parts(listener_hello)?);
+    parts.push(version.to_be_bytes().to_vec());
+    parts.push(cipher.to_string().into_bytes());
+    let parts: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+    Ok(transcript_hash(&parts))
+}
+
+/// Returns every field of a `Handshake` message as a transcript part. Lists are
+/// preceded by their length, so no two messages give the same parts.
+fn hello_parts(hello: &WireMessage) -> anyhow::Result<Vec<Vec<u8>>> {
+    let WireMessage::Handshake { pubkey, identity, signature, ciphers, version, codecs } = hello else {
+        anyhow::bail!("expected handshake");
+    };
+    let mut parts = vec![pubkey.clone().into_bytes(), identity.clone().into_bytes(), signature.clone().into_bytes(), version.to_be_bytes().to_vec()];
+    parts.push((ciphers.len() as u32).to_be_bytes().to_vec());
+    parts.extend(ciphers.iter().map(|c| c.to_string().into_bytes()));
+    parts.push((codecs.len() as u32).to_be_bytes().to_vec());
+    parts.extend(codecs.iter().map(|c| c.to_string().into_bytes()));
+    Ok(parts)
+}
+
+/// Handles the cryptographic handshake to establish a secure session, returning the
+/// established connection. This function is called for both the listener and the
+/// client. Each side signs its ephemeral key with its long-term identity key, so the
+/// ephemeral keys cannot be swapped by a man-in-the-middle. Both sides advertise
+/// their protocol version, ciphers and codecs, and independently pick the lower
+/// version and the strongest common cipher and codec. The handshake is written as
+/// JSON and must complete within the handshake timeout. `expected_identity` is the
+/// peer's identity key from a previous session, when reconnecting.
+pub(crate) async fn handshake(
+    stream: TcpStream,
+    is_listener: bool,
+    identity: &Identity,
+    alias: Option<&str>,
+    expected_identity: Option<&str>,
+    framing: FramingConfig,
+) -> anyhow::Result<Connection> {
+    let remote_ip = if is_listener { Some(stream.peer_addr()?.ip()) } else { None };
+    let (r, w) = stream.into_split();
+    let mut reader = FrameReader::new(r, framing);
+    let mut writer = FrameWriter::new(w, framing);
+    let (my_secret, my_pub) = generate_keypair();
+    let my_pub_b64 = pubkey_to_b64(&my_pub);
+    let my_signature = identity.sign_handshake(my_pub.as_bytes());
+
+    if is_listener {
+        let incoming = reader.read_handshake().await?;
+        match &incoming {
+            WireMessage::Handshake { pubkey, identity: peer_identity, signature, ciphers, version, codecs } => {
+                let peer_pub = pubkey_from_b64(pubkey)?;
+                verify_handshake(peer_identity, peer_pub.as_bytes(), signature)?;
+                let peer = check_peer_identity(alias, peer_identity, expected_identity, remote_ip)?;
+                let version = negotiate_version(*version)?;
+                let cipher = CipherType::negotiate(ciphers)?;
+                let codec = Codec::negotiate(&local_codecs(), codecs);
+                let hm = WireMessage::Handshake {
+                    pubkey: my_pub_b64.clone(),
+                    identity: identity.public_b64(),
+                    signature: my_signature,
+                    ciphers: CipherType::SUPPORTED.to_vec(),
+                    version: PROTOCOL_VERSION,
+                    codecs: local_codecs(),
+                };
+                writer.write(&hm).await?;
+                writer.set_codec(codec);
+                let transcript = handshake_transcript(&incoming, &hm, version, cipher)?;
+                let keys = derive_session_keys(&my_secret, &peer_pub, &transcript, false);
+                let session = Session::new(keys, my_secret, peer_pub, cipher, version, false);
+                outln!("🔐 Session keys derived (listener, session {})", session.short_id());
+                Ok(Connection { reader, writer, session, peer, is_client: false })
+            }
+            _ => anyhow::bail!("expected handshake"),
+        }
+    } else {
+        let hm = WireMessage::Handshake {
+            pubkey: my_pub_b64.clone(),
+            identity: identity.public_b64(),
+            signature: my_signature,
+            ciphers: CipherType::SUPPORTED.to_vec(),
+            version: PROTOCOL_VERSION,
+            codecs: local_codecs(),
+        };
+        writer.write(&hm).await?;
+        let reply = reader.read_handshake().await?;
+        match &reply {
+            WireMessage::Handshake { pubkey, identity: peer_identity, signature, ciphers, version, codecs } => {
+                let peer_pub = pubkey_from_b64(pubkey)?;
+                verify_handshake(peer_identity, peer_pub.as_bytes(), signature)?;
+                let peer = check_peer_identity(alias, peer_identity, expected_identity, remote_ip)?;
+                let version = negotiate_version(*version)?;
+                let cipher = CipherType::negotiate(ciphers)?;
+                writer.set_codec(Codec::negotiate(&local_codecs(), codecs));
+                let transcript = handshake_transcript(&hm, &reply, version, cipher)?;
+                let keys = derive_session_keys(&my_secret, &peer_pub, &transcript, true);
+                let session = Session::new(keys, my_secret, peer_pub, cipher, version, true);
+                outln!("🔐 Session keys derived (client, session {})", session.short_id());
+                Ok(Connection { reader, writer, session, peer, is_client: true })
+            }
+            _ => anyhow::bail!("expected handshake reply"),
+        }
+    }
 }
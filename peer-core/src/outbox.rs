@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a sent message may go without an `Ack` before it is reported as failed.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many unacknowledged messages are kept for one peer. Sending or queuing more
+/// drops the oldest.
+pub const MAX_PENDING: usize = 500;
+
+/// How many new sessions a message is resent on before it is given up on.
+pub const MAX_RESENDS: u32 = 3;
+
+/// How many received message IDs are remembered to recognise retransmissions.
+const SEEN_CAPACITY: usize = 1000;
+
+/// A chat message that has been sent but not yet acknowledged by the peer. `sender_id`
+/// is the identity key of its author, which is not ours for messages a room host
+/// relays.
+#[derive(Clone, Debug)]
+pub struct Pending {
+    pub id: String,
+    pub sender_id: String,
+    pub text: String,
+    pub sent: Instant,
+    pub failed: bool,
+    /// How many times the message was resent on a new session.
+    pub resends: u32,
+}
+
+/// Tracks the delivery state of the conversation with one peer: the messages still
+/// waiting for an `Ack`, and the IDs of recently received messages. It outlives a
+/// single connection, so unacknowledged messages can be resent after a reconnect and
+/// the peer's retransmissions are not shown twice.
+#[derive(Default)]
+pub struct Outbox {
+    pending: Vec<Pending>,
+    seen: HashSet<String>,
+    seen_order: VecDeque<String>,
+}
+
+impl Outbox {
+    /// Records a message that has just been sent. If `MAX_PENDING` messages were
+    /// already waiting, the oldest is dropped and returned, to be reported as not
+    /// delivered.
+    pub fn push(&mut self, id: String, sender_id: String, text: String) -> Option<Pending> {
+        self.pending.push(Pending { id, sender_id, text, sent: Instant::now(), failed: false, resends: 0 });
+        (self.pending.len() > MAX_PENDING).then(|| self.pending.remove(0))
+    }
+
+    /// Marks a message as delivered, returning it if it was still pending.
+    pub fn ack(&mut self, id: &str) -> Option<Pending> {
+        let pos = self.pending.iter().position(|p| p.id == id)?;
+        Some(self.pending.remove(pos))
+    }
+
+    /// Marks messages that have waited longer than `ACK_TIMEOUT` as failed and returns
+    /// them. Each message is only returned once.
+    pub fn expire(&mut self) -> Vec<Pending> {
+        let mut expired = Vec::new();
+        for p in self.pending.iter_mut().filter(|p| !p.failed && p.sent.elapsed() >= ACK_TIMEOUT) {
+            p.failed = true;
+            expired.push(p.clone());
+        }
+        expired
+    }
+
+    /// Removes and returns the messages that were already resent `MAX_RESENDS` times.
+    /// Called before `resend`, so they are reported instead of being sent again.
+    pub fn give_up(&mut self) -> Vec<Pending> {
+        let (given_up, kept) = std::mem::take(&mut self.pending).into_iter().partition(|p| p.resends >= MAX_RESENDS);
+        self.pending = kept;
+        given_up
+    }
+
+    /// Returns the messages that are still unacknowledged, oldest first, and restarts
+    /// their timers. Called when a new session starts to retransmit them.
+    pub fn resend(&mut self) -> Vec<Pending> {
+        for p in &mut self.pending {
+            p.sent = Instant::now();
+            p.failed = false;
+            p.resends += 1;
+        }
+        self.pending.clone()
+    }
+
+    /// Returns the number of unacknowledged messages.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Records a received message ID. Returns false if it was already seen, meaning
+    /// the message is a retransmission.
+    pub fn mark_seen(&mut self, id: &str) -> bool {
+        if !self.seen.insert(id.to_string()) {
+            return false;
+        }
+        self.seen_order.push_back(id.to_string());
+        if self.seen_order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// The outboxes of all peers, keyed by identity key.
+#[derive(Clone, Default)]
+pub struct Outboxes {
+    inner: Arc<Mutex<HashMap<String, Arc<Mutex<Outbox>>>>>,
+}
+
+impl Outboxes {
+    /// Returns the outbox for the peer with the given identity key, creating it if
+    /// needed.
+    pub fn for_peer(&self, identity: &str) -> Arc<Mutex<Outbox>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .entry(identity.to_string())
+            .or_default()
+            .clone()
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use crate::discovery::{Discovery, DiscoveryConfig, FoundPeer, Sighting};
+use crate::status;
+
+/// How often peers that were not seen again in time are dropped from the list.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A peer on the list of a `DiscoveryService`. `first_seen` and `last_seen` are in
+/// seconds since the Unix epoch.
+#[derive(Debug, Clone)]
+pub struct SeenPeer {
+    pub peer: FoundPeer,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// A change to the list of peers of a `DiscoveryService`.
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    /// A peer that is not on the list announced itself.
+    Appeared(SeenPeer),
+    /// A listed peer announced different details, or no longer announced the address
+    /// it was listed with.
+    Updated(SeenPeer),
+    /// A listed peer left, or was not seen again in time, and was dropped.
+    Expired(SeenPeer),
+    /// The search failed and stopped.
+    Stopped(String),
+}
+
+/// A long-lived search for peers on the local network. It keeps a list of the peers
+/// it has seen, in the order they appeared, drops those that leave or are not seen
+/// again in time, and reports each change as a `PeerEvent`. Peers are told apart by
+/// their identity key and listening port. The statuses saved peers announce are
+/// remembered for `list-peers`. The search stops when the service is dropped.
+pub struct DiscoveryService {
+    peers: Arc<Mutex<Vec<SeenPeer>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl DiscoveryService {
+    /// Starts searching with the backend set in `config`. Returns the service and the
+    /// receiver of its events.
+    pub fn start(config: &DiscoveryConfig) -> anyhow::Result<(Self, mpsc::UnboundedReceiver<PeerEvent>)> {
+        let backend = config.start()?;
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(search(backend, peers.clone(), events));
+        Ok((DiscoveryService { peers, task }, events_rx))
+    }
+
+    /// Returns the listed peers, in the order they appeared.
+    pub fn peers(&self) -> Vec<SeenPeer> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+impl Drop for DiscoveryService {
+    fn drop(&mut self) {
+        self.task.abort();
+        status::save_known();
+    }
+}
+
+/// Runs the search of a `DiscoveryService` until it fails.
+async fn search(backend: Box<dyn Discovery>, peers: Arc<Mutex<Vec<SeenPeer>>>, events: mpsc::UnboundedSender<PeerEvent>) {
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        let changes = tokio::select! {
+            sighting = backend.next_sighting() => match sighting {
+                Ok(sighting) => record(&mut peers.lock().unwrap(), sighting).into_iter().collect(),
+                Err(e) => {
+                    let _ = events.send(PeerEvent::Stopped(format!("{:#}", e)));
+                    return;
+                }
+            },
+            _ = prune.tick() => expire(&mut peers.lock().unwrap(), backend.as_ref()),
+        };
+        // The list is kept up to date even if nobody listens to the events
+        for change in changes {
+            if let PeerEvent::Appeared(seen) | PeerEvent::Updated(seen) = &change {
+                let announcement = &seen.peer.announcement;
+                if let Some(peer_status) = &announcement.status {
+                    status::remember(&announcement.identity, peer_status);
+                }
+            }
+            let _ = events.send(change);
+        }
+    }
+}
+
+/// Updates the list with what the search learned, and returns the change to report,
+/// if any.
+fn record(peers: &mut Vec<SeenPeer>, sighting: Sighting) -> Option<PeerEvent> {
+    match sighting {
+        Sighting::Seen(peer) => {
+            let now = now();
+            let key = (&peer.announcement.identity, peer.announcement.port);
+            let Some(listed) = peers.iter_mut().find(|p| (&p.peer.announcement.identity, p.peer.announcement.port) == key) else {
+                let seen = SeenPeer { peer, first_seen: now, last_seen: now };
+                peers.push(seen.clone());
+                return Some(PeerEvent::Appeared(seen));
+            };
+            listed.last_seen = now;
+            let addresses = &peer.announcement.addresses;
+            let moved = !addresses.is_empty() && !addresses.contains(&listed.peer.addr.ip());
+            if listed.peer.announcement.same_details(&peer.announcement) && !moved {
+                return None;
+            }
+            listed.peer = peer;
+            Some(PeerEvent::Updated(listed.clone()))
+        }
+        Sighting::Gone { identity, port } => {
+            let index = peers
+                .iter()
+                .position(|p| p.peer.announcement.identity == identity && p.peer.announcement.port == port)?;
+            Some(PeerEvent::Expired(peers.remove(index)))
+        }
+    }
+}
+
+/// Drops the peers that were not seen for longer than the backend's expiry for each,
+/// and returns the changes to report.
+fn expire(peers: &mut Vec<SeenPeer>, backend: &dyn Discovery) -> Vec<PeerEvent> {
+    let now = now();
+    let mut expired = Vec::new();
+    peers.retain(|p| {
+        let Some(expiry) = backend.expiry(&p.peer.announcement) else { return true };
+        let fresh = now.saturating_sub(p.last_seen) <= expiry.as_secs();
+        if !fresh {
+            expired.push(PeerEvent::Expired(p.clone()));
+        }
+        fresh
+    });
+    expired
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
@@ -1,60 +1,192 @@
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
 use std::fs;
 use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha256};
+use peer_common::crypto::{decrypt_message, derive_passphrase_key, encrypt_message};
+use peer_common::types::PeerStatus;
+use peer_common::Identity;
+use crate::addr::{parse_addr, DEFAULT_PORT};
+use crate::console::errln;
+use crate::discovery::DiscoveryConfig;
 
 /// Represents the configuration for a single peer, including their name (alias),
-/// address, and an optional public key. The public key is not currently used but
-/// is reserved for future functionality.
+/// address, and an optional public key. The public key is the peer's long-term
+/// identity key, pinned the first time we successfully connect to them. `verified`
+/// records whether the user has compared safety numbers with the peer out-of-band,
+/// and `status` the status the peer last showed, if we have seen one.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PeerConfig {
     pub name: String,
     pub addr: String,
     pub pubkey_b64: Option<String>,
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<KnownStatus>,
+}
+
+/// A status a saved peer showed, and since when we have seen it, in seconds since
+/// the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KnownStatus {
+    #[serde(flatten)]
+    pub status: PeerStatus,
+    pub since: u64,
 }
 
 /// The main container for the application's persistent data, which is a list of
-/// `PeerConfig`s. This struct is serialized to and from the configuration file.
+/// `PeerConfig`s, the directory received files are saved in, if one was set, the
+/// discovery settings, and how many seconds without input turn our status to away
+/// (see `status::IDLE_AWAY_AFTER`). This struct is serialized to and from the configuration file.
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Persist {
     pub peers: Vec<PeerConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_away_secs: Option<u64>,
+}
+
+/// The result of comparing the identity key presented by a peer against the key we
+/// have pinned for them.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrustStatus {
+    /// No key has been pinned for this peer yet (trust on first use).
+    FirstUse,
+    /// The presented key matches the pinned key.
+    Trusted,
+    /// The presented key differs from the pinned key. The pinned key is returned.
+    Mismatch(String),
+}
+
+/// The configuration file set with `set_config_path`, if any.
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Held through each load, change and save of `Persist::update`, so that changes made
+/// at the same time, such as a pinned key and the statuses saved in the background,
+/// do not overwrite each other.
+static UPDATE: Mutex<()> = Mutex::new(());
+
+/// Held while the configuration file is written.
+static WRITE: Mutex<()> = Mutex::new(());
+
+/// Makes the persisted data be loaded from and saved to `path` instead of the default
+/// configuration file, and the identity key and history be kept next to it. Must be
+/// called before any of them is first loaded.
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
 }
 
 /// Returns the path to the configuration file, which is `.p2p-chat.json` in the
-/// user's home directory.
+/// user's home directory unless another one was set with `set_config_path`.
 fn get_config_path() -> anyhow::Result<PathBuf> {
+    if let Some(path) = CONFIG_PATH.get() {
+        return Ok(path.clone());
+    }
     let home = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
     Ok(home.join(".p2p-chat.json"))
 }
 
+/// Returns the directory the identity key and history are kept in: the one the
+/// configuration file set with `set_config_path` is in, or else the user's home
+/// directory. Each configuration file thus comes with an identity of its own.
+fn get_data_dir() -> anyhow::Result<PathBuf> {
+    if let Some(path) = CONFIG_PATH.get() {
+        return Ok(match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        });
+    }
+    dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))
+}
+
+/// Returns the path to the identity key file, which is `.p2p-chat.key` in the
+/// data directory (see `get_data_dir`).
+fn get_identity_path() -> anyhow::Result<PathBuf> {
+    Ok(get_data_dir()?.join(".p2p-chat.key"))
+}
+
+/// Returns the path to the directory the chat history is kept in, which is
+/// `.p2p-chat-history` in the data directory (see `get_data_dir`).
+fn get_history_dir() -> anyhow::Result<PathBuf> {
+    Ok(get_data_dir()?.join(".p2p-chat-history"))
+}
+
+/// Loads this install's long-term identity key, generating and saving a new one the
+/// first time the application runs. The key file is only readable by the owner.
+pub fn load_or_create_identity() -> anyhow::Result<Identity> {
+    let path = get_identity_path()?;
+    if let Ok(s) = fs::read_to_string(&path) {
+        return Ok(Identity::from_b64(&s)?);
+    }
+
+    let identity = Identity::generate();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(&path)?;
+    f.write_all(identity.to_b64().as_bytes())?;
+    Ok(identity)
+}
+
 impl Persist {
     /// Loads the persisted data from the configuration file. If the file doesn't exist
-    /// or is invalid, it returns a default `Persist` instance.
-    pub fn load() -> Self {
-        if let Ok(path) = get_config_path() {
-            if let Ok(s) = fs::read_to_string(path) {
-                if let Ok(p) = serde_json::from_str(&s) {
-                    return p;
-                }
-            }
+    /// yet, it returns a default `Persist` instance. A file that cannot be read or
+    /// parsed is an error, so that saving never replaces the saved peers and their
+    /// pinned keys with the defaults.
+    pub fn load() -> anyhow::Result<Self> {
+        let path = get_config_path()?;
+        match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| anyhow::anyhow!("invalid configuration file {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Persist::default()),
+            Err(e) => Err(anyhow::anyhow!("could not read configuration file {}: {}", path.display(), e)),
         }
-        Persist::default()
     }
 
     /// Saves the current state of the `Persist` struct to the configuration file in a
-    /// pretty-printed JSON format.
-    pub fn save(&self) -> anyhow::Result<()> {
+    /// pretty-printed JSON format. The data is written to a temporary file that then
+    /// replaces the configuration file, so `load` never reads a half-written one.
+    fn save(&self) -> anyhow::Result<()> {
         let path = get_config_path()?;
-        let mut f = fs::File::create(path)?;
+        let name = path.file_name().ok_or_else(|| anyhow::anyhow!("invalid configuration path {}", path.display()))?;
+        let temp = path.with_file_name(format!("{}.{}.tmp", name.to_string_lossy(), std::process::id()));
+        let _write = WRITE.lock().unwrap();
+        let mut f = fs::File::create(&temp)?;
         f.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&temp, &path)?;
         Ok(())
     }
 
+    /// Loads the persisted data, lets `change` modify it, and saves it if `change`
+    /// returns true. Returns what `change` returned. No other update runs in between,
+    /// so none of their changes is lost.
+    pub fn update(change: impl FnOnce(&mut Persist) -> bool) -> anyhow::Result<bool> {
+        let _update = UPDATE.lock().unwrap();
+        let mut persist = Persist::load()?;
+        let changed = change(&mut persist);
+        if changed {
+            persist.save()?;
+        }
+        Ok(changed)
+    }
+
     /// Adds a new peer to the list of peers. If a peer with the same name already
-    /// exists, it is replaced.
+    /// exists, it is replaced and any identity key pinned for it is forgotten.
     pub fn add_peer(&mut self, name: String, addr: String) {
         self.peers.retain(|p| p.name != name);
-        self.peers.push(PeerConfig { name, addr, pubkey_b64: None });
+        self.peers.push(PeerConfig { name, addr, pubkey_b64: None, verified: false, status: None });
     }
 
     /// Retrieves a peer by their name (alias).
@@ -62,8 +194,241 @@ impl Persist {
         self.peers.iter().find(|p| p.name == name)
     }
 
+    /// Retrieves a peer by their address. Saved addresses are compared as parsed by
+    /// `parse_addr`, so `[fe80::1%eth0]:12345` matches `[fe80::1%2]:12345`.
+    pub fn get_peer_by_addr(&self, addr: &str) -> Option<&PeerConfig> {
+        self.peers.iter().find(|p| p.addr == addr || parse_addr(&p.addr, DEFAULT_PORT).is_ok_and(|a| a == addr))
+    }
+
+    /// Retrieves a peer with a pinned identity key whose saved address has the IP
+    /// address `ip`, such as the peer a listener was just connected from.
+    pub fn get_pinned_peer_at(&self, ip: IpAddr) -> Option<&PeerConfig> {
+        self.peers.iter().filter(|p| p.pubkey_b64.is_some()).find(|p| {
+            parse_addr(&p.addr, DEFAULT_PORT)
+                .ok()
+                .and_then(|a| a.parse::<SocketAddr>().ok())
+                .is_some_and(|a| a.ip().to_canonical() == ip.to_canonical())
+        })
+    }
+
+    /// Retrieves a peer by their pinned identity key.
+    pub fn get_peer_by_key(&self, pubkey_b64: &str) -> Option<&PeerConfig> {
+        self.peers.iter().find(|p| p.pubkey_b64.as_deref() == Some(pubkey_b64))
+    }
+
+    /// Compares the identity key presented by the named peer with the pinned key.
+    pub fn check_peer_key(&self, name: &str, pubkey_b64: &str) -> TrustStatus {
+        match self.get_peer(name).and_then(|p| p.pubkey_b64.as_deref()) {
+            None => TrustStatus::FirstUse,
+            Some(pinned) if pinned == pubkey_b64 => TrustStatus::Trusted,
+            Some(pinned) => TrustStatus::Mismatch(pinned.to_string()),
+        }
+    }
+
+    /// Pins the identity key for the named peer. Does nothing if the peer is unknown.
+    pub fn pin_peer_key(&mut self, name: &str, pubkey_b64: String) {
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.name == name) {
+            peer.pubkey_b64 = Some(pubkey_b64);
+        }
+    }
+
+    /// Marks the named peer as verified. Returns `false` if the peer is unknown or has
+    /// no pinned identity key to verify.
+    pub fn mark_verified(&mut self, name: &str) -> bool {
+        match self.peers.iter_mut().find(|p| p.name == name) {
+            Some(peer) if peer.pubkey_b64.is_some() => {
+                peer.verified = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the status shown since `since` by the peer with the pinned identity
+    /// key `pubkey_b64`. Returns true if that changed the status of a saved peer.
+    pub fn record_status(&mut self, pubkey_b64: &str, status: &PeerStatus, since: u64) -> bool {
+        let mut changed = false;
+        for peer in self.peers.iter_mut().filter(|p| p.pubkey_b64.as_deref() == Some(pubkey_b64)) {
+            if peer.status.as_ref().is_some_and(|known| known.status == *status) {
+                continue;
+            }
+            peer.status = Some(KnownStatus { status: status.clone(), since });
+            changed = true;
+        }
+        changed
+    }
+
+    /// Returns the directory received files are saved in: the configured one, or a
+    /// `p2p-chat` folder in the user's downloads directory (or home directory, if
+    /// there is none).
+    pub fn download_dir(&self) -> anyhow::Result<PathBuf> {
+        if let Some(dir) = &self.download_dir {
+            return Ok(dir.clone());
+        }
+        let base = dirs::download_dir()
+            .or_else(dirs::home_dir)
+            .ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
+        Ok(base.join("p2p-chat"))
+    }
+
     /// Returns a reference to the list of all saved peers.
     pub fn list_peers(&self) -> &Vec<PeerConfig> {
         &self.peers
     }
 }
+
+/// The associated data of the record that checks the history passphrase.
+const HISTORY_CHECK: &[u8] = b"p2p-chat-history-check";
+
+/// A chat message kept in the history. `sender_id` is the identity key of its author,
+/// which is our own for messages we sent, and `timestamp` is in seconds since the
+/// Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub sender_id: String,
+    pub text: String,
+}
+
+/// One line of a history file: an encrypted `HistoryEntry` and its nonce, base64
+/// encoded.
+#[derive(Serialize, Deserialize)]
+struct HistoryRecord {
+    nonce: String,
+    payload: String,
+}
+
+/// The contents of `key.json` in the history directory: the salt the history key is
+/// derived with, and a record encrypted under the key to check the passphrase with.
+#[derive(Serialize, Deserialize)]
+struct HistoryKeyFile {
+    salt: String,
+    check: HistoryRecord,
+}
+
+/// The chat history: an append-only log of the messages exchanged with each peer,
+/// keyed by the peer's identity key. Every message is encrypted with a key derived
+/// from a local passphrase and authenticated together with the peer's identity key,
+/// so records cannot be moved from one peer's log to another's.
+pub struct History {
+    dir: PathBuf,
+    key: [u8; 32],
+}
+
+impl History {
+    /// Returns true once the history has been set up with a passphrase.
+    pub fn exists() -> bool {
+        get_history_dir().is_ok_and(|dir| dir.join("key.json").exists())
+    }
+
+    /// Unlocks the history with the passphrase. The first time, the history is set up
+    /// with the given passphrase; after that, a wrong passphrase is an error.
+    pub fn open(passphrase: &str) -> anyhow::Result<History> {
+        let dir = get_history_dir()?;
+        let key_path = dir.join("key.json");
+        if let Ok(s) = fs::read_to_string(&key_path) {
+            let key_file: HistoryKeyFile = serde_json::from_str(&s)?;
+            let salt = general_purpose::STANDARD.decode(&key_file.salt)?;
+            let history = History { key: derive_passphrase_key(passphrase.as_bytes(), &salt)?, dir };
+            history
+                .open_record(&key_file.check, HISTORY_CHECK)
+                .map_err(|_| anyhow::anyhow!("wrong passphrase"))?;
+            return Ok(history);
+        }
+
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&dir)?;
+        let salt: [u8; 16] = rand::random();
+        let history = History { key: derive_passphrase_key(passphrase.as_bytes(), &salt)?, dir };
+        let key_file = HistoryKeyFile {
+            salt: general_purpose::STANDARD.encode(salt),
+            check: history.seal(HISTORY_CHECK, HISTORY_CHECK)?,
+        };
+        let mut f = open_private(&key_path, false)?;
+        f.write_all(serde_json::to_string_pretty(&key_file)?.as_bytes())?;
+        Ok(history)
+    }
+
+    /// Appends a message to the history of the peer with the given identity key.
+    pub fn append(&self, peer: &str, entry: &HistoryEntry) -> anyhow::Result<()> {
+        let record = self.seal(&serde_json::to_vec(entry)?, peer.as_bytes())?;
+        let mut f = open_private(&self.log_path(peer), true)?;
+        writeln!(f, "{}", serde_json::to_string(&record)?)?;
+        Ok(())
+    }
+
+    /// Saves a message sent or received just now to the history of the peer with the
+    /// given identity key. Failures are reported but do not interrupt the chat.
+    pub fn record(&self, peer: &str, sender_id: &str, text: &str) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let entry = HistoryEntry { timestamp, sender_id: sender_id.to_string(), text: text.to_string() };
+        if let Err(e) = self.append(peer, &entry) {
+            errln!("could not save message to history: {}", e);
+        }
+    }
+
+    /// Returns the history of the peer with the given identity key, oldest first.
+    /// Records that cannot be read, such as one cut short by a crash, are skipped.
+    pub fn load(&self, peer: &str) -> anyhow::Result<Vec<HistoryEntry>> {
+        let s = match fs::read_to_string(self.log_path(peer)) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(s.lines()
+            .filter_map(|line| serde_json::from_str::<HistoryRecord>(line).ok())
+            .filter_map(|record| self.open_record(&record, peer.as_bytes()).ok())
+            .filter_map(|plaintext| serde_json::from_slice(&plaintext).ok())
+            .collect())
+    }
+
+    /// Returns the path of the log of the peer with the given identity key. Identity
+    /// keys may contain `/`, so the file is named after a hash of the key.
+    fn log_path(&self, peer: &str) -> PathBuf {
+        let hash = format!("{:x}", Sha256::digest(peer.as_bytes()));
+        self.dir.join(format!("{}.log", &hash[..32]))
+    }
+
+    /// Encrypts a record under the history key.
+    fn seal(&self, plaintext: &[u8], aad: &[u8]) -> anyhow::Result<HistoryRecord> {
+        let (ciphertext, nonce) = encrypt_message(&self.key, plaintext, aad)?;
+        Ok(HistoryRecord {
+            nonce: general_purpose::STANDARD.encode(nonce),
+            payload: general_purpose::STANDARD.encode(ciphertext),
+        })
+    }
+
+    /// Decrypts a record encrypted under the history key.
+    fn open_record(&self, record: &HistoryRecord, aad: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce: [u8; 24] = general_purpose::STANDARD
+            .decode(&record.nonce)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("bad nonce"))?;
+        let payload = general_purpose::STANDARD.decode(&record.payload)?;
+        Ok(decrypt_message(&self.key, &payload, &nonce, aad)?)
+    }
+}
+
+/// Opens a file that only the owner can read, creating it if needed. With `append`,
+/// writes go to the end of the file; otherwise it is truncated.
+fn open_private(path: &std::path::Path, append: bool) -> std::io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true);
+    if append {
+        options.append(true);
+    } else {
+        options.write(true).truncate(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use peer_common::types::{PeerStatus, RoomEvent, StatusUpdate};
+use peer_common::Identity;
+use crate::console::{self, errln, outln, print_message};
+use crate::link::{Connection, Link, LinkEvent};
+use crate::net::handle_verify_command;
+use crate::outbox::Outbox;
+use crate::persistence::History;
+use crate::status;
+use crate::transfer::Transfers;
+
+/// A chat room hosted by the listener. Every member has its own end-to-end encrypted
+/// session with the host; the host decrypts each message and encrypts it again for
+/// every other member. Messages carry the identity key of their author as `sender_id`.
+/// The host sets it from the member's authenticated session, so a member cannot post
+/// in someone else's name. Members' statuses are relayed the same way.
+pub(crate) struct Room {
+    name: String,
+    identity: Arc<Identity>,
+    members: Mutex<BTreeMap<u64, Link>>,
+    events: mpsc::UnboundedSender<LinkEvent>,
+    history: Option<Arc<History>>,
+}
+
+impl Room {
+    /// Creates an empty room. The members' messages are reported on `events` and
+    /// must be handed back to `relay`. Messages are saved to `history`, if given, in
+    /// the log of the member who wrote or received them.
+    pub fn new(name: &str, identity: Arc<Identity>, events: mpsc::UnboundedSender<LinkEvent>, history: Option<Arc<History>>) -> Arc<Room> {
+        Arc::new(Room {
+            name: name.to_string(),
+            identity,
+            members: Mutex::new(BTreeMap::new()),
+            events,
+            history,
+        })
+    }
+
+    /// Adds the peer of an established connection to the room and keeps it there
+    /// until it disconnects. The new member is welcomed with the list of members and
+    /// their statuses, and everyone else is told that it joined, and later that it
+    /// left.
+    pub async fn join(&self, conn: Connection, outbox: Arc<Mutex<Outbox>>, transfers: Arc<Mutex<Transfers>>) -> anyhow::Result<()> {
+        use colored::Colorize;
+
+        // The members stay locked until the new member is added, so its first
+        // messages cannot be relayed before then.
+        let (link, mut tasks) = {
+            let mut members = self.members.lock().unwrap();
+            let (link, tasks) = Link::start(conn, self.identity.clone(), outbox, transfers, self.events.clone())?;
+            let identity = link.peer.identity.clone();
+            let mut everyone = vec![self.identity.public_b64()];
+            everyone.extend(members.values().map(|m| m.peer.identity.clone()));
+            everyone.push(identity.clone());
+            let _ = link.send_room_event(&RoomEvent::Welcome { room: self.name.clone(), members: everyone });
+            for member in members.values() {
+                if let Some(status) = member.status_of(&member.peer.identity) {
+                    let _ = link.send_status(&StatusUpdate { identity: member.peer.identity.clone(), status });
+                }
+            }
+            for member in members.values() {
+                let _ = member.send_room_event(&RoomEvent::Joined { identity: identity.clone() });
+            }
+            members.insert(link.id, link.clone());
+            (link, tasks)
+        };
+        let identity = link.peer.identity.clone();
+        outln!("{}", format!("→ {} joined #{}", link.name(), self.name).cyan());
+        console::reprompt();
+
+        tasks.closed().await;
+
+        let link = {
+            let mut members = self.members.lock().unwrap();
+            for member in members.values().filter(|m| m.id != link.id) {
+                let _ = member.send_room_event(&RoomEvent::Left { identity: identity.clone() });
+            }
+            members.remove(&link.id).unwrap_or(link)
+        };
+        outln!("\n{}", format!("← {} left #{}", link.name(), self.name).cyan());
+        console::reprompt();
+        tasks.shutdown(link).await;
+        Ok(())
+    }
+
+    /// Returns true if the link belongs to a member of this room.
+    pub fn has_member(&self, link: u64) -> bool {
+        self.members.lock().unwrap().contains_key(&link)
+    }
+
+    /// Returns the current members.
+    pub fn members(&self) -> Vec<Link> {
+        self.members.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Sends a message from the host to every member. Returns false if the room is
+    /// empty.
+    pub fn send(&self, text: &str) -> bool {
+        let members = self.members();
+        if members.is_empty() {
+            outln!("Nobody else is in #{}.", self.name);
+            return false;
+        }
+        let own_id = self.identity.public_b64();
+        for member in &members {
+            if let Err(e) = member.send_chat(None, &own_id, text) {
+                errln!("could not send to {}: {}", member.name(), e);
+            }
+            if let Some(history) = &self.history {
+                history.record(&member.peer.identity, &own_id, text);
+            }
+        }
+        true
+    }
+
+    /// Handles the `/who` command.
+    pub fn who(&self) {
+        let members = self.members();
+        outln!("#{} ({} member(s) and you):", self.name, members.len());
+        for member in &members {
+            let status = member.status_of(&member.peer.identity).map_or(String::new(), |s| format!(" [{}]", s));
+            let verified = if member.verified() { "" } else { " (unverified)" };
+            outln!("  - {}{}{}", member.name(), status, verified);
+        }
+    }
+
+    /// Handles the `/verify` command. With several members, the first argument names
+    /// the one to verify.
+    pub fn verify(&self, arg: &str) -> anyhow::Result<()> {
+        let members = self.members();
+        let mut args = arg.split_whitespace();
+        let (member, arg) = match (members.as_slice(), args.next()) {
+            ([member], None) => (Some(member), ""),
+            ([member], Some("confirm")) => (Some(member), "confirm"),
+            (_, Some(name)) => (members.iter().find(|m| m.name() == name), args.next().unwrap_or("")),
+            (_, None) => (None, ""),
+        };
+        match member {
+            Some(member) => handle_verify_command(arg, &self.identity, member)?,
+            None => outln!("Usage: /verify <member> [confirm]"),
+        }
+        Ok(())
+    }
+
+    /// Handles the `/ping` command.
+    pub fn ping(&self) {
+        for member in &self.members() {
+            outln!("  - {}: {}", member.name(), member.ping_status());
+        }
+    }
+
+    /// Handles the `/rekey` command.
+    pub fn rekey(&self) {
+        for member in &self.members() {
+            if member.rekey() {
+                outln!("🔄 Rekey requested with {}.", member.name());
+            }
+        }
+    }
+
+    /// Shows a member's message and sends it on to everyone else in the room.
+    pub fn relay(&self, link: u64, id: &str, text: &str) {
+        let members = self.members();
+        let Some(from) = members.iter().find(|m| m.id == link) else { return };
+        print_message(Some(&format!("#{}", self.name)), &from.name(), text);
+        if let Some(history) = &self.history {
+            history.record(&from.peer.identity, &from.peer.identity, text);
+        }
+        for member in members.iter().filter(|m| m.id != link) {
+            if let Err(e) = member.send_chat(Some(id), &from.peer.identity, text) {
+                errln!("could not relay to {}: {}", member.name(), e);
+            }
+        }
+    }
+
+    /// Shows a member's change of status and sends it on to everyone else in the
+    /// room. A member can only change its own status, and one seen for the first
+    /// time is only shown if it is not plain available.
+    pub fn relay_status(&self, link: u64, update: &StatusUpdate, previous: Option<&PeerStatus>) {
+        use colored::Colorize;
+
+        let members = self.members();
+        let Some(from) = members.iter().find(|m| m.id == link) else { return };
+        if update.identity != from.peer.identity {
+            return;
+        }
+        if previous.is_some() || update.status != PeerStatus::default() {
+            outln!("\n{}", format!("[#{}] {} is now {}", self.name, from.name(), update.status).cyan());
+        }
+        status::remember(&from.peer.identity, &update.status);
+        for member in members.iter().filter(|m| m.id != link) {
+            if let Err(e) = member.send_status(update) {
+                errln!("could not relay to {}: {}", member.name(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,747 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use peer_common::types::{new_message_id, Availability, PeerStatus, RoomEvent, StatusUpdate};
+use peer_common::Identity;
+use crate::addr::{parse_addr, DEFAULT_PORT};
+use crate::console::{self, display_name, ConversationStatus, Status, errln, outln, print_message, print_own_message, stdin_lines};
+use crate::framing::FramingConfig;
+use crate::link::{Connection, Link, LinkEvent};
+use crate::net::{handle_verify_command, handshake, is_transient};
+use crate::outbox::{Outbox, Outboxes};
+use crate::persistence::{History, Persist};
+use crate::room::Room;
+use crate::status::{self, IdleAway};
+use crate::transfer::{self, PeerTransfers};
+
+/// How many earlier messages are shown when a conversation with a peer opens.
+const SCROLLBACK_LEN: usize = 10;
+
+/// The first and the longest delay between reconnection attempts. The delay doubles
+/// after every failed attempt.
+const RECONNECT_INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// A conversation in the console: the room we host, or a peer we connected to.
+#[derive(Clone)]
+enum Conversation {
+    Room(Arc<Room>),
+    Peer(Arc<PeerConversation>),
+}
+
+/// A conversation with a peer we connected to, which may be hosting a room. It lasts
+/// across reconnections until it is closed.
+struct PeerConversation {
+    name: String,
+    identity: String,
+    target: String,
+    alias: Option<String>,
+    state: Mutex<PeerState>,
+    close: Notify,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct PeerState {
+    /// The current link, or `None` while reconnecting.
+    link: Option<Link>,
+    /// The ID of the latest link. It is kept after the link closes, so messages that
+    /// were still on their way from it are shown in the right conversation.
+    link_id: Option<u64>,
+    /// The peer's outbox. Messages typed while reconnecting are queued in it.
+    outbox: Option<Arc<Mutex<Outbox>>>,
+    /// The room the peer hosts and the identity keys of its members.
+    room: Option<(String, Vec<String>)>,
+}
+
+/// Holds all the live conversations of the process and runs the console that routes
+/// input to them. Typed messages go to the active conversation, which `/switch`
+/// changes; `@name message` sends to another one. Incoming messages from every
+/// conversation are shown as they arrive, tagged with their conversation. If there is
+/// a `history`, every message is saved to it.
+pub(crate) struct SessionManager {
+    identity: Arc<Identity>,
+    framing: FramingConfig,
+    history: Option<Arc<History>>,
+    outboxes: Outboxes,
+    transfers: PeerTransfers,
+    events: mpsc::UnboundedSender<LinkEvent>,
+    conversations: Mutex<BTreeMap<String, Conversation>>,
+    active: Mutex<Option<String>>,
+}
+
+impl SessionManager {
+    /// Creates a session manager without conversations. The returned receiver must be
+    /// passed to `run`.
+    pub fn new(identity: Arc<Identity>, framing: FramingConfig, history: Option<History>) -> (Arc<SessionManager>, mpsc::UnboundedReceiver<LinkEvent>) {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let manager = SessionManager {
+            identity,
+            framing,
+            history: history.map(Arc::new),
+            outboxes: Outboxes::default(),
+            transfers: PeerTransfers::default(),
+            events,
+            conversations: Mutex::new(BTreeMap::new()),
+            active: Mutex::new(None),
+        };
+        let manager = Arc::new(manager);
+        console::watch(Arc::downgrade(&manager));
+        (manager, events_rx)
+    }
+
+    /// Returns the outboxes of all peers.
+    pub fn outboxes(&self) -> Outboxes {
+        self.outboxes.clone()
+    }
+
+    /// Returns the file transfers of all peers.
+    pub fn transfers(&self) -> PeerTransfers {
+        self.transfers.clone()
+    }
+
+    /// Creates a room hosted by this process and opens a conversation for it.
+    pub fn host(&self, name: &str) -> Arc<Room> {
+        let room = Room::new(name, self.identity.clone(), self.events.clone(), self.history.clone());
+        self.open(format!("#{}", name), Conversation::Room(room.clone()));
+        outln!("Hosting room #{}. Type /who to see who is here.", name);
+        room
+    }
+
+    /// Connects to a peer and opens a conversation with it, named after the saved peer
+    /// it turns out to be, or else its address. If the connection is lost, it is
+    /// re-established with exponential backoff until the conversation is closed, and
+    /// the peer must present the same identity key as before. The latest messages
+    /// exchanged with the peer are shown first.
+    pub async fn connect(self: &Arc<Self>, target: &str, alias: Option<String>) -> anyhow::Result<()> {
+        let alias = match alias {
+            Some(alias) => Some(alias),
+            None => Persist::load()?.get_peer_by_addr(target).map(|p| p.name.clone()),
+        };
+        let stream = TcpStream::connect(target).await?;
+        outln!("Connected to {}", target);
+        let conn = handshake(stream, false, &self.identity, alias.as_deref(), None, self.framing).await?;
+        let name = conn.peer.name.clone().unwrap_or_else(|| target.to_string());
+        if self.conversations.lock().unwrap().contains_key(&name) {
+            anyhow::bail!("already connected to {}", name);
+        }
+
+        self.show_scrollback(&name, &conn.peer.identity);
+        let conversation = Arc::new(PeerConversation {
+            name: name.clone(),
+            identity: conn.peer.identity.clone(),
+            target: target.to_string(),
+            alias,
+            state: Mutex::new(PeerState::default()),
+            close: Notify::new(),
+            task: Mutex::new(None),
+        });
+        self.open(name, Conversation::Peer(conversation.clone()));
+        let manager = self.clone();
+        let task = tokio::spawn(manager.keep_connected(conversation.clone(), conn));
+        *conversation.task.lock().unwrap() = Some(task);
+        Ok(())
+    }
+
+    /// Shows the latest messages in the history of the peer with the given identity
+    /// key.
+    fn show_scrollback(&self, name: &str, peer: &str) {
+        let Some(history) = &self.history else { return };
+        match history.load(peer) {
+            Ok(entries) if !entries.is_empty() => {
+                let latest = &entries[entries.len().saturating_sub(SCROLLBACK_LEN)..];
+                console::print_scrollback(name, latest, &self.identity.public_b64());
+            }
+            Ok(_) => {}
+            Err(e) => errln!("could not read the history with {}: {}", name, e),
+        }
+    }
+
+    /// Adds a conversation and makes it the active one.
+    fn open(&self, name: String, conversation: Conversation) {
+        self.conversations.lock().unwrap().insert(name.clone(), conversation);
+        *self.active.lock().unwrap() = Some(name);
+    }
+
+    /// Removes a peer conversation, unless it has already been replaced by a new one
+    /// with the same name.
+    fn remove(&self, conversation: &Arc<PeerConversation>) {
+        let mut conversations = self.conversations.lock().unwrap();
+        if let Some(Conversation::Peer(current)) = conversations.get(&conversation.name) {
+            if Arc::ptr_eq(current, conversation) {
+                conversations.remove(&conversation.name);
+            }
+        }
+        let mut active = self.active.lock().unwrap();
+        if active.as_ref().is_some_and(|a| !conversations.contains_key(a)) {
+            *active = conversations.keys().next().cloned();
+        }
+    }
+
+    /// Runs the sessions of a peer conversation, reconnecting whenever the connection
+    /// is lost, until the conversation is closed or the peer can no longer be trusted.
+    async fn keep_connected(self: Arc<Self>, conversation: Arc<PeerConversation>, mut conn: Connection) {
+        use colored::Colorize;
+
+        loop {
+            let peer_identity = conn.peer.identity.clone();
+            let outbox = self.outboxes.for_peer(&peer_identity);
+            let transfers = self.transfers.for_peer(&peer_identity);
+            // The state stays locked until the link is recorded, so its first events
+            // cannot be looked up before then.
+            let started = {
+                let mut state = conversation.state.lock().unwrap();
+                let started = Link::start(conn, self.identity.clone(), outbox.clone(), transfers, self.events.clone());
+                if let Ok((link, _)) = &started {
+                    state.link = Some(link.clone());
+                    state.link_id = Some(link.id);
+                    state.outbox = Some(outbox);
+                }
+                started
+            };
+            let (link, mut tasks) = match started {
+                Ok(started) => started,
+                Err(e) => {
+                    errln!("{}: {}", conversation.name, e);
+                    break;
+                }
+            };
+            self.refresh_prompt();
+            console::reprompt();
+
+            let closed = tokio::select! {
+                _ = tasks.closed() => false,
+                _ = conversation.close.notified() => true,
+            };
+            {
+                let mut state = conversation.state.lock().unwrap();
+                state.link = None;
+                state.room = None;
+            }
+            if !closed {
+                outln!("\n{}", format!("{} disconnected.", link.name()).yellow());
+            }
+            let undelivered = link.pending_count();
+            tasks.shutdown(link).await;
+            if undelivered > 0 {
+                outln!("{} message(s) not delivered to {}; they will be resent when this peer reconnects.", undelivered, conversation.name);
+            }
+            if closed {
+                return;
+            }
+
+            let mut backoff = RECONNECT_INITIAL_BACKOFF;
+            conn = loop {
+                outln!("Reconnecting to {} in {}s... (type /close {} to give up)", conversation.name, backoff.as_secs(), conversation.name);
+                self.refresh_prompt();
+                console::reprompt();
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = conversation.close.notified() => return,
+                }
+                backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+
+                let reconnected = tokio::select! {
+                    result = self.reconnect(&conversation, &peer_identity) => result,
+                    _ = conversation.close.notified() => return,
+                };
+                match reconnected {
+                    Ok(conn) => break conn,
+                    Err(e) if is_transient(&e) => errln!("\nconnection error: {}", e),
+                    Err(e) => {
+                        errln!("\n{}: {}", conversation.name, e);
+                        self.remove(&conversation);
+                        return;
+                    }
+                }
+            };
+        }
+        self.remove(&conversation);
+    }
+
+    /// Connects to the peer of a conversation again. It must present the identity key
+    /// it had before.
+    async fn reconnect(&self, conversation: &PeerConversation, peer_identity: &str) -> anyhow::Result<Connection> {
+        let stream = TcpStream::connect(&conversation.target).await?;
+        outln!("\nReconnected to {}", conversation.target);
+        handshake(stream, false, &self.identity, conversation.alias.as_deref(), Some(peer_identity), self.framing).await
+    }
+
+    /// Reads user input and shows what arrives on the links until the user types
+    /// `/quit`. When stdin is closed, a room host keeps relaying messages; otherwise
+    /// the console ends. Our status turns to away while no input is typed.
+    pub async fn run(self: &Arc<Self>, mut events: mpsc::UnboundedReceiver<LinkEvent>) -> anyhow::Result<()> {
+        let mut lines = stdin_lines().lock().await;
+        let mut stdin_open = true;
+        let mut idle = IdleAway::new(Persist::load()?.idle_away_secs);
+        loop {
+            self.refresh_prompt();
+            console::reprompt();
+            tokio::select! {
+                line = lines.recv(), if stdin_open => match line {
+                    Some(line) => {
+                        idle.input();
+                        if !self.handle_input(line.trim_end()) {
+                            break;
+                        }
+                    }
+                    None if self.hosting() => stdin_open = false,
+                    None => break,
+                },
+                Some(event) = events.recv() => self.handle_event(event),
+                _ = idle.wait(), if stdin_open => idle.away(),
+            }
+        }
+        self.close_all().await;
+        status::save_known();
+        Ok(())
+    }
+
+    /// Returns true if this process hosts a room.
+    fn hosting(&self) -> bool {
+        self.conversations.lock().unwrap().values().any(|c| matches!(c, Conversation::Room(_)))
+    }
+
+    /// Closes every peer conversation, waiting until the queued frames are written.
+    async fn close_all(&self) {
+        let conversations: Vec<Conversation> = std::mem::take(&mut *self.conversations.lock().unwrap()).into_values().collect();
+        for conversation in conversations {
+            if let Conversation::Peer(peer) = conversation {
+                peer.close.notify_one();
+                let task = peer.task.lock().unwrap().take();
+                if let Some(task) = task {
+                    let _ = task.await;
+                }
+            }
+        }
+    }
+
+    /// Returns the conversation with the given name.
+    fn get(&self, name: &str) -> Option<Conversation> {
+        self.conversations.lock().unwrap().get(name).cloned()
+    }
+
+    /// Returns the active conversation and its name.
+    fn active(&self) -> Option<(String, Conversation)> {
+        let name = self.active.lock().unwrap().clone()?;
+        let conversation = self.get(&name)?;
+        Some((name, conversation))
+    }
+
+    /// Shows the active conversation in the prompt, flagging peers whose identity has
+    /// not been verified.
+    fn refresh_prompt(&self) {
+        use colored::Colorize;
+
+        let prompt = match self.active() {
+            None => "> ".to_string(),
+            Some((name, Conversation::Room(_))) => format!("{} > ", name.cyan()),
+            Some((name, Conversation::Peer(peer))) => {
+                let state = peer.state.lock().unwrap();
+                let mut prompt = format!("{} ", name.cyan());
+                if let Some((room, _)) = &state.room {
+                    prompt.push_str(&format!("{} ", format!("#{}", room).cyan()));
+                }
+                match &state.link {
+                    Some(link) if !link.verified() => prompt.push_str(&format!("{} ", "[unverified]".red())),
+                    Some(_) => {}
+                    None => prompt.push_str(&format!("{} ", "[offline]".yellow())),
+                }
+                prompt.push_str("> ");
+                prompt
+            }
+        };
+        console::set_prompt(prompt);
+    }
+
+    /// Handles a line of user input. Returns false if the user quit. A command is the
+    /// first word of the line, so `/statusx` is sent as a message rather than taken for
+    /// `/status`.
+    fn handle_input(self: &Arc<Self>, text: &str) -> bool {
+        if text.is_empty() {
+            return true;
+        }
+        let (command, arg) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let arg = arg.trim();
+        match command {
+            "/quit" => return false,
+            "/list" => {
+                self.list();
+                return true;
+            }
+            "/switch" => {
+                if self.get(arg).is_some() {
+                    *self.active.lock().unwrap() = Some(arg.to_string());
+                    outln!("Switched to {}.", arg);
+                } else {
+                    outln!("No conversation named '{}'. Type /list to see them.", arg);
+                }
+                return true;
+            }
+            "/status" => {
+                match status::parse(arg) {
+                    Some(new) => {
+                        outln!("Your status is now {}.", new);
+                        status::set(new);
+                    }
+                    None => outln!("Your status is {}. Usage: /status [available|away|busy] [text]", status::current()),
+                }
+                return true;
+            }
+            "/away" => {
+                let new = PeerStatus { availability: Availability::Away, text: (!arg.is_empty()).then(|| arg.to_string()) };
+                outln!("Your status is now {}.", new);
+                status::set(new);
+                return true;
+            }
+            "/connect" => {
+                if arg.is_empty() {
+                    outln!("Usage: /connect <ALIAS|ADDR:PORT>");
+                    return true;
+                }
+                // As with the `connect` command, a saved alias is resolved to its address.
+                let saved = match Persist::load() {
+                    Ok(persist) => persist.get_peer(arg).cloned(),
+                    Err(e) => {
+                        errln!("{}", e);
+                        return true;
+                    }
+                };
+                let addr = match parse_addr(saved.as_ref().map_or(arg, |p| &p.addr), DEFAULT_PORT) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        outln!("{}", e);
+                        return true;
+                    }
+                };
+                let alias = saved.map(|p| p.name);
+                let manager = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = manager.connect(&addr, alias).await {
+                        errln!("\ncould not connect to {}: {}", addr, e);
+                        console::reprompt();
+                    }
+                });
+                return true;
+            }
+            _ => {}
+        }
+        if let Some(rest) = text.strip_prefix('@') {
+            let (name, message) = rest.split_once(' ').unwrap_or((rest, ""));
+            match self.get(name) {
+                Some(conversation) if !message.trim().is_empty() => self.send(name, &conversation, message.trim(), true),
+                Some(_) => outln!("Usage: @{} <message>", name),
+                None => outln!("No conversation named '{}'. Type /list to see them.", name),
+            }
+            return true;
+        }
+
+        let Some((name, conversation)) = self.active() else {
+            outln!("Not in a conversation. Type /connect <ALIAS|ADDR:PORT> to start one.");
+            return true;
+        };
+        match command {
+            "/close" => {
+                let name = if arg.is_empty() { name } else { arg.to_string() };
+                match self.get(&name) {
+                    Some(Conversation::Peer(peer)) => {
+                        self.remove(&peer);
+                        peer.close.notify_one();
+                        outln!("Closed {}.", name);
+                    }
+                    Some(Conversation::Room(_)) => outln!("Type /quit to stop hosting {}.", name),
+                    None => outln!("No conversation named '{}'. Type /list to see them.", name),
+                }
+                return true;
+            }
+            "/send" => {
+                transfer::send_file(conversation.links(), arg);
+                return true;
+            }
+            "/accept" | "/reject" => {
+                transfer::answer_offer(&conversation.links(), command == "/accept");
+                return true;
+            }
+            _ => {}
+        }
+        match conversation {
+            Conversation::Room(room) => match command {
+                "/who" => room.who(),
+                "/ping" => room.ping(),
+                "/rekey" => room.rekey(),
+                "/verify" => {
+                    if let Err(e) = room.verify(arg) {
+                        errln!("could not verify: {}", e);
+                    }
+                }
+                _ => self.send(&name, &Conversation::Room(room), text, false),
+            },
+            Conversation::Peer(peer) => {
+                let link = peer.state.lock().unwrap().link.clone();
+                match (command, link) {
+                    ("/who", _) => peer.who(&self.identity.public_b64()),
+                    ("/ping", Some(link)) => outln!("Round-trip time to {}: {}", link.name(), link.ping_status()),
+                    ("/rekey", Some(link)) => {
+                        if link.rekey() {
+                            outln!("🔄 Rekey requested.");
+                        } else {
+                            outln!("A rekey is already in progress.");
+                        }
+                    }
+                    ("/verify", Some(link)) => {
+                        if let Err(e) = handle_verify_command(arg, &self.identity, &link) {
+                            errln!("could not verify: {}", e);
+                        }
+                    }
+                    ("/ping" | "/rekey" | "/verify", None) => outln!("Not connected."),
+                    _ => self.send(&name, &Conversation::Peer(peer), text, false),
+                }
+            }
+        }
+        true
+    }
+
+    /// Sends a message to a conversation. `tagged` shows the conversation's name in
+    /// front of the message, for messages sent to a conversation that is not active.
+    fn send(&self, name: &str, conversation: &Conversation, text: &str, tagged: bool) {
+        let tag = tagged.then_some(name);
+        match conversation {
+            Conversation::Room(room) => {
+                if room.send(text) {
+                    print_own_message(tag, text, "(sending)");
+                }
+            }
+            Conversation::Peer(peer) => {
+                let own_id = self.identity.public_b64();
+                let state = peer.state.lock().unwrap();
+                match (&state.link, &state.outbox) {
+                    (Some(link), _) => match link.send_chat(None, &own_id, text) {
+                        Ok(()) => print_own_message(tag, text, "(sending)"),
+                        Err(e) => {
+                            errln!("could not send to {}: {}", name, e);
+                            return;
+                        }
+                    },
+                    (None, Some(outbox)) => {
+                        let dropped = outbox.lock().unwrap().push(new_message_id(), own_id.clone(), text.to_string());
+                        console::print_undelivered(name, &own_id, dropped.as_slice());
+                        print_own_message(tag, text, "(queued)");
+                    }
+                    (None, None) => {
+                        outln!("Not connected.");
+                        return;
+                    }
+                }
+                if let Some(history) = &self.history {
+                    history.record(&peer.identity, &own_id, text);
+                }
+            }
+        }
+    }
+
+    /// Handles the `/list` command.
+    fn list(&self) {
+        let status = self.status();
+        if status.conversations.is_empty() {
+            outln!("No conversations. Type /connect <ALIAS|ADDR:PORT> to start one.");
+            return;
+        }
+        outln!("Conversations:");
+        for conversation in &status.conversations {
+            let marker = if status.active.as_ref() == Some(&conversation.name) { "*" } else { " " };
+            outln!("{} {} ({})", marker, conversation.name, conversation.state);
+        }
+    }
+
+    /// Returns the state of every conversation, as shown by `/list` and by frontends.
+    pub fn status(&self) -> Status {
+        let active = self.active.lock().unwrap().clone();
+        let conversations = self.conversations.lock().unwrap().clone();
+        let own_id = self.identity.public_b64();
+        let conversations = conversations
+            .into_iter()
+            .map(|(name, conversation)| match conversation {
+                Conversation::Room(room) => {
+                    let members = room.members();
+                    ConversationStatus {
+                        name,
+                        state: format!("hosting, {} member(s)", members.len()),
+                        cipher: None,
+                        members: members.iter().map(|m| m.name()).collect(),
+                    }
+                }
+                Conversation::Peer(peer) => {
+                    let state = peer.state.lock().unwrap();
+                    let mut status = match &state.link {
+                        Some(link) if link.verified() => "connected".to_string(),
+                        Some(_) => "connected, unverified".to_string(),
+                        None => "reconnecting".to_string(),
+                    };
+                    if let Some(peer_status) = state.link.as_ref().and_then(|l| l.status_of(&peer.identity)) {
+                        status.push_str(&format!(", {}", peer_status));
+                    }
+                    let mut members = Vec::new();
+                    if let Some((room, ids)) = &state.room {
+                        status.push_str(&format!(", in #{} with {} member(s)", room, ids.len()));
+                        members = ids
+                            .iter()
+                            .map(|m| if *m == own_id { "you".to_string() } else { display_name(m) })
+                            .collect();
+                    }
+                    let pending = state.outbox.as_ref().map_or(0, |o| o.lock().unwrap().pending_count());
+                    if pending > 0 {
+                        status.push_str(&format!(", {} undelivered", pending));
+                    }
+                    ConversationStatus {
+                        name,
+                        state: status,
+                        cipher: state.link.as_ref().map(|l| l.cipher.to_string()),
+                        members,
+                    }
+                }
+            })
+            .collect();
+        Status { conversations, active }
+    }
+
+    /// Shows a message or room event arriving on one of the links.
+    fn handle_event(&self, event: LinkEvent) {
+        let conversations: Vec<(String, Conversation)> = self.conversations.lock().unwrap().clone().into_iter().collect();
+        match event {
+            LinkEvent::Chat { link, id, sender_id, sender, text } => {
+                for (name, conversation) in conversations {
+                    match conversation {
+                        Conversation::Room(room) if room.has_member(link) => room.relay(link, &id, &text),
+                        Conversation::Peer(peer) if peer.owns(link) => {
+                            let tag = (sender != name).then_some(name.as_str());
+                            print_message(tag, &sender, &text);
+                            if let Some(history) = &self.history {
+                                history.record(&peer.identity, &sender_id, &text);
+                            }
+                        }
+                        _ => continue,
+                    }
+                    return;
+                }
+            }
+            LinkEvent::FileOffer { link, sender, offer } => {
+                for (name, conversation) in conversations {
+                    match conversation {
+                        Conversation::Room(room) if room.has_member(link) => {
+                            transfer::print_offer(Some(&name), &sender, &offer);
+                        }
+                        Conversation::Peer(peer) if peer.owns(link) => {
+                            transfer::print_offer((sender != name).then_some(name.as_str()), &sender, &offer);
+                        }
+                        _ => continue,
+                    }
+                    return;
+                }
+            }
+            LinkEvent::Status { link, update, previous } => {
+                for (name, conversation) in conversations {
+                    match conversation {
+                        Conversation::Room(room) if room.has_member(link) => room.relay_status(link, &update, previous.as_ref()),
+                        Conversation::Peer(peer) if peer.owns(link) => peer.handle_status(&name, &update, previous.as_ref()),
+                        _ => continue,
+                    }
+                    return;
+                }
+            }
+            LinkEvent::Room { link, event } => {
+                for (name, conversation) in conversations {
+                    if let Conversation::Peer(peer) = conversation {
+                        if peer.owns(link) {
+                            peer.handle_room_event(&name, event, &self.identity.public_b64());
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Conversation {
+    /// Returns the links of the conversation: the room's members, or the link to the
+    /// peer while connected.
+    fn links(&self) -> Vec<Link> {
+        match self {
+            Conversation::Room(room) => room.members(),
+            Conversation::Peer(peer) => peer.state.lock().unwrap().link.iter().cloned().collect(),
+        }
+    }
+}
+
+impl PeerConversation {
+    /// Returns true if the link belongs to this conversation.
+    fn owns(&self, link: u64) -> bool {
+        self.state.lock().unwrap().link_id == Some(link)
+    }
+
+    /// Handles the `/who` command.
+    fn who(&self, own_id: &str) {
+        let state = self.state.lock().unwrap();
+        match (&state.room, &state.link) {
+            (Some((room, members)), _) => {
+                outln!("#{} ({} member(s)):", room, members.len());
+                for member in members {
+                    let name = if member == own_id { "you".to_string() } else { display_name(member) };
+                    let status = state.link.as_ref().and_then(|l| l.status_of(member));
+                    outln!("  - {}{}", name, status.map_or(String::new(), |s| format!(" [{}]", s)));
+                }
+            }
+            (None, Some(link)) => match link.status_of(&self.identity) {
+                Some(status) => outln!("You are chatting with {} [{}].", link.name(), status),
+                None => outln!("You are chatting with {}.", link.name()),
+            },
+            (None, None) => outln!("Not connected."),
+        }
+    }
+
+    /// Shows a status the peer reported, for itself or, if it hosts the room we are
+    /// in, for another member (see `Link::start`), and remembers it for `list-peers`.
+    /// A status seen for the first time is only shown if it is not plain available.
+    fn handle_status(&self, name: &str, update: &StatusUpdate, previous: Option<&PeerStatus>) {
+        use colored::Colorize;
+
+        if previous.is_some() || update.status != PeerStatus::default() {
+            let who = if update.identity == self.identity { name.to_string() } else { display_name(&update.identity) };
+            outln!("\n{}", format!("[{}] {} is now {}", name, who, update.status).cyan());
+        }
+        status::remember(&update.identity, &update.status);
+    }
+
+    /// Updates the room membership shown by `/who` and announces joins and leaves.
+    fn handle_room_event(&self, name: &str, event: RoomEvent, own_id: &str) {
+        use colored::Colorize;
+
+        let mut state = self.state.lock().unwrap();
+        match event {
+            RoomEvent::Welcome { room, members } => {
+                let names: Vec<String> = members
+                    .iter()
+                    .map(|m| if m == own_id { "you".to_string() } else { display_name(m) })
+                    .collect();
+                outln!("\n{}", format!("[{}] Joined #{} ({} member(s): {})", name, room, members.len(), names.join(", ")).cyan());
+                state.room = Some((room, members));
+            }
+            RoomEvent::Joined { identity } => {
+                if let Some((room, members)) = &mut state.room {
+                    outln!("\n{}", format!("[{}] → {} joined #{}", name, display_name(&identity), room).cyan());
+                    members.push(identity);
+                }
+            }
+            RoomEvent::Left { identity } => {
+                if let Some((room, members)) = &mut state.room {
+                    outln!("\n{}", format!("[{}] ← {} left #{}", name, display_name(&identity), room).cyan());
+                    if let Some(pos) = members.iter().position(|m| *m == identity) {
+                        members.remove(pos);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use peer_common::types::{Availability, PeerStatus};
+use crate::console::{errln, outln};
+use crate::persistence::Persist;
+
+/// How long the console waits for input before our status turns to away by itself,
+/// unless `idle_away_secs` is set in the configuration file.
+pub const IDLE_AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// How long statuses of other peers are kept in memory before they are saved.
+const SAVE_DELAY: Duration = Duration::from_secs(30);
+
+/// The status we show to others. Links and discovery backends subscribe to it, so a
+/// change reaches connected peers and the next announcement.
+static OWN: OnceLock<watch::Sender<PeerStatus>> = OnceLock::new();
+
+/// The statuses of other peers not saved yet, by identity key, with the time each
+/// was first seen in seconds since the Unix epoch.
+static SEEN: Mutex<BTreeMap<String, (PeerStatus, u64)>> = Mutex::new(BTreeMap::new());
+
+/// Whether saving the statuses in `SEEN` has been scheduled.
+static SAVE_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the channel our status is kept in.
+fn own() -> &'static watch::Sender<PeerStatus> {
+    OWN.get_or_init(|| watch::channel(PeerStatus::default()).0)
+}
+
+/// Returns the status we show to others.
+pub fn current() -> PeerStatus {
+    own().borrow().clone()
+}
+
+/// Changes the status we show to others. Connected peers that understand statuses
+/// are told, and a listener announces it from then on.
+pub fn set(status: PeerStatus) {
+    own().send_replace(status);
+}
+
+/// Returns a receiver that sees every change of our status.
+pub(crate) fn subscribe() -> watch::Receiver<PeerStatus> {
+    own().subscribe()
+}
+
+/// Parses the arguments of the `/status` command: an availability, a text, or
+/// both, e.g. `busy in a meeting`. A text alone keeps the current availability.
+pub(crate) fn parse(arg: &str) -> Option<PeerStatus> {
+    let arg = arg.trim();
+    let (first, rest) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let (availability, text) = match Availability::parse(first) {
+        Some(availability) => (availability, rest.trim()),
+        None if arg.is_empty() => return None,
+        None => (current().availability, arg),
+    };
+    let text = (!text.is_empty()).then(|| text.to_string());
+    Some(PeerStatus { availability, text })
+}
+
+/// Remembers the status shown by the peer with the identity key `identity`, so
+/// `list-peers` can show it if it is a saved peer. Statuses are kept in memory and
+/// saved `SAVE_DELAY` after the first one changed, or by `save_known`.
+pub(crate) fn remember(identity: &str, status: &PeerStatus) {
+    let mut seen = SEEN.lock().unwrap();
+    if seen.get(identity).is_some_and(|(known, _)| known == status) {
+        return;
+    }
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    seen.insert(identity.to_string(), (status.clone(), since));
+    if !SAVE_SCHEDULED.swap(true, Ordering::SeqCst) {
+        tokio::spawn(async {
+            tokio::time::sleep(SAVE_DELAY).await;
+            save_known();
+        });
+    }
+}
+
+/// Saves the statuses remembered since they were last saved with the peers they
+/// belong to.
+pub fn save_known() {
+    SAVE_SCHEDULED.store(false, Ordering::SeqCst);
+    let seen = std::mem::take(&mut *SEEN.lock().unwrap());
+    if seen.is_empty() {
+        return;
+    }
+    let saved = Persist::update(|persist| {
+        let mut changed = false;
+        for (identity, (status, since)) in &seen {
+            changed |= persist.record_status(identity, status, *since);
+        }
+        changed
+    });
+    if let Err(e) = saved {
+        errln!("could not save the statuses of peers: {}", e);
+    }
+}
+
+/// Turns our status to away when no input has been typed for a while, and back to
+/// what it was on the next input. Only an available status is changed, and not if
+/// it was changed by hand in the meantime.
+pub(crate) struct IdleAway {
+    /// How long to wait for input, or `None` to never turn away.
+    after: Option<Duration>,
+    last_input: Instant,
+    idle: bool,
+    /// The status before we turned away, while the away status is ours.
+    before: Option<PeerStatus>,
+}
+
+impl IdleAway {
+    /// Starts waiting for input. `secs` is the configured wait in seconds, 0 to
+    /// never turn away, or `None` for `IDLE_AWAY_AFTER`.
+    pub fn new(secs: Option<u64>) -> Self {
+        let after = match secs {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(IDLE_AWAY_AFTER),
+        };
+        IdleAway { after, last_input: Instant::now(), idle: false, before: None }
+    }
+
+    /// Waits until the time without input is up. Cancel-safe, so it can be used in
+    /// `tokio::select!`; waits forever once idle, until the next input.
+    pub async fn wait(&self) {
+        match self.after {
+            Some(after) if !self.idle => tokio::time::sleep_until(self.last_input + after).await,
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// Turns away, since the time without input is up.
+    pub fn away(&mut self) {
+        self.idle = true;
+        let status = current();
+        if status.availability != Availability::Available {
+            return;
+        }
+        set(PeerStatus { availability: Availability::Away, text: None });
+        self.before = Some(status);
+        outln!("\nNo input for a while; your status is now away until you type something.");
+    }
+
+    /// Records that input was typed, and restores the status from before turning
+    /// away.
+    pub fn input(&mut self) {
+        self.last_input = Instant::now();
+        self.idle = false;
+        let Some(before) = self.before.take() else { return };
+        if current() == (PeerStatus { availability: Availability::Away, text: None }) {
+            outln!("Welcome back; your status is {} again.", before);
+            set(before);
+        }
+    }
+}
@@ -0,0 +1,678 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use peer_common::key_fingerprint;
+use peer_common::types::{new_message_id, FileAccept, FileChunk, FileComplete, FileMessage, FileOffer};
+use crate::console::{self, errln, outln};
+use crate::link::{Link, LinkEvent};
+use crate::persistence::Persist;
+
+/// The size of the pieces a file is sent in. A chunk is well below the default
+/// `max_frame_size` even when the JSON codec encodes it as base64 twice.
+const CHUNK_SIZE: usize = 48 * 1024;
+
+/// How many chunks may be queued for sending at a time.
+pub(crate) const CHUNKS_IN_FLIGHT: usize = 8;
+
+/// Progress is shown every time another `PROGRESS_STEP` percent of a file has been
+/// sent or received.
+const PROGRESS_STEP: u64 = 25;
+
+/// A file we offered, kept until the peer has saved or declined it. `task` streams it
+/// while the peer is receiving it.
+struct Outgoing {
+    offer: FileOffer,
+    path: PathBuf,
+    task: Option<JoinHandle<()>>,
+}
+
+/// A file being received into a partial file in the download directory. The file is
+/// written by a task of its own, so the link's reader never waits for the disk;
+/// `received` counts the bytes handed to it so far.
+struct Incoming {
+    offer: FileOffer,
+    received: u64,
+    writer: mpsc::Sender<Write>,
+}
+
+/// What the task writing a received file is asked to do. Each carries the link the
+/// peer is on now, to answer it with; the task keeps none, since a transfer outlives
+/// the links it is received on.
+enum Write {
+    /// Append a chunk to the partial file.
+    Chunk { link: Link, data: Vec<u8> },
+    /// Check the whole file and save it under its name.
+    Finish { link: Link },
+}
+
+/// The file transfers with one peer: the files we offered it, its offers waiting for
+/// an answer, and the files it is sending us. Like the `Outbox`, it outlives a single
+/// connection, so a transfer interrupted by a reconnect continues where it stopped.
+#[derive(Default)]
+pub(crate) struct Transfers {
+    outgoing: BTreeMap<String, Outgoing>,
+    offers: Vec<FileOffer>,
+    incoming: BTreeMap<String, Incoming>,
+}
+
+impl Transfers {
+    /// Stops streaming files to the peer. Called when the link they were sent on
+    /// closes; they are offered again on the next one.
+    pub fn stop_sending(&mut self) {
+        for outgoing in self.outgoing.values_mut() {
+            if let Some(task) = outgoing.task.take() {
+                task.abort();
+            }
+        }
+    }
+}
+
+/// The file transfers of all peers, keyed by identity key.
+#[derive(Clone, Default)]
+pub(crate) struct PeerTransfers {
+    inner: Arc<Mutex<HashMap<String, Arc<Mutex<Transfers>>>>>,
+}
+
+impl PeerTransfers {
+    /// Returns the file transfers with the peer with the given identity key, creating
+    /// them if needed.
+    pub fn for_peer(&self, identity: &str) -> Arc<Mutex<Transfers>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .entry(identity.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+/// Handles the `/send` command: offers the file at `path` to the peers on the links.
+/// The file is hashed in the background before it is offered.
+pub(crate) fn send_file(links: Vec<Link>, path: &str) {
+    if path.is_empty() {
+        outln!("Usage: /send <path>");
+        return;
+    }
+    let path = match path.strip_prefix("~/").zip(dirs::home_dir()) {
+        Some((rest, home)) => home.join(rest),
+        None => PathBuf::from(path),
+    };
+    if links.is_empty() {
+        outln!("Not connected.");
+        return;
+    }
+    tokio::spawn(offer_file(links, path));
+}
+
+/// Hashes a file and offers it to the peers on the links.
+async fn offer_file(links: Vec<Link>, path: PathBuf) {
+    let hashed = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || hash_file(&path)).await.unwrap_or_else(|e| Err(io::Error::other(e)))
+    };
+    let (size, sha256) = match hashed {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            errln!("\ncould not read {}: {}", path.display(), e);
+            console::reprompt();
+            return;
+        }
+    };
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => "file".to_string(),
+    };
+    let offer = FileOffer { transfer_id: new_message_id(), name, size, sha256 };
+    for link in links {
+        let outgoing = Outgoing { offer: offer.clone(), path: path.clone(), task: None };
+        link.transfers().lock().unwrap().outgoing.insert(offer.transfer_id.clone(), outgoing);
+        match link.send_file_message(&FileMessage::Offer(offer.clone())) {
+            Ok(()) => outln!("\n📎 Offered {} ({}) to {}.", offer.name, format_size(size), link.name()),
+            Err(e) => errln!("\ncould not offer {} to {}: {}", offer.name, link.name(), e),
+        }
+    }
+    console::reprompt();
+}
+
+/// Offers the files that were still being sent to the peer again. Called when a new
+/// link to the peer starts; a peer that had accepted one resumes it without asking.
+pub(crate) fn resume(link: &Link) {
+    let offers: Vec<FileOffer> = link.transfers().lock().unwrap().outgoing.values().map(|o| o.offer.clone()).collect();
+    for offer in offers {
+        if let Err(e) = link.send_file_message(&FileMessage::Offer(offer.clone())) {
+            errln!("could not offer {} to {}: {}", offer.name, link.name(), e);
+        }
+    }
+}
+
+/// Handles a file transfer message received on the link. Waits while the chunks of
+/// a file have not been written yet, which holds back the peer.
+pub(crate) async fn handle_message(link: &Link, message: FileMessage, events: &mpsc::UnboundedSender<LinkEvent>) {
+    match message {
+        FileMessage::Offer(offer) => handle_offer(link, offer, events),
+        FileMessage::Accept(accept) => start_sending(link, accept),
+        FileMessage::Chunk(chunk) => receive_chunk(link, chunk).await,
+        FileMessage::Complete(complete) => handle_complete(link, complete).await,
+    }
+}
+
+/// Records a file offered by the peer, or resumes receiving it if it was accepted
+/// before the connection was lost.
+fn handle_offer(link: &Link, mut offer: FileOffer, events: &mpsc::UnboundedSender<LinkEvent>) {
+    // The transfer ID names the partial file, so it must be nothing but a message ID.
+    let hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    if !hex(&offer.transfer_id, 32) || !hex(&offer.sha256, 64) {
+        errln!("\ndropped bad file offer from {}", link.name());
+        return;
+    }
+    // The name is shown in every message about the transfer, where it must not be
+    // able to move the cursor or reorder the text around it.
+    offer.name = printable_name(&offer.name);
+    let transfers = link.transfers();
+    let mut transfers = transfers.lock().unwrap();
+    if let Some(incoming) = transfers.incoming.get(&offer.transfer_id) {
+        let accept = FileAccept { transfer_id: offer.transfer_id.clone(), offset: incoming.received };
+        match link.send_file_message(&FileMessage::Accept(accept)) {
+            Ok(()) => outln!("\n↻ Resuming {} from {} at {}%.", offer.name, link.name(), percent(incoming.received, offer.size)),
+            Err(e) => errln!("\ncould not resume {}: {}", offer.name, e),
+        }
+        console::reprompt();
+        return;
+    }
+    if transfers.offers.iter().any(|o| o.transfer_id == offer.transfer_id) {
+        return;
+    }
+    transfers.offers.push(offer.clone());
+    let _ = events.send(LinkEvent::FileOffer { link: link.id, sender: link.name(), offer });
+}
+
+/// Shows a file offered by `sender`, tagged with the conversation it belongs to, if
+/// given.
+pub(crate) fn print_offer(conversation: Option<&str>, sender: &str, offer: &FileOffer) {
+    use colored::Colorize;
+
+    let tag = conversation.map(|c| format!("{} ", format!("[{}]", c).cyan())).unwrap_or_default();
+    outln!(
+        "\n{}📎 {} offers {} ({}). Type /accept to download it or /reject to decline.",
+        tag,
+        sender.yellow(),
+        offer.name,
+        format_size(offer.size)
+    );
+}
+
+/// Handles the `/accept` and `/reject` commands for the peers on the links: the
+/// oldest offer from any of them is answered.
+pub(crate) fn answer_offer(links: &[Link], accept: bool) {
+    for link in links {
+        let offer = {
+            let transfers = link.transfers();
+            let mut transfers = transfers.lock().unwrap();
+            if transfers.offers.is_empty() {
+                continue;
+            }
+            transfers.offers.remove(0)
+        };
+        if accept {
+            tokio::spawn(start_receiving(link.clone(), offer));
+        } else {
+            decline(link, &offer, "declined");
+            outln!("Declined {}.", offer.name);
+        }
+        return;
+    }
+    outln!("No file has been offered.");
+}
+
+/// Tells the peer that we will not take the file it offered.
+fn decline(link: &Link, offer: &FileOffer, reason: &str) {
+    let complete = FileComplete { transfer_id: offer.transfer_id.clone(), error: Some(reason.to_string()) };
+    if let Err(e) = link.send_file_message(&FileMessage::Complete(complete)) {
+        errln!("could not answer {}: {}", link.name(), e);
+    }
+}
+
+/// Accepts an offered file. It is written to a partial file in the download
+/// directory, named after the peer and the transfer, so a download cut short by a
+/// lost connection continues where it stopped when the file is offered again.
+async fn start_receiving(link: Link, offer: FileOffer) {
+    let opened = {
+        let name = part_name(&link.peer.identity, &offer.transfer_id);
+        tokio::task::spawn_blocking(move || open_part(&name?)).await.unwrap_or_else(|e| Err(e.into()))
+    };
+    let (part, file, mut hasher, mut received) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            errln!("\ncould not accept {}: {}", offer.name, e);
+            decline(&link, &offer, "the file could not be saved");
+            console::reprompt();
+            return;
+        }
+    };
+    let file = tokio::fs::File::from_std(file);
+    if received > offer.size {
+        if let Err(e) = file.set_len(0).await {
+            errln!("\ncould not accept {}: {}", offer.name, e);
+            decline(&link, &offer, "the file could not be saved");
+            console::reprompt();
+            return;
+        }
+        hasher = Sha256::new();
+        received = 0;
+    }
+
+    // The transfer is known before the peer hears of it, so no chunk is missed.
+    let (writer, rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    tokio::spawn(write_file(offer.clone(), part.clone(), file, hasher, received, rx));
+    let incoming = Incoming { offer: offer.clone(), received, writer };
+    link.transfers().lock().unwrap().incoming.insert(offer.transfer_id.clone(), incoming);
+    let accept = FileAccept { transfer_id: offer.transfer_id.clone(), offset: received };
+    let dir = part.parent().unwrap_or(Path::new("."));
+    match link.send_file_message(&FileMessage::Accept(accept)) {
+        Ok(()) if received > 0 => outln!("\nResuming {} from {}% into {}.", offer.name, percent(received, offer.size), dir.display()),
+        Ok(()) => outln!("\nDownloading {} into {}.", offer.name, dir.display()),
+        Err(e) => {
+            link.transfers().lock().unwrap().incoming.remove(&offer.transfer_id);
+            errln!("\ncould not accept {}: {}", offer.name, e);
+        }
+    }
+    console::reprompt();
+}
+
+/// Returns the name of the partial file for a transfer from the peer with the given
+/// identity key: a hidden file named after the start of the key's fingerprint and the
+/// transfer ID, so no two transfers share one.
+fn part_name(identity: &str, transfer_id: &str) -> anyhow::Result<String> {
+    let fingerprint: String = key_fingerprint(identity)?.split(' ').take(4).collect();
+    Ok(format!(".{}-{}.part", fingerprint, transfer_id))
+}
+
+/// Opens the partial file with the given name in the download directory, creating
+/// both if needed. Returns its path, the file, and a hasher that has seen what the
+/// file already holds, with its size.
+fn open_part(name: &str) -> anyhow::Result<(PathBuf, fs::File, Sha256, u64)> {
+    let dir = Persist::load()?.download_dir()?;
+    fs::create_dir_all(&dir)?;
+    let part = dir.join(name);
+    let mut file = fs::OpenOptions::new().read(true).append(true).create(true).open(&part)?;
+    let mut hasher = Sha256::new();
+    let received = io::copy(&mut file, &mut hasher)?;
+    Ok((part, file, hasher, received))
+}
+
+/// Writes the chunks of a file we are receiving to its partial file, `written` bytes
+/// into it, until the file is finished or the transfer ends. If a chunk cannot be
+/// written, the transfer is given up.
+async fn write_file(offer: FileOffer, part: PathBuf, mut file: tokio::fs::File, mut hasher: Sha256, mut written: u64, mut rx: mpsc::Receiver<Write>) {
+    use tokio::io::AsyncWriteExt;
+
+    while let Some(write) = rx.recv().await {
+        match write {
+            Write::Chunk { link, data } => {
+                if let Err(e) = file.write_all(&data).await {
+                    errln!("\ncould not save {}: {}", offer.name, e);
+                    if link.transfers().lock().unwrap().incoming.remove(&offer.transfer_id).is_some() {
+                        decline(&link, &offer, "the file could not be saved");
+                    }
+                    console::reprompt();
+                    return;
+                }
+                hasher.update(&data);
+                let end = written + data.len() as u64;
+                if let Some(percent) = progress(offer.size, written, end) {
+                    outln!("\n⇣ {}: {}% received from {}", offer.name, percent, link.name());
+                    console::reprompt();
+                }
+                written = end;
+            }
+            Write::Finish { link } => {
+                let flushed = file.flush().await;
+                drop(file);
+                let sha256 = format!("{:x}", hasher.finalize());
+                return finish_receiving(&link, offer, part, flushed.map(|()| (written, sha256))).await;
+            }
+        }
+    }
+    // The transfer stopped; the partial file is kept, so it can resume.
+    let _ = file.flush().await;
+}
+
+/// Starts streaming a file the peer accepted, from the offset it asked for.
+fn start_sending(link: &Link, accept: FileAccept) {
+    let transfers = link.transfers();
+    let mut transfers = transfers.lock().unwrap();
+    let Some(outgoing) = transfers.outgoing.get_mut(&accept.transfer_id) else { return };
+    if accept.offset > outgoing.offer.size {
+        return;
+    }
+    if let Some(task) = outgoing.task.take() {
+        task.abort();
+    }
+    if accept.offset > 0 {
+        outln!("\n⇡ {} resumes {} at {}%.", link.name(), outgoing.offer.name, percent(accept.offset, outgoing.offer.size));
+    } else {
+        outln!("\n⇡ Sending {} to {}.", outgoing.offer.name, link.name());
+    }
+    console::reprompt();
+    let task = tokio::spawn(stream_file(link.clone(), outgoing.offer.clone(), outgoing.path.clone(), accept.offset));
+    outgoing.task = Some(task);
+}
+
+/// Sends a file in chunks from `offset` on, followed by a `FileComplete`. If the file
+/// cannot be read, the transfer is given up. If the link closes, the transfer stops
+/// until the file is offered again on the next link.
+async fn stream_file(link: Link, offer: FileOffer, path: PathBuf, offset: u64) {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => return give_up(&link, &offer, e.to_string()),
+    };
+    if let Err(e) = file.seek(io::SeekFrom::Start(offset)).await {
+        return give_up(&link, &offer, e.to_string());
+    }
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut sent = offset;
+    while sent < offer.size {
+        let n = match file.read(&mut buf).await {
+            Ok(0) => return give_up(&link, &offer, "the file has changed since it was offered".to_string()),
+            Ok(n) => n.min((offer.size - sent) as usize),
+            Err(e) => return give_up(&link, &offer, e.to_string()),
+        };
+        let chunk = FileChunk { transfer_id: offer.transfer_id.clone(), offset: sent, data: buf[..n].to_vec() };
+        if link.send_file_chunk(chunk).await.is_err() {
+            return;
+        }
+        if let Some(percent) = progress(offer.size, sent, sent + n as u64) {
+            outln!("\n⇡ {}: {}% sent to {}", offer.name, percent, link.name());
+            console::reprompt();
+        }
+        sent += n as u64;
+    }
+    let complete = FileComplete { transfer_id: offer.transfer_id.clone(), error: None };
+    let _ = link.send_file_message(&FileMessage::Complete(complete));
+}
+
+/// Gives up sending a file, telling the peer why.
+fn give_up(link: &Link, offer: &FileOffer, reason: String) {
+    errln!("\ncould not send {}: {}", offer.name, reason);
+    link.transfers().lock().unwrap().outgoing.remove(&offer.transfer_id);
+    let complete = FileComplete { transfer_id: offer.transfer_id.clone(), error: Some(reason) };
+    let _ = link.send_file_message(&FileMessage::Complete(complete));
+    console::reprompt();
+}
+
+/// Hands a chunk of a file we are receiving to the task writing it. Chunks that do
+/// not continue the file where it ends, such as ones resent after a reconnect, are
+/// ignored.
+async fn receive_chunk(link: &Link, chunk: FileChunk) {
+    let writer = {
+        let transfers = link.transfers();
+        let mut transfers = transfers.lock().unwrap();
+        let Some(incoming) = transfers.incoming.get_mut(&chunk.transfer_id) else { return };
+        if chunk.offset != incoming.received {
+            return;
+        }
+        let end = incoming.received + chunk.data.len() as u64;
+        if end > incoming.offer.size {
+            errln!("\ncould not save {}: more data than offered", incoming.offer.name);
+            if let Some(incoming) = transfers.incoming.remove(&chunk.transfer_id) {
+                decline(link, &incoming.offer, "the file could not be saved");
+            }
+            console::reprompt();
+            return;
+        }
+        incoming.received = end;
+        incoming.writer.clone()
+    };
+    let _ = writer.send(Write::Chunk { link: link.clone(), data: chunk.data }).await;
+}
+
+/// Handles the end of a transfer. From the sender, it means the whole file has been
+/// sent, or that it gave up; from the receiver, that it saved the file, or why not.
+async fn handle_complete(link: &Link, complete: FileComplete) {
+    use colored::Colorize;
+
+    let incoming = {
+        let transfers = link.transfers();
+        let mut transfers = transfers.lock().unwrap();
+        if let Some(outgoing) = transfers.outgoing.remove(&complete.transfer_id) {
+            if let Some(task) = outgoing.task {
+                task.abort();
+            }
+            match complete.error {
+                None => outln!("\n{}", format!("✓ {} received {}.", link.name(), outgoing.offer.name).green()),
+                Some(e) => outln!("\n{}", format!("✗ {} did not take {}: {}", link.name(), outgoing.offer.name, e).red()),
+            }
+            console::reprompt();
+            return;
+        }
+        match transfers.incoming.remove(&complete.transfer_id) {
+            Some(incoming) => incoming,
+            None => {
+                // The peer withdrew an offer we had not answered yet.
+                transfers.offers.retain(|o| o.transfer_id != complete.transfer_id);
+                return;
+            }
+        }
+    };
+    match complete.error {
+        // The writer reports once it has checked the file.
+        None => {
+            let _ = incoming.writer.send(Write::Finish { link: link.clone() }).await;
+        }
+        // The partial file is kept, so the download resumes if the file is sent
+        // again.
+        Some(e) => {
+            outln!("\n{}", format!("✗ {} stopped sending {}: {}", link.name(), incoming.offer.name, e).red());
+            console::reprompt();
+        }
+    }
+}
+
+/// Checks the size and hash of a received file, given by `written` as the size and
+/// hash of what was written, moves it into the download directory under its
+/// sanitised name and tells the sender how it went. A file that does not match its
+/// offer is deleted.
+async fn finish_receiving(link: &Link, offer: FileOffer, part: PathBuf, written: io::Result<(u64, String)>) {
+    use colored::Colorize;
+
+    let saved = match written {
+        Ok((size, sha256)) => {
+            let (matches, name) = (size == offer.size && sha256 == offer.sha256, offer.name.clone());
+            tokio::task::spawn_blocking(move || {
+                if !matches {
+                    let _ = fs::remove_file(&part);
+                    return Err("the SHA-256 hash does not match".to_string());
+                }
+                save_part(&part, &name).map_err(|e| e.to_string())
+            })
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()))
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    let error = match saved {
+        Ok(path) => {
+            outln!("\n{}", format!("✓ Received {} from {}, saved as {}", offer.name, link.name(), path.display()).green());
+            None
+        }
+        Err(e) => {
+            outln!("\n{}", format!("✗ Could not receive {} from {}: {}", offer.name, link.name(), e).red());
+            Some(e)
+        }
+    };
+    console::reprompt();
+    let complete = FileComplete { transfer_id: offer.transfer_id, error };
+    if let Err(e) = link.send_file_message(&FileMessage::Complete(complete)) {
+        errln!("could not answer {}: {}", link.name(), e);
+    }
+}
+
+/// Moves a completed partial file next to it under the sanitised `name`, adding a
+/// number if a file with that name already exists. Returns the new path.
+fn save_part(part: &Path, name: &str) -> io::Result<PathBuf> {
+    let dir = part.parent().unwrap_or(Path::new("."));
+    let name = sanitize_filename(name);
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+        _ => (name.clone(), String::new()),
+    };
+    let mut path = dir.join(&name);
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({}){}", stem, n, ext));
+        n += 1;
+    }
+    fs::rename(part, &path)?;
+    Ok(path)
+}
+
+/// Turns a file name chosen by the peer into one that is safe to create in the
+/// download directory. Only the last path component is kept, characters that are
+/// not allowed in file names on common systems are replaced, and the result is never
+/// empty, hidden, `..`, or a device name reserved by Windows.
+pub fn sanitize_filename(name: &str) -> String {
+    const MAX_LEN: usize = 200;
+
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') { '_' } else { c })
+        .collect();
+    let mut name = name.trim_matches(|c: char| c == '.' || c.is_whitespace()).to_string();
+    if name.is_empty() {
+        name = "file".to_string();
+    }
+
+    let device = name.split('.').next().unwrap_or_default().trim_end().to_ascii_uppercase();
+    let numbered = |prefix: &str| device.len() == 4 && device.starts_with(prefix) && device.as_bytes()[3].is_ascii_digit();
+    if matches!(device.as_str(), "CON" | "PRN" | "AUX" | "NUL") || numbered("COM") || numbered("LPT") {
+        name.insert(0, '_');
+    }
+
+    // Long names are shortened in the middle, keeping a short extension.
+    if name.len() > MAX_LEN {
+        let ext = match name.rsplit_once('.') {
+            Some((_, ext)) if ext.len() <= 16 => format!(".{}", ext),
+            _ => String::new(),
+        };
+        let mut end = MAX_LEN - ext.len();
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name = format!("{}{}", &name[..end], ext);
+    }
+    name
+}
+
+/// Removes the control characters and bidirectional formatting characters from a
+/// file name chosen by the peer, so it can be printed.
+fn printable_name(name: &str) -> String {
+    let bidi = |c: char| matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}');
+    name.chars().filter(|&c| !c.is_control() && !bidi(c)).collect()
+}
+
+/// Reads a whole file, returning its size and hex-encoded SHA-256 hash.
+fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Returns how much of a file of `size` bytes the first `done` bytes are, in percent.
+fn percent(done: u64, size: u64) -> u64 {
+    (done * 100).checked_div(size).unwrap_or(100)
+}
+
+/// Returns the percentage to show when a transfer of `size` bytes has gone from
+/// `before` to `after` bytes, if it has passed another `PROGRESS_STEP`. The end of a
+/// transfer is not shown as progress.
+fn progress(size: u64, before: u64, after: u64) -> Option<u64> {
+    let passed = percent(after, size) / PROGRESS_STEP > percent(before, size) / PROGRESS_STEP;
+    (passed && after < size).then(|| percent(after, size))
+}
+
+/// Formats a size in bytes for display, e.g. `3.2 MB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_traversal_keeps_only_the_last_component() {
+        assert_eq!(sanitize_filename("../../.ssh/authorized_keys"), "authorized_keys");
+        assert_eq!(sanitize_filename("..\\..\\evil.exe"), "evil.exe");
+        assert_eq!(sanitize_filename(".."), "file");
+        assert_eq!(sanitize_filename("../"), "file");
+    }
+
+    #[test]
+    fn absolute_paths_keep_only_the_file_name() {
+        assert_eq!(sanitize_filename("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Windows\\system32\\drivers\\etc\\hosts"), "hosts");
+        assert_eq!(sanitize_filename("C:boot.ini"), "C_boot.ini");
+        assert_eq!(sanitize_filename("\\\\server\\share\\x.txt"), "x.txt");
+    }
+
+    #[test]
+    fn control_and_reserved_characters_are_replaced() {
+        assert_eq!(sanitize_filename("a\u{1b}[31mb\n.txt"), "a_[31mb_.txt");
+        assert_eq!(sanitize_filename("what?<>|*\".txt"), "what______.txt");
+        assert_eq!(sanitize_filename("\0"), "_");
+    }
+
+    #[test]
+    fn hidden_and_empty_names_are_replaced() {
+        assert_eq!(sanitize_filename(""), "file");
+        assert_eq!(sanitize_filename("   "), "file");
+        assert_eq!(sanitize_filename(".bashrc"), "bashrc");
+        assert_eq!(sanitize_filename("notes.txt. "), "notes.txt");
+    }
+
+    #[test]
+    fn windows_device_names_are_prefixed() {
+        for name in ["CON", "con.txt", "Aux.tar.gz", "nul ", "COM1", "lpt9.log", "PRN"] {
+            assert!(sanitize_filename(name).starts_with('_'), "{:?} -> {:?}", name, sanitize_filename(name));
+        }
+        for name in ["COM", "COM10", "console.txt", "auxiliary", "LPTX"] {
+            assert!(!sanitize_filename(name).starts_with('_'), "{:?} -> {:?}", name, sanitize_filename(name));
+        }
+    }
+
+    #[test]
+    fn long_names_are_shortened_keeping_the_extension() {
+        let name = sanitize_filename(&format!("{}.tar", "é".repeat(300)));
+        assert!(name.len() <= 200);
+        assert!(name.ends_with("é.tar"));
+    }
+
+    #[test]
+    fn printable_names_lose_control_and_bidi_characters() {
+        assert_eq!(printable_name("photo\u{1b}[2J\u{7}.jpg"), "photo[2J.jpg");
+        assert_eq!(printable_name("invoice\u{202e}fdp.exe"), "invoicefdp.exe");
+        assert_eq!(printable_name("\u{2066}a\u{2069}\u{200f}b\r\n"), "ab");
+        assert_eq!(printable_name(""), "");
+        assert_eq!(printable_name("résumé 2024.pdf"), "résumé 2024.pdf");
+    }
+}